@@ -1,108 +1,389 @@
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 
 use crossterm::style::Stylize;
 use handlebars::Handlebars;
+use serde::{Deserialize, Serialize};
 
-use crate::config::{SymbolicTarget, TemplateTarget, Variables};
-use crate::difference::{self, diff_nonempty, generate_template_diff, print_diff};
-use crate::filesystem::{Filesystem, SymlinkComparison, TemplateComparison};
+use crate::backup::BackupStore;
+use crate::config::{
+    CachedFile, CopyTarget, HardlinkTarget, MergeStrategy, Mode, SymbolicTarget, TemplateTarget,
+    UnixGroup, UnixUser, Variables,
+};
+use crate::difference::{
+    self, diff_nonempty, generate_template_diff, merge3, print_diff, DiffSink,
+};
+use crate::filesystem::{
+    self, Filesystem, HardlinkComparison, SymlinkComparison, TemplateComparison,
+};
+
+/// The kinds of actions an `ActionRunner` performs
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    DeleteSymlink,
+    DeleteTemplate,
+    CreateSymlink,
+    CreateTemplate,
+    UpdateSymlink,
+    UpdateTemplate,
+    DeleteCopy,
+    CreateCopy,
+    UpdateCopy,
+    DeleteHardlink,
+    CreateHardlink,
+    UpdateHardlink,
+}
+
+impl std::fmt::Display for ActionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        use self::ActionKind::*;
+        match self {
+            DeleteSymlink => "delete symlink",
+            DeleteTemplate => "delete template",
+            CreateSymlink => "create symlink",
+            CreateTemplate => "create template",
+            UpdateSymlink => "update symlink",
+            UpdateTemplate => "update template",
+            DeleteCopy => "delete copy",
+            CreateCopy => "create copy",
+            UpdateCopy => "update copy",
+            DeleteHardlink => "delete hardlink",
+            CreateHardlink => "create hardlink",
+            UpdateHardlink => "update hardlink",
+        }
+        .fmt(f)
+    }
+}
 
 #[cfg_attr(test, mockall::automock)]
 pub trait ActionRunner {
     fn delete_symlink(&mut self, source: &Path, target: &Path) -> Result<bool>;
-    fn delete_template(&mut self, source: &Path, cache: &Path, target: &Path) -> Result<bool>;
+    /// `cached` is what the target was last deployed with
+    fn delete_template(&mut self, source: &Path, cache: &Path, cached: &CachedFile)
+        -> Result<bool>;
     fn create_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool>;
+    /// `cached` is filled in with what the target was deployed with
     fn create_template(
         &mut self,
         source: &Path,
         cache: &Path,
         target: &TemplateTarget,
+        cached: &mut CachedFile,
     ) -> Result<bool>;
     fn update_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool>;
+    /// `cached` is what the target was last deployed with, and is updated if it's redeployed
     fn update_template(
         &mut self,
         source: &Path,
         cache: &Path,
         target: &TemplateTarget,
+        cached: &mut CachedFile,
     ) -> Result<bool>;
+    /// `cached` is what the target was last copied with
+    fn delete_copy(&mut self, source: &Path, cached: &CachedFile) -> Result<bool>;
+    /// `cached` is filled in with what the target was copied with
+    fn create_copy(
+        &mut self,
+        source: &Path,
+        target: &CopyTarget,
+        cached: &mut CachedFile,
+    ) -> Result<bool>;
+    /// `cached` is what the target was last copied with, and is updated if it's copied again
+    fn update_copy(
+        &mut self,
+        source: &Path,
+        target: &CopyTarget,
+        cached: &mut CachedFile,
+    ) -> Result<bool>;
+    fn delete_hardlink(&mut self, source: &Path, target: &Path) -> Result<bool>;
+    fn create_hardlink(&mut self, source: &Path, target: &HardlinkTarget) -> Result<bool>;
+    fn update_hardlink(&mut self, source: &Path, target: &HardlinkTarget) -> Result<bool>;
+}
+
+/// What the actions of a `RealActionRunner` changed, for the targets with an `on_change` command
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    /// `on_change` commands of the targets that changed, each only once, in the order they changed
+    pub commands: Vec<String>,
+    /// Hashes of the linked sources' contents as of when their links were last deployed
+    pub source_hashes: BTreeMap<PathBuf, String>,
 }
 
 pub struct RealActionRunner<'a> {
     fs: &'a mut dyn Filesystem,
+    backups: &'a mut BackupStore,
     handlebars: &'a Handlebars<'a>,
     variables: &'a Variables,
     force: bool,
+    merge: MergeStrategy,
     diff_context_lines: usize,
+    diff_sink: DiffSink,
+    /// Hashes of the linked sources' contents as of the previous deployment, to notice edits
+    previous_source_hashes: BTreeMap<PathBuf, String>,
+    changes: Changes,
 }
 
 impl<'a> RealActionRunner<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         fs: &'a mut dyn Filesystem,
+        backups: &'a mut BackupStore,
         handlebars: &'a Handlebars,
         variables: &'a Variables,
         force: bool,
+        merge: MergeStrategy,
         diff_context_lines: usize,
+        diff_sink: DiffSink,
     ) -> RealActionRunner<'a> {
         RealActionRunner {
             fs,
+            backups,
             handlebars,
             variables,
             force,
+            merge,
             diff_context_lines,
+            diff_sink,
+            previous_source_hashes: BTreeMap::new(),
+            changes: Changes::default(),
+        }
+    }
+
+    /// Hashes of the linked sources' contents from the previous deployment's `Changes`
+    pub fn with_source_hashes(
+        mut self,
+        source_hashes: BTreeMap<PathBuf, String>,
+    ) -> RealActionRunner<'a> {
+        self.previous_source_hashes = source_hashes;
+        self
+    }
+
+    /// The filesystem the actions are performed on
+    pub fn filesystem(&mut self) -> &mut dyn Filesystem {
+        self.fs
+    }
+
+    /// What the actions performed so far changed
+    pub fn take_changes(&mut self) -> Changes {
+        std::mem::take(&mut self.changes)
+    }
+
+    fn collect(&mut self, command: &str) {
+        if !self.changes.commands.iter().any(|c| c == command) {
+            self.changes.commands.push(command.into());
         }
     }
+
+    /// Performs `action` on a target deployed with its own contents (a template or copy),
+    /// collecting `on_change` if the target's contents are different afterwards
+    fn track_contents(
+        &mut self,
+        target: &Path,
+        on_change: &Option<String>,
+        action: impl FnOnce(&mut Self) -> Result<bool>,
+    ) -> Result<bool> {
+        let on_change = match on_change {
+            Some(on_change) => on_change,
+            None => return action(self),
+        };
+        let before = contents_hash(self.fs, target);
+        let result = action(self)?;
+        if result && contents_hash(self.fs, target) != before {
+            self.collect(on_change);
+        }
+        Ok(result)
+    }
+
+    /// Performs `action` on a link to `source`, collecting `on_change` if it (re)created the link
+    /// or the source was edited since the link was last deployed. `linked` tells whether the
+    /// link is already in place
+    fn track_link(
+        &mut self,
+        source: &Path,
+        on_change: &Option<String>,
+        linked: impl FnOnce(&mut dyn Filesystem) -> bool,
+        action: impl FnOnce(&mut Self) -> Result<bool>,
+    ) -> Result<bool> {
+        let on_change = match on_change {
+            Some(on_change) => on_change,
+            None => return action(self),
+        };
+        let relinked = !linked(self.fs);
+        let previous = self.previous_source_hashes.get(source).cloned();
+        let result = action(self);
+        // Directories aren't hashed, so only relinking them counts
+        let hash = match result {
+            Ok(true) => contents_hash(self.fs, source),
+            // Still deployed as before as far as the cache is concerned
+            _ => previous.clone(),
+        };
+        let edited = matches!((&previous, &hash), (Some(previous), Some(hash)) if previous != hash);
+        if matches!(result, Ok(true)) && (relinked || edited) {
+            self.collect(on_change);
+        }
+        if let Some(hash) = hash {
+            self.changes.source_hashes.insert(source.into(), hash);
+        }
+        result
+    }
+}
+
+/// Hash of the contents of a regular file (following symlinks), if it can be read
+fn contents_hash(fs: &mut dyn Filesystem, path: &Path) -> Option<String> {
+    fs.read(path)
+        .ok()
+        .map(|contents| filesystem::hash_contents(&contents))
 }
 
 impl<'a> ActionRunner for RealActionRunner<'a> {
     fn delete_symlink(&mut self, source: &Path, target: &Path) -> Result<bool> {
-        delete_symlink(source, target, self.fs, self.force)
+        delete_symlink(source, target, self.fs, self.backups, self.force)
     }
-    fn delete_template(&mut self, source: &Path, cache: &Path, target: &Path) -> Result<bool> {
-        delete_template(source, cache, target, self.fs, self.force)
+    fn delete_template(
+        &mut self,
+        source: &Path,
+        cache: &Path,
+        cached: &CachedFile,
+    ) -> Result<bool> {
+        delete_template(source, cache, cached, self.fs, self.backups, self.force)
     }
     fn create_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool> {
-        create_symlink(source, target, self.fs, self.force)
+        self.track_link(
+            source,
+            &target.on_change,
+            |fs| symlinked(fs, source, &target.target),
+            |runner| create_symlink(source, target, runner.fs, runner.backups, runner.force),
+        )
     }
     fn create_template(
         &mut self,
         source: &Path,
         cache: &Path,
         target: &TemplateTarget,
+        cached: &mut CachedFile,
     ) -> Result<bool> {
-        create_template(
-            source,
-            cache,
-            target,
-            self.fs,
-            self.handlebars,
-            self.variables,
-            self.force,
-        )
+        self.track_contents(&target.target, &target.on_change, |runner| {
+            create_template(
+                source,
+                cache,
+                target,
+                cached,
+                runner.fs,
+                runner.backups,
+                runner.handlebars,
+                runner.variables,
+                runner.force,
+                runner.merge,
+            )
+        })
     }
     fn update_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool> {
-        update_symlink(source, target, self.fs, self.force)
+        self.track_link(
+            source,
+            &target.on_change,
+            |fs| symlinked(fs, source, &target.target),
+            |runner| update_symlink(source, target, runner.fs, runner.backups, runner.force),
+        )
     }
     fn update_template(
         &mut self,
         source: &Path,
         cache: &Path,
         target: &TemplateTarget,
+        cached: &mut CachedFile,
     ) -> Result<bool> {
-        update_template(
+        self.track_contents(&target.target, &target.on_change, |runner| {
+            update_template(
+                source,
+                cache,
+                target,
+                cached,
+                runner.fs,
+                runner.backups,
+                runner.handlebars,
+                runner.variables,
+                runner.force,
+                runner.merge,
+                runner.diff_context_lines,
+                runner.diff_sink,
+            )
+        })
+    }
+    fn delete_copy(&mut self, source: &Path, cached: &CachedFile) -> Result<bool> {
+        delete_copy(source, cached, self.fs, self.backups, self.force)
+    }
+    fn create_copy(
+        &mut self,
+        source: &Path,
+        target: &CopyTarget,
+        cached: &mut CachedFile,
+    ) -> Result<bool> {
+        self.track_contents(&target.target, &target.on_change, |runner| {
+            create_copy(
+                source,
+                target,
+                cached,
+                runner.fs,
+                runner.backups,
+                runner.force,
+            )
+        })
+    }
+    fn update_copy(
+        &mut self,
+        source: &Path,
+        target: &CopyTarget,
+        cached: &mut CachedFile,
+    ) -> Result<bool> {
+        self.track_contents(&target.target, &target.on_change, |runner| {
+            update_copy(
+                source,
+                target,
+                cached,
+                runner.fs,
+                runner.backups,
+                runner.force,
+            )
+        })
+    }
+    fn delete_hardlink(&mut self, source: &Path, target: &Path) -> Result<bool> {
+        delete_hardlink(source, target, self.fs, self.backups, self.force)
+    }
+    fn create_hardlink(&mut self, source: &Path, target: &HardlinkTarget) -> Result<bool> {
+        self.track_link(
+            source,
+            &target.on_change,
+            |fs| hardlinked(fs, source, &target.target),
+            |runner| create_hardlink(source, target, runner.fs, runner.backups, runner.force),
+        )
+    }
+    fn update_hardlink(&mut self, source: &Path, target: &HardlinkTarget) -> Result<bool> {
+        self.track_link(
             source,
-            cache,
-            target,
-            self.fs,
-            self.handlebars,
-            self.variables,
-            self.force,
-            self.diff_context_lines,
+            &target.on_change,
+            |fs| hardlinked(fs, source, &target.target),
+            |runner| update_hardlink(source, target, runner.fs, runner.backups, runner.force),
         )
     }
 }
 
+fn symlinked(fs: &mut dyn Filesystem, source: &Path, target: &Path) -> bool {
+    matches!(
+        fs.compare_symlink(source, target),
+        Ok(SymlinkComparison::Identical)
+    )
+}
+
+fn hardlinked(fs: &mut dyn Filesystem, source: &Path, target: &Path) -> bool {
+    matches!(
+        fs.compare_hardlink(source, target),
+        Ok(HardlinkComparison::Identical)
+    )
+}
+
 // == DELETE ==
 
 /// Returns true if symlink should be deleted from cache
@@ -110,6 +391,7 @@ pub fn delete_symlink(
     source: &Path,
     target: &Path,
     fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
     force: bool,
 ) -> Result<bool> {
     info!("{} symlink {:?} -> {:?}", "[-]".red(), source, target);
@@ -138,8 +420,11 @@ pub fn delete_symlink(
                 "Deleting symlink {:?} -> {:?} but {}. Forcing.",
                 source, target, comparison
             );
-            perform_symlink_target_deletion(fs, target)
-                .context("perform symlink target deletion")?;
+            backups
+                .backup(fs, target)
+                .context("back up symlink target while forcing")?;
+            fs.delete_parents(target, false)
+                .context("delete parents of symlink")?;
             Ok(true)
         }
         SymlinkComparison::Changed | SymlinkComparison::TargetNotSymlink => {
@@ -159,18 +444,79 @@ fn perform_symlink_target_deletion(fs: &mut dyn Filesystem, target: &Path) -> Re
     Ok(())
 }
 
+/// Returns true if hard link should be deleted from cache
+pub fn delete_hardlink(
+    source: &Path,
+    target: &Path,
+    fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
+    force: bool,
+) -> Result<bool> {
+    info!("{} hardlink {:?} -> {:?}", "[-]".red(), source, target);
+
+    let comparison = fs
+        .compare_hardlink(source, target)
+        .context("detect hard link's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        HardlinkComparison::Identical => {
+            debug!("Performing deletion");
+            fs.remove_file(target).context("remove hard link")?;
+            fs.delete_parents(target, false)
+                .context("delete parents of hard link")?;
+            Ok(true)
+        }
+        HardlinkComparison::OnlySourceExists | HardlinkComparison::BothMissing => {
+            warn!(
+                "Deleting hardlink {:?} -> {:?} but target doesn't exist. Removing from cache anyways.",
+                source, target
+            );
+            Ok(true)
+        }
+        // Unlike a symlink, the target is the last copy of its contents if the source is missing
+        HardlinkComparison::OnlyTargetExists
+        | HardlinkComparison::Changed
+        | HardlinkComparison::TargetNotRegularFile
+            if force =>
+        {
+            warn!(
+                "Deleting hardlink {:?} -> {:?} but {}. Forcing.",
+                source, target, comparison
+            );
+            backups
+                .backup(fs, target)
+                .context("back up hard link target while forcing")?;
+            fs.delete_parents(target, false)
+                .context("delete parents of hard link")?;
+            Ok(true)
+        }
+        HardlinkComparison::OnlyTargetExists
+        | HardlinkComparison::Changed
+        | HardlinkComparison::TargetNotRegularFile => {
+            error!(
+                "Deleting {:?} -> {:?} but {}. Skipping.",
+                source, target, comparison
+            );
+            Ok(false)
+        }
+    }
+}
+
 /// Returns true if template should be deleted from cache
 pub fn delete_template(
     source: &Path,
     cache: &Path,
-    target: &Path,
+    cached: &CachedFile,
     fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
     force: bool,
 ) -> Result<bool> {
+    let target = &cached.target;
     info!("{} template {:?} -> {:?}", "[-]".red(), source, target);
 
     let comparison = fs
-        .compare_template(target, cache)
+        .compare_contents(target, Some(&cached.hash), cached.stamp())
         .context("detect templated file's current state")?;
     debug!("Current state: {}", comparison);
 
@@ -182,35 +528,93 @@ pub fn delete_template(
                 .context("perform template target deletion")?;
             Ok(true)
         }
-        TemplateComparison::OnlyCacheExists => {
+        TemplateComparison::OnlyCacheExists | TemplateComparison::BothMissing => {
             warn!(
-                "Deleting template {:?} -> {:?} but {}. Deleting cache anyways.",
+                "Deleting template {:?} -> {:?} but target doesn't exist. Removing from cache anyways.",
+                source, target
+            );
+            perform_cache_deletion(fs, cache).context("perform cache deletion")?;
+            Ok(true)
+        }
+        TemplateComparison::Changed
+        | TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::OnlyTargetExists
+            if force =>
+        {
+            warn!(
+                "Deleting template {:?} -> {:?} but {}. Forcing.",
                 source, target, comparison
             );
             perform_cache_deletion(fs, cache).context("perform cache deletion")?;
+            backups
+                .backup(fs, target)
+                .context("back up target file while forcing")?;
+            fs.delete_parents(target, false)
+                .context("delete parent directory in target location")?;
             Ok(true)
         }
-        TemplateComparison::OnlyTargetExists | TemplateComparison::BothMissing => {
+        TemplateComparison::Changed
+        | TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::OnlyTargetExists => {
             error!(
-                "Deleting template {:?} -> {:?} but cache doesn't exist. Cache probably CORRUPTED.",
-                source, target
+                "Deleting template {:?} -> {:?} but {}. Skipping.",
+                source, target, comparison
             );
-            error!("This is probably a bug. Delete cache.toml and cache/ folder.");
             Ok(false)
         }
-        TemplateComparison::Changed | TemplateComparison::TargetNotRegularFile if force => {
+    }
+}
+
+/// Returns true if copy should be deleted from cache
+pub fn delete_copy(
+    source: &Path,
+    cached: &CachedFile,
+    fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
+    force: bool,
+) -> Result<bool> {
+    let target = &cached.target;
+    info!("{} copy {:?} -> {:?}", "[-]".red(), source, target);
+
+    let comparison = fs
+        .compare_contents(target, Some(&cached.hash), cached.stamp())
+        .context("detect copied file's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        TemplateComparison::Identical => {
+            debug!("Performing deletion");
+            perform_template_target_deletion(fs, target).context("perform copy target deletion")?;
+            Ok(true)
+        }
+        TemplateComparison::OnlyCacheExists | TemplateComparison::BothMissing => {
             warn!(
-                "Deleting template {:?} -> {:?} but {}. Forcing.",
+                "Deleting copy {:?} -> {:?} but target doesn't exist. Removing from cache anyways.",
+                source, target
+            );
+            Ok(true)
+        }
+        TemplateComparison::Changed
+        | TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::OnlyTargetExists
+            if force =>
+        {
+            warn!(
+                "Deleting copy {:?} -> {:?} but {}. Forcing.",
                 source, target, comparison
             );
-            perform_cache_deletion(fs, cache).context("perform cache deletion")?;
-            perform_template_target_deletion(fs, target)
-                .context("perform template target deletion")?;
+            backups
+                .backup(fs, target)
+                .context("back up target file while forcing")?;
+            fs.delete_parents(target, false)
+                .context("delete parent directory in target location")?;
             Ok(true)
         }
-        TemplateComparison::Changed | TemplateComparison::TargetNotRegularFile => {
+        TemplateComparison::Changed
+        | TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::OnlyTargetExists => {
             error!(
-                "Deleting template {:?} -> {:?} but {}. Skipping.",
+                "Deleting copy {:?} -> {:?} but {}. Skipping.",
                 source, target, comparison
             );
             Ok(false)
@@ -218,7 +622,11 @@ pub fn delete_template(
     }
 }
 
+/// Deletes the cached copy of a template's render, if one was kept
 fn perform_cache_deletion(fs: &mut dyn Filesystem, cache: &Path) -> Result<()> {
+    if !fs.exists(cache) {
+        return Ok(());
+    }
     fs.remove_file(cache).context("delete template cache")?;
     fs.delete_parents(cache, true)
         .context("delete parent directory in cache")?;
@@ -239,6 +647,7 @@ pub fn create_symlink(
     source: &Path,
     target: &SymbolicTarget,
     fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
     force: bool,
 ) -> Result<bool> {
     info!(
@@ -256,15 +665,15 @@ pub fn create_symlink(
     match comparison {
         SymlinkComparison::OnlySourceExists => {
             debug!("Performing creation");
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
+            create_parents(
+                fs,
+                &target.target,
                 &target.owner,
+                &target.group,
+                target.dir_mode,
             )
             .context("create parent for target file")?;
-            fs.make_symlink(&target.target, source, &target.owner)
+            fs.make_symlink(&target.target, source, &target.owner, &target.group)
                 .context("create target symlink")?;
             Ok(true)
         }
@@ -284,9 +693,10 @@ pub fn create_symlink(
                 "Creating symlink {:?} -> {:?} but {}. Forcing.",
                 source, target.target, comparison
             );
-            fs.remove_file(&target.target)
-                .context("remove symlink target while forcing")?;
-            fs.make_symlink(&target.target, source, &target.owner)
+            backups
+                .backup(fs, &target.target)
+                .context("back up symlink target while forcing")?;
+            fs.make_symlink(&target.target, source, &target.owner, &target.group)
                 .context("create target symlink")?;
             Ok(true)
         }
@@ -300,15 +710,81 @@ pub fn create_symlink(
     }
 }
 
+/// Returns true if hard link should be added to cache
+pub fn create_hardlink(
+    source: &Path,
+    target: &HardlinkTarget,
+    fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
+    force: bool,
+) -> Result<bool> {
+    info!(
+        "{} hardlink {:?} -> {:?}",
+        "[+]".green(),
+        source,
+        target.target
+    );
+
+    let comparison = fs
+        .compare_hardlink(source, &target.target)
+        .context("detect hard link's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        HardlinkComparison::OnlySourceExists => {
+            debug!("Performing creation");
+            create_parents(fs, &target.target, &None, &None, target.dir_mode)
+                .context("create parent for target file")?;
+            fs.make_hardlink(&target.target, source)
+                .context("create target hard link")?;
+            Ok(true)
+        }
+        HardlinkComparison::Identical => {
+            warn!("Creating hardlink {:?} -> {:?} but target is already a hard link to source. Adding to cache anyways", source, target.target);
+            Ok(true)
+        }
+        HardlinkComparison::OnlyTargetExists | HardlinkComparison::BothMissing => {
+            error!(
+                "Creating hardlink {:?} -> {:?} but {}. Skipping.",
+                source, target.target, comparison
+            );
+            Ok(false)
+        }
+        HardlinkComparison::Changed | HardlinkComparison::TargetNotRegularFile if force => {
+            warn!(
+                "Creating hardlink {:?} -> {:?} but {}. Forcing.",
+                source, target.target, comparison
+            );
+            backups
+                .backup(fs, &target.target)
+                .context("back up hard link target while forcing")?;
+            fs.make_hardlink(&target.target, source)
+                .context("create target hard link")?;
+            Ok(true)
+        }
+        HardlinkComparison::Changed | HardlinkComparison::TargetNotRegularFile => {
+            error!(
+                "Creating hardlink {:?} -> {:?} but {}. Skipping.",
+                source, target.target, comparison
+            );
+            Ok(false)
+        }
+    }
+}
+
 /// Returns true if the template should be added to cache
+#[allow(clippy::too_many_arguments)]
 pub fn create_template(
     source: &Path,
     cache: &Path,
     target: &TemplateTarget,
+    cached: &mut CachedFile,
     fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
     force: bool,
+    merge: MergeStrategy,
 ) -> Result<bool> {
     info!(
         "{} template {:?} -> {:?}",
@@ -317,41 +793,105 @@ pub fn create_template(
         target.target
     );
 
+    let keep_copy = keeps_copy(target, merge);
     let comparison = fs
-        .compare_template(&target.target, cache)
+        .compare_contents(&target.target, None, None)
         .context("detect templated file's current state")?;
     debug!("Current state: {}", comparison);
 
     match comparison {
-        TemplateComparison::BothMissing => {
+        // Without a hash to compare with, the target is either missing or unknown
+        TemplateComparison::BothMissing
+        | TemplateComparison::OnlyCacheExists
+        | TemplateComparison::Identical => {
             debug!("Performing creation");
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
+            create_parents(
+                fs,
+                &target.target,
                 &target.owner,
+                &target.group,
+                target.dir_mode,
             )
             .context("create parent for target file")?;
-            perform_template_deploy(source, cache, target, fs, handlebars, variables)
-                .context("perform template cache")?;
+            *cached = perform_template_deploy(
+                source, cache, target, fs, handlebars, variables, keep_copy,
+            )
+            .context("perform template cache")?;
             Ok(true)
         }
-        TemplateComparison::OnlyCacheExists | TemplateComparison::Identical => {
+        TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::Changed
+        | TemplateComparison::OnlyTargetExists
+            if force =>
+        {
             warn!(
-                "Creating template {:?} -> {:?} but cache file already exists. This is probably a result of an error in the last run.",
+                "Creating template {:?} -> {:?} but target file already exists. Forcing.",
                 source, target.target
             );
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
+            backups
+                .backup(fs, &target.target)
+                .context("back up existing file while forcing")?;
+            create_parents(
+                fs,
+                &target.target,
                 &target.owner,
+                &target.group,
+                target.dir_mode,
             )
             .context("create parent for target file")?;
-            perform_template_deploy(source, cache, target, fs, handlebars, variables)
-                .context("perform template cache")?;
+            *cached = perform_template_deploy(
+                source, cache, target, fs, handlebars, variables, keep_copy,
+            )
+            .context("perform template cache")?;
+            Ok(true)
+        }
+        TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::Changed
+        | TemplateComparison::OnlyTargetExists => {
+            error!(
+                "Creating template {:?} -> {:?} but target file already exists. Skipping.",
+                source, target.target
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Returns true if copy should be added to cache
+pub fn create_copy(
+    source: &Path,
+    target: &CopyTarget,
+    cached: &mut CachedFile,
+    fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
+    force: bool,
+) -> Result<bool> {
+    info!("{} copy {:?} -> {:?}", "[+]".green(), source, target.target);
+
+    let source_hash = filesystem::hash_contents(&fs.read(source).context("read source file")?);
+    let comparison = fs
+        .compare_contents(&target.target, Some(&source_hash), None)
+        .context("detect copied file's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        TemplateComparison::OnlyCacheExists | TemplateComparison::BothMissing => {
+            debug!("Performing creation");
+            create_parents(
+                fs,
+                &target.target,
+                &target.owner,
+                &target.group,
+                target.dir_mode,
+            )
+            .context("create parent for target file")?;
+            *cached = perform_copy_deploy(source, target, fs).context("perform copy")?;
+            Ok(true)
+        }
+        TemplateComparison::Identical => {
+            warn!("Creating copy {:?} -> {:?} but target already has the same contents. Adding to cache anyways", source, target.target);
+            let stamp = fs.stamp(&target.target);
+            *cached = CachedFile::new(target.target.clone(), source_hash, stamp);
             Ok(true)
         }
         TemplateComparison::TargetNotRegularFile
@@ -360,28 +900,28 @@ pub fn create_template(
             if force =>
         {
             warn!(
-                "Creating template {:?} -> {:?} but target file already exists. Forcing.",
+                "Creating copy {:?} -> {:?} but target file already exists. Forcing.",
                 source, target.target
             );
-            fs.remove_file(&target.target)
-                .context("remove existing file while forcing")?;
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
+            backups
+                .backup(fs, &target.target)
+                .context("back up existing file while forcing")?;
+            create_parents(
+                fs,
+                &target.target,
                 &target.owner,
+                &target.group,
+                target.dir_mode,
             )
             .context("create parent for target file")?;
-            perform_template_deploy(source, cache, target, fs, handlebars, variables)
-                .context("perform template cache")?;
+            *cached = perform_copy_deploy(source, target, fs).context("perform copy")?;
             Ok(true)
         }
         TemplateComparison::TargetNotRegularFile
         | TemplateComparison::Changed
         | TemplateComparison::OnlyTargetExists => {
             error!(
-                "Creating template {:?} -> {:?} but target file already exists. Skipping.",
+                "Creating copy {:?} -> {:?} but target file already exists. Skipping.",
                 source, target.target
             );
             Ok(false)
@@ -396,6 +936,7 @@ pub fn update_symlink(
     source: &Path,
     target: &SymbolicTarget,
     fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
     force: bool,
 ) -> Result<bool> {
     debug!("Updating symlink {:?} -> {:?}...", source, target.target);
@@ -408,7 +949,7 @@ pub fn update_symlink(
     match comparison {
         SymlinkComparison::Identical => {
             debug!("Performing update");
-            fs.set_owner(&target.target, &target.owner)
+            fs.set_owner(&target.target, &target.owner, &target.group)
                 .context("set target symlink owner")?;
             Ok(true)
         }
@@ -424,9 +965,10 @@ pub fn update_symlink(
                 "Updating symlink {:?} -> {:?} but {}. Forcing.",
                 source, target.target, comparison
             );
-            fs.remove_file(&target.target)
-                .context("remove symlink target while forcing")?;
-            fs.make_symlink(&target.target, source, &target.owner)
+            backups
+                .backup(fs, &target.target)
+                .context("back up symlink target while forcing")?;
+            fs.make_symlink(&target.target, source, &target.owner, &target.group)
                 .context("create target symlink")?;
             Ok(true)
         }
@@ -442,36 +984,98 @@ pub fn update_symlink(
                 "Updating symlink {:?} -> {:?} but {}. Creating it anyways.",
                 source, target.target, comparison
             );
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
+            create_parents(
+                fs,
+                &target.target,
                 &target.owner,
+                &target.group,
+                target.dir_mode,
             )
             .context("create parent for target file")?;
-            fs.make_symlink(&target.target, source, &target.owner)
+            fs.make_symlink(&target.target, source, &target.owner, &target.group)
                 .context("create target symlink")?;
             Ok(true)
         }
     }
 }
 
+/// Returns true if the hard link wasn't skipped
+pub fn update_hardlink(
+    source: &Path,
+    target: &HardlinkTarget,
+    fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
+    force: bool,
+) -> Result<bool> {
+    debug!("Updating hardlink {:?} -> {:?}...", source, target.target);
+
+    let comparison = fs
+        .compare_hardlink(source, &target.target)
+        .context("detect hard link's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        HardlinkComparison::Identical => Ok(true),
+        HardlinkComparison::OnlyTargetExists | HardlinkComparison::BothMissing => {
+            error!(
+                "Updating hardlink {:?} -> {:?} but source is missing. Skipping.",
+                source, target.target
+            );
+            Ok(false)
+        }
+        HardlinkComparison::Changed | HardlinkComparison::TargetNotRegularFile if force => {
+            warn!(
+                "Updating hardlink {:?} -> {:?} but {}. Forcing.",
+                source, target.target, comparison
+            );
+            backups
+                .backup(fs, &target.target)
+                .context("back up hard link target while forcing")?;
+            fs.make_hardlink(&target.target, source)
+                .context("create target hard link")?;
+            Ok(true)
+        }
+        HardlinkComparison::Changed | HardlinkComparison::TargetNotRegularFile => {
+            error!(
+                "Updating hardlink {:?} -> {:?} but {}. Skipping.",
+                source, target.target, comparison
+            );
+            Ok(false)
+        }
+        HardlinkComparison::OnlySourceExists => {
+            warn!(
+                "Updating hardlink {:?} -> {:?} but {}. Creating it anyways.",
+                source, target.target, comparison
+            );
+            create_parents(fs, &target.target, &None, &None, target.dir_mode)
+                .context("create parent for target file")?;
+            fs.make_hardlink(&target.target, source)
+                .context("create target hard link")?;
+            Ok(true)
+        }
+    }
+}
+
 /// Returns true if the template was not skipped
 #[allow(clippy::too_many_arguments)]
 pub fn update_template(
     source: &Path,
     cache: &Path,
     target: &TemplateTarget,
+    cached: &mut CachedFile,
     fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
     force: bool,
+    merge: MergeStrategy,
     diff_context_lines: usize,
+    diff_sink: DiffSink,
 ) -> Result<bool> {
     debug!("Updating template {:?} -> {:?}...", source, target.target);
+    let keep_copy = keeps_copy(target, merge);
     let comparison = fs
-        .compare_template(&target.target, cache)
+        .compare_contents(&target.target, Some(&cached.hash), cached.stamp())
         .context("detect templated file's current state")?;
     debug!("Current state: {}", comparison);
 
@@ -481,42 +1085,44 @@ pub fn update_template(
             difference::print_template_diff(
                 source,
                 target,
+                fs,
                 handlebars,
                 variables,
                 diff_context_lines,
+                diff_sink,
             );
-            fs.set_owner(&target.target, &target.owner)
+            fs.set_owner(&target.target, &target.owner, &target.group)
                 .context("set target file owner")?;
-            perform_template_deploy(source, cache, target, fs, handlebars, variables)
-                .context("perform template cache")?;
+            *cached = perform_template_deploy(
+                source, cache, target, fs, handlebars, variables, keep_copy,
+            )
+            .context("perform template cache")?;
             Ok(true)
         }
-        TemplateComparison::OnlyCacheExists => {
+        TemplateComparison::OnlyCacheExists | TemplateComparison::BothMissing => {
             warn!(
                 "Updating template {:?} -> {:?} but target is missing. Creating it anyways.",
                 source, target.target
             );
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
+            create_parents(
+                fs,
+                &target.target,
                 &target.owner,
+                &target.group,
+                target.dir_mode,
             )
             .context("create parent for target file")?;
-            perform_template_deploy(source, cache, target, fs, handlebars, variables)
-                .context("perform template cache")?;
-            Ok(true)
-        }
-        TemplateComparison::OnlyTargetExists | TemplateComparison::BothMissing => {
-            error!(
-                "Updating template {:?} -> {:?} but cache is missing. Cache is CORRUPTED.",
-                source, target.target
-            );
-            error!("This is probably a bug. Delete cache.toml and cache/ folder.");
+            *cached = perform_template_deploy(
+                source, cache, target, fs, handlebars, variables, keep_copy,
+            )
+            .context("perform template cache")?;
             Ok(true)
         }
-        TemplateComparison::Changed | TemplateComparison::TargetNotRegularFile if force => {
+        TemplateComparison::Changed
+        | TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::OnlyTargetExists
+            if force =>
+        {
             warn!(
                 "Updating template {:?} -> {:?} but {}. Forcing.",
                 source, target.target, comparison
@@ -524,34 +1130,47 @@ pub fn update_template(
             difference::print_template_diff(
                 source,
                 target,
+                fs,
                 handlebars,
                 variables,
                 diff_context_lines,
+                diff_sink,
             );
-            fs.remove_file(&target.target)
-                .context("remove target while forcing")?;
-            perform_template_deploy(source, cache, target, fs, handlebars, variables)
-                .context("perform template cache")?;
+            backups
+                .backup(fs, &target.target)
+                .context("back up target while forcing")?;
+            *cached = perform_template_deploy(
+                source, cache, target, fs, handlebars, variables, keep_copy,
+            )
+            .context("perform template cache")?;
             Ok(true)
         }
-        TemplateComparison::Changed => {
+        TemplateComparison::Changed | TemplateComparison::OnlyTargetExists => {
             // At this point, we're not sure if there's a difference between the rendered source
             // and target, only that the target has been modified in some way.
-            let diff = generate_template_diff(source, target, handlebars, variables, false)
+            let diff = generate_template_diff(source, target, fs, handlebars, variables, false)
                 .context("diff source and target")?;
-            if diff_nonempty(&diff) {
+            let merge = target.merge.unwrap_or(merge);
+            if diff_nonempty(&diff) && merge != MergeStrategy::Refuse {
+                perform_template_merge(
+                    source, cache, target, cached, fs, handlebars, variables, merge,
+                )
+                .context("perform template merge")
+            } else if diff_nonempty(&diff) {
                 error!(
                     "Updating template {:?} -> {:?} but {}. Skipping",
                     source, target.target, comparison
                 );
                 if log_enabled!(log::Level::Info) {
                     info!("Refusing because of the following changes in target location: ");
-                    print_diff(diff, diff_context_lines);
+                    print_diff(diff, diff_context_lines, diff_sink);
                 }
                 Ok(false)
             } else {
-                perform_template_deploy(source, cache, target, fs, handlebars, variables)
-                    .context("perform template cache")?;
+                *cached = perform_template_deploy(
+                    source, cache, target, fs, handlebars, variables, keep_copy,
+                )
+                .context("perform template cache")?;
                 Ok(true)
             }
         }
@@ -566,6 +1185,149 @@ pub fn update_template(
     }
 }
 
+/// Returns true if the copy wasn't skipped
+pub fn update_copy(
+    source: &Path,
+    target: &CopyTarget,
+    cached: &mut CachedFile,
+    fs: &mut dyn Filesystem,
+    backups: &mut BackupStore,
+    force: bool,
+) -> Result<bool> {
+    debug!("Updating copy {:?} -> {:?}...", source, target.target);
+
+    let comparison = fs
+        .compare_contents(&target.target, Some(&cached.hash), cached.stamp())
+        .context("detect copied file's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        TemplateComparison::Identical => {
+            fs.set_owner(&target.target, &target.owner, &target.group)
+                .context("set target file owner")?;
+            if filesystem::hash_contents(&fs.read(source).context("read source file")?)
+                == cached.hash
+            {
+                debug!("Source is unchanged");
+                set_target_mode(source, &target.target, target.mode, &target.owner, fs)?;
+                // The target is known to be unchanged, so this can't hide changes to it
+                *cached = CachedFile::new(
+                    target.target.clone(),
+                    cached.hash.clone(),
+                    fs.stamp(&target.target),
+                );
+            } else {
+                info!(
+                    "{} copy {:?} -> {:?}",
+                    "[~]".yellow(),
+                    source,
+                    target.target
+                );
+                *cached = perform_copy_deploy(source, target, fs).context("perform copy")?;
+            }
+            Ok(true)
+        }
+        TemplateComparison::OnlyCacheExists | TemplateComparison::BothMissing => {
+            warn!(
+                "Updating copy {:?} -> {:?} but target is missing. Creating it anyways.",
+                source, target.target
+            );
+            create_parents(
+                fs,
+                &target.target,
+                &target.owner,
+                &target.group,
+                target.dir_mode,
+            )
+            .context("create parent for target file")?;
+            *cached = perform_copy_deploy(source, target, fs).context("perform copy")?;
+            Ok(true)
+        }
+        TemplateComparison::Changed
+        | TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::OnlyTargetExists
+            if force =>
+        {
+            warn!(
+                "Updating copy {:?} -> {:?} but {}. Forcing.",
+                source, target.target, comparison
+            );
+            backups
+                .backup(fs, &target.target)
+                .context("back up existing file while forcing")?;
+            *cached = perform_copy_deploy(source, target, fs).context("perform copy")?;
+            Ok(true)
+        }
+        TemplateComparison::Changed
+        | TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::OnlyTargetExists => {
+            error!(
+                "Updating copy {:?} -> {:?} but {}. Skipping.",
+                source, target.target, comparison
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Returns what the target was copied with
+fn perform_copy_deploy(
+    source: &Path,
+    target: &CopyTarget,
+    fs: &mut dyn Filesystem,
+) -> Result<CachedFile> {
+    let hash = filesystem::hash_contents(&fs.read(source).context("read source file")?);
+    fs.copy_file(source, &target.target, &target.owner, &target.group)
+        .context("copy source to target")?;
+    set_target_mode(source, &target.target, target.mode, &target.owner, fs)?;
+
+    let stamp = fs.stamp(&target.target);
+    Ok(CachedFile::new(target.target.clone(), hash, stamp))
+}
+
+/// Sets the target's mode if one is configured, otherwise copies it from the source
+fn set_target_mode(
+    source: &Path,
+    target: &Path,
+    mode: Option<Mode>,
+    owner: &Option<UnixUser>,
+    fs: &mut dyn Filesystem,
+) -> Result<()> {
+    match mode {
+        Some(Mode(mode)) => fs
+            .set_mode(target, mode, owner)
+            .context("set mode of target"),
+        None => fs
+            .copy_permissions(source, target, owner)
+            .context("copy permissions from source to target"),
+    }
+}
+
+/// Whether the target's mode differs from the one deploying gives it: the configured one, or
+/// else the source's. Modes that can't be read never differ
+pub(crate) fn mode_differs(
+    source: &Path,
+    target: &Path,
+    mode: Option<Mode>,
+    fs: &mut dyn Filesystem,
+) -> bool {
+    let expected = match mode {
+        Some(Mode(mode)) => Some(mode),
+        None => fs.mode(source),
+    };
+    match (expected, fs.mode(target)) {
+        (Some(expected), Some(actual)) => expected != actual,
+        _ => false,
+    }
+}
+
+/// Whether a copy of the template's render has to be kept as the base for merging changes
+pub(crate) fn keeps_copy(target: &TemplateTarget, merge: MergeStrategy) -> bool {
+    target.merge.unwrap_or(merge) != MergeStrategy::Refuse
+}
+
+/// Renders the template to the target through the cache, which only keeps the render if
+/// `keep_copy` is set. Returns what the target was deployed with
 pub(crate) fn perform_template_deploy(
     source: &Path,
     cache: &Path,
@@ -573,26 +1335,140 @@ pub(crate) fn perform_template_deploy(
     fs: &mut dyn Filesystem,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
-) -> Result<()> {
-    let file_contents = fs
-        .read_to_string(source)
-        .context("read template source file")?;
-    let file_contents = target.apply_actions(file_contents);
-    let rendered = handlebars
-        .render_template(&file_contents, variables)
-        .context("render template")?;
+    keep_copy: bool,
+) -> Result<CachedFile> {
+    let rendered = render_source(source, target, fs, handlebars, variables)?;
+    let hash = filesystem::hash_contents(&rendered);
 
     // Cache
-    fs.create_dir_all(cache.parent().context("get parent of cache file")?, &None)
-        .context("create parent for cache file")?;
+    fs.create_dir_all(
+        cache.parent().context("get parent of cache file")?,
+        &None,
+        &None,
+    )
+    .context("create parent for cache file")?;
     fs.write(cache, rendered)
         .context("write rendered template to cache")?;
 
     // Target
-    fs.copy_file(cache, &target.target, &target.owner)
+    fs.copy_file(cache, &target.target, &target.owner, &target.group)
         .context("copy template from cache to target")?;
-    fs.copy_permissions(source, &target.target, &target.owner)
-        .context("copy permissions from source to target")?;
+    set_target_mode(source, &target.target, target.mode, &target.owner, fs)?;
+
+    if !keep_copy {
+        perform_cache_deletion(fs, cache).context("perform cache deletion")?;
+    }
+
+    let stamp = fs.stamp(&target.target);
+    Ok(CachedFile::new(target.target.clone(), hash, stamp))
+}
 
+/// Creates the parent directories of `target`, setting the mode of the ones
+/// that didn't exist before if `dir_mode` is given
+fn create_parents(
+    fs: &mut dyn Filesystem,
+    target: &Path,
+    owner: &Option<UnixUser>,
+    group: &Option<UnixGroup>,
+    dir_mode: Option<Mode>,
+) -> Result<()> {
+    let parent = target.parent().context("get parent of target file")?;
+    // Innermost first, so that a restrictive mode doesn't lock us out of the rest
+    let mut missing = Vec::new();
+    if dir_mode.is_some() {
+        for dir in parent.ancestors() {
+            if dir.as_os_str().is_empty() || fs.exists(dir) {
+                break;
+            }
+            missing.push(dir.to_path_buf());
+        }
+    }
+
+    fs.create_dir_all(parent, owner, group)?;
+
+    if let Some(Mode(mode)) = dir_mode {
+        for dir in missing {
+            fs.set_mode(&dir, mode, owner)
+                .with_context(|| format!("set mode of directory {:?}", dir))?;
+        }
+    }
     Ok(())
 }
+
+/// Merges the changes made to the target since the last deploy with the newly rendered template,
+/// using the cached render as the common base.
+/// Returns true if the target was updated
+#[allow(clippy::too_many_arguments)]
+fn perform_template_merge(
+    source: &Path,
+    cache: &Path,
+    target: &TemplateTarget,
+    cached: &mut CachedFile,
+    fs: &mut dyn Filesystem,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+    merge: MergeStrategy,
+) -> Result<bool> {
+    let text = |contents: Vec<u8>| {
+        String::from_utf8(contents).context("contents aren't valid UTF-8, so they can't be merged")
+    };
+    if !fs.exists(cache) {
+        error!(
+            "Updating template {:?} -> {:?} but no render was kept to merge the changes in target with. Skipping. Deploy it with --force once so that one is kept from then on",
+            source, target.target
+        );
+        return Ok(false);
+    }
+    let base = text(fs.read(cache).context("read cached template")?)?;
+    let ours = text(fs.read(&target.target).context("read template target")?)?;
+    let theirs = text(render_source(source, target, fs, handlebars, variables)?)?;
+
+    let merged = merge3(&base, &ours, &theirs);
+    if merged.conflicts > 0 && merge == MergeStrategy::Merge {
+        error!(
+            "Updating template {:?} -> {:?} but changes in target conflict with changes in source. Skipping",
+            source, target.target
+        );
+        return Ok(false);
+    }
+
+    if merged.conflicts > 0 {
+        warn!(
+            "Merging template {:?} -> {:?} produced {} conflict(s). Resolve the conflict markers in the target.",
+            source, target.target, merged.conflicts
+        );
+    } else {
+        info!(
+            "{} template {:?} -> {:?} (merged changes in target)",
+            "[~]".yellow(),
+            source,
+            target.target
+        );
+    }
+
+    // Go through the cache so that the target is written with the right owner,
+    // then leave the new render there as the base for the next merge
+    fs.write(cache, merged.text.into_bytes())
+        .context("write merged template to cache")?;
+    fs.copy_file(cache, &target.target, &target.owner, &target.group)
+        .context("copy merged template from cache to target")?;
+    set_target_mode(source, &target.target, target.mode, &target.owner, fs)?;
+    let hash = filesystem::hash_contents(theirs.as_bytes());
+    fs.write(cache, theirs.into_bytes())
+        .context("write rendered template to cache")?;
+
+    // The target doesn't have the rendered contents, so its stamp can't vouch for them
+    *cached = CachedFile::new(target.target.clone(), hash, None);
+    Ok(true)
+}
+
+fn render_source(
+    source: &Path,
+    target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+) -> Result<Vec<u8>> {
+    let file_contents = fs.read(source).context("read template source file")?;
+    difference::render_contents(source, file_contents, target, handlebars, variables)
+}
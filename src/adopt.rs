@@ -0,0 +1,503 @@
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use handlebars::Handlebars;
+
+use std::path::{Path, PathBuf};
+
+use crate::args::Options;
+use crate::config::{
+    CachedFile, CopyTarget, HardlinkTarget, SymbolicTarget, TemplateTarget, Variables,
+};
+use crate::deploy::{desired_files, load_configuration_and_cache};
+use crate::difference::{
+    apply_hunk, diff_strings, hunkify_diff, inside_any, print_hunk, render_contents, DiffSink,
+};
+use crate::exit::{ExitStatus, Failure};
+use crate::filesystem::{
+    self, ask_boolean, DryRunFilesystem, Filesystem, HardlinkComparison, MemoryFilesystem,
+    RealFilesystem, SymlinkComparison, TemplateComparison,
+};
+use crate::handlebars_helpers::create_new_handlebars;
+
+/// Brings changes made directly to deployed files back into the repository.
+/// If `paths` is nonempty, only files whose source or target is inside one of them are adopted.
+/// Returns true if some changes could not be adopted
+pub fn adopt(opt: &Options, paths: &[PathBuf]) -> Result<bool> {
+    let (mut config, mut cache) = load_configuration_and_cache(opt)?;
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+    let desired = desired_files(config.files).context("sort files by how they're deployed")?;
+
+    let (mut real_fs, mut dry_run_fs, mut memory_fs);
+    let fs: &mut dyn Filesystem = if opt.in_memory {
+        memory_fs = MemoryFilesystem::read_through()
+            .context("set up in-memory filesystem")?
+            .with_root(opt.root.clone());
+        &mut memory_fs
+    } else if !opt.dry_run {
+        real_fs = RealFilesystem::new(opt.noconfirm, opt.escalate.unwrap_or_default())
+            .with_root(opt.root.clone());
+        &mut real_fs
+    } else {
+        dry_run_fs = DryRunFilesystem::new().with_root(opt.root.clone());
+        &mut dry_run_fs
+    };
+
+    let inside = inside_any(paths)?;
+    let selected = |source: &Path, target: &Path| paths.is_empty() || inside(source, target);
+
+    let mut incomplete = false;
+
+    for (source, target) in &desired.symlinks {
+        if cache.symlinks.get(source) != Some(&target.target) || !selected(source, &target.target) {
+            continue;
+        }
+        incomplete |= !adopt_symlink(source, target, fs, opt.noconfirm)
+            .with_context(|| format!("adopt symlink {:?} -> {:?}", source, target.target))?;
+    }
+
+    for (source, target) in &desired.hardlinks {
+        if cache.hardlinks.get(source) != Some(&target.target) || !selected(source, &target.target)
+        {
+            continue;
+        }
+        incomplete |= !adopt_hardlink(source, target, fs, opt.noconfirm)
+            .with_context(|| format!("adopt hardlink {:?} -> {:?}", source, target.target))?;
+    }
+
+    let mut cache_changed = false;
+    for (source, target) in &desired.templates {
+        let cached = match cache.templates.get_mut(source) {
+            Some(cached) if cached.target == target.target => cached,
+            _ => continue,
+        };
+        if !selected(source, &target.target) {
+            continue;
+        }
+        let previous_hash = cached.hash.clone();
+        incomplete |= !adopt_template(
+            source,
+            &opt.cache_directory.join(source),
+            target,
+            cached,
+            fs,
+            &handlebars,
+            &config.variables,
+            opt.noconfirm,
+            opt.diff_context_lines,
+        )
+        .with_context(|| format!("adopt template {:?} -> {:?}", source, target.target))?;
+        cache_changed |= cached.hash != previous_hash;
+    }
+
+    for (source, target) in &desired.copies {
+        let cached = match cache.copies.get_mut(source) {
+            Some(cached) if cached.target == target.target => cached,
+            _ => continue,
+        };
+        if !selected(source, &target.target) {
+            continue;
+        }
+        let previous = cached.clone();
+        incomplete |= !adopt_copy(source, target, cached, fs, opt.noconfirm)
+            .with_context(|| format!("adopt copy {:?} -> {:?}", source, target.target))?;
+        cache_changed |= *cached != previous;
+    }
+
+    if cache_changed && !opt.dry_run {
+        filesystem::save_file(&opt.cache_file, cache).context("save cache")?;
+    }
+
+    Ok(incomplete)
+}
+
+/// If the symlink was replaced by a regular file, copies that file into the source
+/// and restores the symlink.
+/// Returns true if there was nothing left to adopt
+fn adopt_symlink(
+    source: &Path,
+    target: &SymbolicTarget,
+    fs: &mut dyn Filesystem,
+    noconfirm: bool,
+) -> Result<bool> {
+    let comparison = fs
+        .compare_symlink(source, &target.target)
+        .context("detect symlink's current state")?;
+    debug!("Current state: {}", comparison);
+
+    // Only a regular file can be copied back into the source
+    let replaced_by_file = comparison == SymlinkComparison::TargetNotSymlink
+        && fs
+            .compare_contents(&target.target, None, None)
+            .context("detect target's current state")?
+            == TemplateComparison::OnlyTargetExists;
+
+    match comparison {
+        SymlinkComparison::TargetNotSymlink if replaced_by_file => {
+            info!(
+                "{} symlink {:?} <- {:?}",
+                "[<]".blue(),
+                source,
+                target.target
+            );
+            if !noconfirm
+                && !ask_boolean(&format!(
+                    "Target {:?} was replaced by a regular file. Copy it into {:?} [y/N]? ",
+                    target.target, source
+                ))
+            {
+                return Ok(false);
+            }
+            fs.copy_file(&target.target, source, &None, &None)
+                .context("copy target into source")?;
+            fs.remove_file(&target.target)
+                .context("remove adopted target")?;
+            fs.make_symlink(&target.target, source, &target.owner, &target.group)
+                .context("restore target symlink")?;
+            Ok(true)
+        }
+        SymlinkComparison::Identical | SymlinkComparison::OnlySourceExists => Ok(true),
+        _ => {
+            warn!(
+                "Not adopting symlink {:?} -> {:?} because {}",
+                source, target.target, comparison
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// If the hard link was replaced by a different file (like programs that save files by replacing
+/// them do), copies that file into the source and restores the hard link.
+/// Returns true if there was nothing left to adopt
+fn adopt_hardlink(
+    source: &Path,
+    target: &HardlinkTarget,
+    fs: &mut dyn Filesystem,
+    noconfirm: bool,
+) -> Result<bool> {
+    let comparison = fs
+        .compare_hardlink(source, &target.target)
+        .context("detect hard link's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        HardlinkComparison::Changed => {
+            info!(
+                "{} hardlink {:?} <- {:?}",
+                "[<]".blue(),
+                source,
+                target.target
+            );
+            if !noconfirm
+                && !ask_boolean(&format!(
+                    "Target {:?} was replaced by a different file. Copy it into {:?} [y/N]? ",
+                    target.target, source
+                ))
+            {
+                return Ok(false);
+            }
+            fs.copy_file(&target.target, source, &None, &None)
+                .context("copy target into source")?;
+            fs.remove_file(&target.target)
+                .context("remove adopted target")?;
+            fs.make_hardlink(&target.target, source)
+                .context("restore target hard link")?;
+            Ok(true)
+        }
+        HardlinkComparison::Identical | HardlinkComparison::OnlySourceExists => Ok(true),
+        _ => {
+            warn!(
+                "Not adopting hardlink {:?} -> {:?} because {}",
+                source, target.target, comparison
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// If the copy was modified since it was last deployed, copies it back into the source and
+/// refreshes `cached` with its contents.
+/// Returns true if there was nothing left to adopt
+fn adopt_copy(
+    source: &Path,
+    target: &CopyTarget,
+    cached: &mut CachedFile,
+    fs: &mut dyn Filesystem,
+    noconfirm: bool,
+) -> Result<bool> {
+    let comparison = fs
+        .compare_contents(&target.target, Some(&cached.hash), cached.stamp())
+        .context("detect copied file's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        TemplateComparison::Changed => {
+            info!("{} copy {:?} <- {:?}", "[<]".blue(), source, target.target);
+            if !noconfirm
+                && !ask_boolean(&format!(
+                    "Target {:?} was modified. Copy it into {:?} [y/N]? ",
+                    target.target, source
+                ))
+            {
+                return Ok(false);
+            }
+            fs.copy_file(&target.target, source, &None, &None)
+                .context("copy target into source")?;
+            let contents = fs.read(&target.target).context("read copy target")?;
+            *cached = CachedFile::new(
+                target.target.clone(),
+                filesystem::hash_contents(&contents),
+                fs.stamp(&target.target),
+            );
+            Ok(true)
+        }
+        TemplateComparison::Identical | TemplateComparison::OnlyCacheExists => Ok(true),
+        _ => {
+            warn!(
+                "Not adopting copy {:?} -> {:?} because {}",
+                source, target.target, comparison
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Interactively applies the changes made to the target since the last deploy onto the source,
+/// then refreshes `cached` (and the cached render, if one was kept) with the newly rendered source.
+/// Returns true if all of the changes were adopted
+#[allow(clippy::too_many_arguments)]
+fn adopt_template(
+    source: &Path,
+    cache: &Path,
+    target: &TemplateTarget,
+    cached: &mut CachedFile,
+    fs: &mut dyn Filesystem,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+    noconfirm: bool,
+    diff_context_lines: usize,
+) -> Result<bool> {
+    let comparison = fs
+        .compare_contents(&target.target, Some(&cached.hash), cached.stamp())
+        .context("detect templated file's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        TemplateComparison::Changed => {}
+        TemplateComparison::Identical | TemplateComparison::OnlyCacheExists => return Ok(true),
+        _ => {
+            warn!(
+                "Not adopting template {:?} -> {:?} because {}",
+                source, target.target, comparison
+            );
+            return Ok(false);
+        }
+    }
+
+    let text = |contents: Vec<u8>| {
+        String::from_utf8(contents)
+            .context("contents aren't valid UTF-8, so changes to them can't be adopted")
+    };
+    // What the target was deployed with, which is only known without a kept render if the source
+    // still renders to it
+    let keeps_copy = fs.exists(cache);
+    let deployed = if keeps_copy {
+        fs.read(cache).context("read cached template")?
+    } else {
+        let contents = fs.read(source).context("read template source")?;
+        let rendered = render_contents(source, contents, target, handlebars, variables)?;
+        if filesystem::hash_contents(&rendered) != cached.hash {
+            warn!(
+                "Not adopting template {:?} -> {:?} because the source changed since it was deployed, and no render was kept to tell which changes were made to the target",
+                source, target.target
+            );
+            return Ok(false);
+        }
+        rendered
+    };
+    let cache_contents = text(deployed)?;
+    let target_contents = text(fs.read(&target.target).context("read template target")?)?;
+    let original_source = text(fs.read(source).context("read template source")?)?;
+
+    info!(
+        "{} template {:?} <- {:?}",
+        "[<]".blue(),
+        source,
+        target.target
+    );
+
+    let hunks = hunkify_diff(
+        diff_strings(&cache_contents, &target_contents),
+        diff_context_lines,
+    );
+    let max_digits = hunks
+        .last()
+        .map(|(left, right, hunk)| {
+            (std::cmp::max(*left, *right) + hunk.len())
+                .to_string()
+                .len()
+        })
+        .unwrap_or(1);
+
+    let mut new_source = original_source.clone();
+    let mut adopted_all = true;
+    for (left_line, right_line, hunk) in hunks {
+        println!("{} {:?} -> {:?}", "[~]".yellow(), cache, target.target);
+        print_hunk(
+            left_line,
+            right_line,
+            hunk.clone(),
+            max_digits,
+            DiffSink::Stdout,
+        );
+        if !noconfirm && !ask_boolean(&format!("Apply this change to {:?} [y/N]? ", source)) {
+            adopted_all = false;
+            continue;
+        }
+        match apply_hunk(&new_source, &hunk) {
+            Some(applied) => new_source = applied,
+            None => {
+                warn!(
+                    "Could not find the original lines of this change in {:?} - they were probably produced by the template. Apply it manually.",
+                    source
+                );
+                adopted_all = false;
+            }
+        }
+    }
+
+    if new_source != original_source {
+        fs.write(source, new_source.clone().into_bytes())
+            .context("write adopted changes to source")?;
+    }
+
+    // The cache holds what the target looked like after the last deploy, so it should now
+    // reflect the updated source
+    let rendered = handlebars
+        .render_template(&target.apply_actions(new_source), variables)
+        .context(Failure::new(ExitStatus::Render, "render template"))?;
+    *cached = CachedFile::new(
+        target.target.clone(),
+        filesystem::hash_contents(rendered.as_bytes()),
+        None,
+    );
+    if keeps_copy {
+        fs.write(cache, rendered.into_bytes())
+            .context("write rendered template to cache")?;
+    }
+
+    Ok(adopted_all)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filesystem() -> MemoryFilesystem {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_dir_all(Path::new("/dots"), &None, &None).unwrap();
+        fs.create_dir_all(Path::new("/home/u"), &None, &None)
+            .unwrap();
+        fs
+    }
+
+    #[test]
+    fn adopt_replaced_symlink() {
+        let mut fs = filesystem();
+        let (source, target) = (Path::new("/dots/rc"), SymbolicTarget::from("/home/u/.rc"));
+        fs.write(source, "old".into()).unwrap();
+        fs.write(&target.target, "new".into()).unwrap();
+
+        assert!(adopt_symlink(source, &target, &mut fs, true).unwrap());
+        assert_eq!(fs.read(source).unwrap(), b"new");
+        assert_eq!(
+            fs.compare_symlink(source, &target.target).unwrap(),
+            SymlinkComparison::Identical
+        );
+
+        // A directory can't be copied into the source
+        fs.remove_file(&target.target).unwrap();
+        fs.create_dir_all(&target.target, &None, &None).unwrap();
+        assert!(!adopt_symlink(source, &target, &mut fs, true).unwrap());
+        assert_eq!(fs.read(source).unwrap(), b"new");
+    }
+
+    #[test]
+    fn adopt_modified_copy() {
+        let mut fs = filesystem();
+        let (source, target) = (Path::new("/dots/key"), CopyTarget::from("/home/u/.key"));
+        fs.write(source, "old".into()).unwrap();
+        fs.write(&target.target, "old".into()).unwrap();
+        let mut cached = CachedFile::new(
+            target.target.clone(),
+            filesystem::hash_contents(b"old"),
+            None,
+        );
+
+        // Nothing to adopt
+        assert!(adopt_copy(source, &target, &mut cached, &mut fs, true).unwrap());
+        assert_eq!(cached.hash, filesystem::hash_contents(b"old"));
+
+        fs.write(&target.target, "new".into()).unwrap();
+        assert!(adopt_copy(source, &target, &mut cached, &mut fs, true).unwrap());
+        assert_eq!(fs.read(source).unwrap(), b"new");
+        assert_eq!(cached.hash, filesystem::hash_contents(b"new"));
+        assert_eq!(
+            fs.compare_contents(&target.target, Some(&cached.hash), cached.stamp())
+                .unwrap(),
+            TemplateComparison::Identical
+        );
+
+        // A directory can't be copied into the source
+        fs.remove_file(&target.target).unwrap();
+        fs.create_dir_all(&target.target, &None, &None).unwrap();
+        assert!(!adopt_copy(source, &target, &mut cached, &mut fs, true).unwrap());
+        assert_eq!(fs.read(source).unwrap(), b"new");
+    }
+
+    #[test]
+    fn adopt_modified_template() {
+        let mut fs = filesystem();
+        let (source, target) = (
+            Path::new("/dots/conf"),
+            TemplateTarget::from("/home/u/.conf"),
+        );
+        fs.write(source, "a\nname={{name}}\nc\n".into()).unwrap();
+        fs.write(&target.target, "a\nname=x\nc\n".into()).unwrap();
+        let handlebars = Handlebars::new();
+        let variables = maplit::btreemap! { "name".into() => "x".into() };
+        let mut cached = CachedFile::new(
+            target.target.clone(),
+            filesystem::hash_contents(b"a\nname=x\nc\n"),
+            None,
+        );
+        let adopt = |fs: &mut MemoryFilesystem, cached: &mut CachedFile| {
+            adopt_template(
+                source,
+                Path::new("/cache/conf"),
+                &target,
+                cached,
+                fs,
+                &handlebars,
+                &variables,
+                true,
+                0,
+            )
+            .unwrap()
+        };
+
+        // Nothing to adopt
+        assert!(adopt(&mut fs, &mut cached));
+        assert_eq!(fs.read(source).unwrap(), b"a\nname={{name}}\nc\n");
+
+        fs.write(&target.target, "A\nname=x\nc\n".into()).unwrap();
+        assert!(adopt(&mut fs, &mut cached));
+        assert_eq!(fs.read(source).unwrap(), b"A\nname={{name}}\nc\n");
+        assert_eq!(cached.hash, filesystem::hash_contents(b"A\nname=x\nc\n"));
+
+        // The changed line was produced by the template, so it can't be found in the source
+        fs.write(&target.target, "A\nname=y\nc\n".into()).unwrap();
+        assert!(!adopt(&mut fs, &mut cached));
+        assert_eq!(fs.read(source).unwrap(), b"A\nname={{name}}\nc\n");
+    }
+}
@@ -1,11 +1,15 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use clap_complete::Shell;
 
+use crate::config::{Escalation, MergeStrategy};
+use crate::exit::EXIT_STATUS_HELP;
+
 /// A small dotfile manager.
 #[derive(Debug, Parser, Default, Clone)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, after_help = EXIT_STATUS_HELP)]
 pub struct Options {
     /// Location of the global configuration
     #[clap(
@@ -27,6 +31,13 @@ pub struct Options {
     )]
     pub local_config: PathBuf,
 
+    /// Deploy into this directory instead of the real root, like a chroot or a staging
+    /// directory for an image. Targets are placed inside it after `~` and variables are
+    /// expanded, symlinks point to where their sources are inside it, and it gets its own cache
+    /// (unless --cache-file and --cache-directory are given)
+    #[clap(long, value_parser = parse_root, global = true)]
+    pub root: Option<PathBuf>,
+
     /// Location of cache file
     #[clap(long, value_parser, default_value = ".dotter/cache.toml")]
     pub cache_file: PathBuf,
@@ -35,6 +46,11 @@ pub struct Options {
     #[clap(long, value_parser, default_value = ".dotter/cache")]
     pub cache_directory: PathBuf,
 
+    /// Location of the lock file that keeps two instances of Dotter from changing the
+    /// repository's deployment at the same time
+    #[clap(long, value_parser, default_value = ".dotter/lock")]
+    pub lock_file: PathBuf,
+
     /// Location of optional pre-deploy hook
     #[clap(long, value_parser, default_value = ".dotter/pre_deploy.sh")]
     pub pre_deploy: PathBuf,
@@ -56,6 +72,11 @@ pub struct Options {
     #[clap(short = 'd', long = "dry-run", global = true)]
     pub dry_run: bool,
 
+    /// Deploy into a filesystem that only exists in memory and starts out like the disk, which
+    /// unlike --dry-run keeps track of owners and modes. For testing. Implies --dry-run
+    #[clap(long, global = true, hide = true)]
+    pub in_memory: bool,
+
     /// Verbosity level - specify up to 3 times to get more detailed output.
     /// Specifying at least once prints the differences between what was before and after Dotter's run
     #[clap(short = 'v', long = "verbose", action = clap::ArgAction::Count, global = true)]
@@ -66,11 +87,32 @@ pub struct Options {
     pub quiet: bool,
 
     /// Force - instead of skipping, overwrite target files if their content is unexpected.
+    /// Overwritten files are backed up and can be brought back with `dotter restore`.
     /// Overrides --dry-run.
     #[clap(short, long, value_parser, global = true)]
     pub force: bool,
 
-    /// Assume "yes" instead of prompting when removing empty directories
+    /// What to do with templates whose target was modified since the last deploy, unless the
+    /// file specifies its own `merge` strategy
+    #[clap(long, value_enum, default_value = "refuse", global = true)]
+    pub merge: MergeStrategy,
+
+    /// How to elevate permissions when they're needed: with sudo, doas, run0 or pkexec, or never
+    /// (failing instead). Defaults to the `escalate` setting in local.toml, or sudo
+    #[clap(long, value_enum, global = true)]
+    pub escalate: Option<Escalation>,
+
+    /// Output format. With `json`, a JSON object describing every action and a final summary
+    /// is printed to stdout on its own line, and all logs go to stderr.
+    #[clap(long, value_enum, default_value = "text", global = true)]
+    pub output: OutputFormat,
+
+    /// Deploy all-or-nothing: if any file fails to deploy or the post-deploy hook fails, undo
+    /// every change made by this run and restore the previous cache.
+    #[clap(long, value_parser)]
+    pub transactional: bool,
+
+    /// Assume "yes" instead of prompting when removing empty directories or adopting changes
     #[clap(short = 'y', long = "noconfirm", global = true)]
     pub noconfirm: bool,
 
@@ -87,6 +129,13 @@ pub struct Options {
     pub action: Option<Action>,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
 #[derive(Debug, Clone, Subcommand, Default)]
 pub enum Action {
     /// Deploy the files to their respective targets. This is the default subcommand.
@@ -97,6 +146,62 @@ pub enum Action {
     /// Note that this operates on all files that are currently in cache.
     Undeploy,
 
+    /// Report which files are in sync, modified, missing or pending creation/deletion without
+    /// deploying anything. Exits with an error status if anything is out of sync.
+    Status,
+
+    /// Print the difference between each deployed template and its freshly rendered source
+    /// without deploying anything or running hooks.
+    Diff {
+        /// Only show templates whose source or target is inside one of these paths
+        #[clap(value_parser)]
+        paths: Vec<PathBuf>,
+
+        /// Only show templates declared by this package. Can be specified multiple times
+        #[clap(long = "package", value_parser)]
+        packages: Vec<String>,
+    },
+
+    /// Bring changes made directly to deployed files back into the repository.
+    /// Symlinks that were replaced by regular files and modified copies are copied back into their
+    /// source, and changes to templates since the last deploy are interactively applied to the template source.
+    #[clap(alias = "pull")]
+    Adopt {
+        /// Only adopt files whose source or target is inside one of these paths
+        #[clap(value_parser)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Print the operations `deploy` would perform without performing them, optionally saving
+    /// them to a file for `dotter apply`.
+    Plan {
+        /// File to save the plan into
+        #[clap(long, value_parser)]
+        out: Option<PathBuf>,
+    },
+
+    /// Perform exactly the operations in a plan saved by `dotter plan --out`.
+    /// Refuses to do anything if the files they affect changed since the plan was made.
+    Apply {
+        /// Location of the saved plan
+        #[clap(value_parser)]
+        plan: PathBuf,
+    },
+
+    /// List the files that were backed up before being overwritten or deleted by --force,
+    /// or move the backup with the given id back to its original location.
+    Restore {
+        /// Id of the backup to restore, as shown when listing the backups
+        #[clap(value_parser)]
+        id: Option<u64>,
+    },
+
+    /// Work on the cache that records what was deployed
+    Cache {
+        #[clap(subcommand)]
+        action: CacheAction,
+    },
+
     /// Initialize global.toml with a single package containing all the files in the current
     /// directory pointing to a dummy value and a local.toml that selects that package.
     Init,
@@ -117,8 +222,57 @@ pub enum Action {
     },
 }
 
+impl Options {
+    /// Whether the cache may be rewritten, like when upgrading it. Only actions that hold the
+    /// lock can do so, while the others only upgrade it in memory
+    pub fn writes_cache(&self) -> bool {
+        !self.dry_run && self.action.as_ref().is_none_or(Action::modifies_repository)
+    }
+}
+
+impl Action {
+    /// Whether the action changes the cache or deployed files, so it has to hold the lock
+    pub fn modifies_repository(&self) -> bool {
+        match self {
+            Action::Deploy
+            | Action::Undeploy
+            | Action::Adopt { .. }
+            | Action::Apply { .. }
+            | Action::Restore { .. }
+            | Action::Cache { .. }
+            | Action::Init
+            | Action::Watch => true,
+            Action::Status
+            | Action::Diff { .. }
+            | Action::Plan { .. }
+            | Action::GenCompletions { .. } => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum CacheAction {
+    /// Rebuild the cache from the configuration and the files that are actually deployed, for
+    /// when it got corrupted or lost. Files whose target doesn't match what would be deployed
+    /// are left out of it and reported.
+    Repair,
+}
+
 pub fn get_options() -> Options {
-    let mut opt = Options::parse();
+    let matches = Options::command().get_matches();
+    let mut opt = Options::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(root) = &opt.root {
+        let cache = root_cache_directory(root);
+        if matches.value_source("cache_file") == Some(ValueSource::DefaultValue) {
+            opt.cache_file = cache.join("cache.toml");
+        }
+        if matches.value_source("cache_directory") == Some(ValueSource::DefaultValue) {
+            opt.cache_directory = cache.join("cache");
+        }
+    }
+    if opt.in_memory {
+        opt.dry_run = true;
+    }
     if opt.dry_run {
         opt.verbosity = std::cmp::max(opt.verbosity, 1);
     }
@@ -128,3 +282,22 @@ pub fn get_options() -> Options {
     }
     opt
 }
+
+fn parse_root(root: &str) -> Result<PathBuf, String> {
+    crate::filesystem::real_path(Path::new(root))
+        .map_err(|e| format!("couldn't find root directory: {}", e))
+}
+
+/// Where the cache of deployments into `root` is kept, so it doesn't get mixed up with the cache
+/// of the real root or of other roots
+fn root_cache_directory(root: &Path) -> PathBuf {
+    use sha2::{Digest, Sha256};
+
+    let name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let hash = Sha256::digest(root.to_string_lossy().as_bytes());
+    let hash: String = hash.iter().take(4).map(|b| format!("{:02x}", b)).collect();
+    PathBuf::from(".dotter/roots").join(format!("{}-{}", name, hash))
+}
@@ -0,0 +1,256 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use std::path::{Path, PathBuf};
+
+use crate::args::Options;
+use crate::filesystem::{self, DryRunFilesystem, Filesystem, MemoryFilesystem, RealFilesystem};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Backup {
+    pub id: u64,
+    /// When the backup was made, in RFC 3339 format
+    pub created: String,
+    /// Where the file was before it was backed up
+    pub original: PathBuf,
+    /// Where the file is kept inside the backup directory
+    pub stored: PathBuf,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupManifest {
+    #[serde(default)]
+    pub backups: Vec<Backup>,
+}
+
+/// Keeps the files that get overwritten or deleted because of `--force`
+pub struct BackupStore {
+    directory: PathBuf,
+    manifest: BackupManifest,
+    changed: bool,
+}
+
+pub fn backup_directory(opt: &Options) -> PathBuf {
+    opt.cache_directory.join(".backups")
+}
+
+pub fn manifest_file(opt: &Options) -> PathBuf {
+    backup_directory(opt).join("manifest.toml")
+}
+
+impl BackupStore {
+    pub fn new(directory: PathBuf, manifest: BackupManifest) -> BackupStore {
+        BackupStore {
+            directory,
+            manifest,
+            changed: false,
+        }
+    }
+
+    /// Loads the manifest from the backup directory, if it exists
+    pub fn open(directory: PathBuf) -> Result<BackupStore> {
+        let manifest = filesystem::load_file(&directory.join("manifest.toml"))
+            .context("load backup manifest")?
+            .unwrap_or_default();
+        Ok(BackupStore::new(directory, manifest))
+    }
+
+    /// Moves `target` into the backup directory and records it in the manifest
+    pub fn backup(&mut self, fs: &mut dyn Filesystem, target: &Path) -> Result<()> {
+        let id = self
+            .manifest
+            .backups
+            .iter()
+            .map(|b| b.id + 1)
+            .max()
+            .unwrap_or(1);
+        let stored = self.directory.join(id.to_string()).join(
+            target
+                .file_name()
+                .with_context(|| format!("get file name of {:?}", target))?,
+        );
+        let created = OffsetDateTime::now_utc()
+            .replace_nanosecond(0)
+            .context("truncate current time")?
+            .format(&Rfc3339)
+            .context("format current time")?;
+
+        fs.create_dir_all(
+            stored.parent().context("get parent of backup location")?,
+            &None,
+            &None,
+        )
+        .context("create backup directory")?;
+        fs.move_file(target, &stored)
+            .context("move file into backup directory")?;
+        warn!("Backed up {:?} to {:?} (id {})", target, stored, id);
+
+        self.manifest.backups.push(Backup {
+            id,
+            created,
+            original: target.into(),
+            stored,
+        });
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Saves the manifest if any backups were added or removed
+    pub fn save(&self) -> Result<()> {
+        if self.changed {
+            filesystem::save_file(&self.directory.join("manifest.toml"), &self.manifest)
+                .context("save backup manifest")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists the backups if `id` is None, otherwise moves the selected backup back to
+/// its original location.
+pub fn restore(opt: &Options, id: Option<u64>) -> Result<()> {
+    let mut store = BackupStore::open(backup_directory(opt))?;
+
+    let id = match id {
+        Some(id) => id,
+        None => {
+            if store.manifest.backups.is_empty() {
+                println!("No backups.");
+            }
+            for backup in &store.manifest.backups {
+                println!(
+                    "{:>4}  {}  {:?}",
+                    backup.id, backup.created, backup.original
+                );
+            }
+            return Ok(());
+        }
+    };
+
+    let backup = store
+        .manifest
+        .backups
+        .iter()
+        .find(|b| b.id == id)
+        .cloned()
+        .with_context(|| format!("find backup with id {}", id))?;
+
+    let (mut real_fs, mut dry_run_fs, mut memory_fs);
+    let fs: &mut dyn Filesystem = if opt.in_memory {
+        memory_fs = MemoryFilesystem::read_through()
+            .context("set up in-memory filesystem")?
+            .with_root(opt.root.clone());
+        &mut memory_fs
+    } else if !opt.dry_run {
+        real_fs = RealFilesystem::new(opt.noconfirm, opt.escalate.unwrap_or_default())
+            .with_root(opt.root.clone());
+        &mut real_fs
+    } else {
+        dry_run_fs = DryRunFilesystem::new().with_root(opt.root.clone());
+        &mut dry_run_fs
+    };
+
+    if fs.exists(&backup.original) {
+        if opt.force {
+            warn!(
+                "Restoring {:?} but it already exists. Forcing.",
+                backup.original
+            );
+            store
+                .backup(fs, &backup.original)
+                .context("back up existing file while forcing")?;
+        } else {
+            anyhow::bail!(
+                "{:?} already exists. Use --force to replace it (it will be backed up)",
+                backup.original
+            );
+        }
+    }
+
+    info!("Restoring {:?} -> {:?}", backup.stored, backup.original);
+    fs.create_dir_all(
+        backup
+            .original
+            .parent()
+            .context("get parent of original location")?,
+        &None,
+        &None,
+    )
+    .context("create parent for original location")?;
+    fs.move_file(&backup.stored, &backup.original)
+        .context("move backup to its original location")?;
+    fs.delete_parents(&backup.stored, true)
+        .context("delete empty backup directory")?;
+
+    store.manifest.backups.retain(|b| b.id != id);
+    store.changed = true;
+    // The backup was only moved inside the in-memory filesystem, so the manifest still has it
+    if !opt.dry_run && !opt.in_memory {
+        store.save()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backup_moves_file() {
+        let mut fs = DryRunFilesystem::new();
+        fs.write(Path::new("dir/a"), "hello".into()).unwrap();
+        fs.write(Path::new("dir/b"), "world".into()).unwrap();
+
+        let mut store = BackupStore::new("backups".into(), BackupManifest::default());
+        store.backup(&mut fs, Path::new("dir/a")).unwrap();
+        store.backup(&mut fs, Path::new("dir/b")).unwrap();
+
+        fs.read(Path::new("dir/a")).unwrap_err();
+        assert_eq!(fs.read(Path::new("backups/1/a")).unwrap(), b"hello");
+        assert_eq!(fs.read(Path::new("backups/2/b")).unwrap(), b"world");
+
+        let ids = store
+            .manifest
+            .backups
+            .iter()
+            .map(|b| (b.id, b.original.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![(1, PathBuf::from("dir/a")), (2, PathBuf::from("dir/b"))]
+        );
+    }
+
+    #[test]
+    fn restore_in_memory_leaves_disk_alone() {
+        let directory = tempfile::tempdir().unwrap();
+        let opt = Options {
+            cache_directory: directory.path().join("cache"),
+            in_memory: true,
+            ..Options::default()
+        };
+        let original = directory.path().join("rc");
+        let stored = backup_directory(&opt).join("1/rc");
+        std::fs::create_dir_all(stored.parent().unwrap()).unwrap();
+        std::fs::write(&stored, "backed up").unwrap();
+        let manifest = BackupManifest {
+            backups: vec![Backup {
+                id: 1,
+                created: "2024-01-01T00:00:00Z".into(),
+                original: original.clone(),
+                stored: stored.clone(),
+            }],
+        };
+        filesystem::save_file(&manifest_file(&opt), &manifest).unwrap();
+        let saved = std::fs::read_to_string(manifest_file(&opt)).unwrap();
+
+        restore(&opt, Some(1)).unwrap();
+        assert!(original.symlink_metadata().is_err());
+        assert!(stored.exists());
+        assert_eq!(std::fs::read_to_string(manifest_file(&opt)).unwrap(), saved);
+    }
+}
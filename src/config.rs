@@ -4,8 +4,9 @@ use serde::{Deserialize, Serialize};
 use crate::filesystem;
 
 use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(untagged)]
@@ -14,14 +15,65 @@ pub enum UnixUser {
     Name(String),
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum UnixGroup {
+    Gid(i32),
+    Name(String),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(deny_unknown_fields)]
 pub struct SymbolicTarget {
     pub target: PathBuf,
     pub owner: Option<UnixUser>,
+    pub group: Option<UnixGroup>,
     pub recurse: Option<bool>,
     #[serde(rename = "if")]
     pub condition: Option<String>,
+    /// Mode of the target's parent directories, if they have to be created
+    pub dir_mode: Option<Mode>,
+    /// Command run after deploying, if the symlink had to be (re)created or the source was
+    /// edited since
+    pub on_change: Option<String>,
+}
+
+/// What to do when a template's target was modified since it was last deployed
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+    /// Skip the file, leaving the modified target in place
+    #[default]
+    Refuse,
+    /// Three-way merge the changes, skipping the file if they conflict
+    Merge,
+    /// Three-way merge the changes, writing conflict markers into the target if they conflict
+    Markers,
+}
+
+/// How to elevate permissions when the current user can't do something
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Escalation {
+    #[default]
+    Sudo,
+    Doas,
+    Run0,
+    Pkexec,
+    /// Fail instead of elevating permissions
+    Never,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -29,10 +81,90 @@ pub struct SymbolicTarget {
 pub struct TemplateTarget {
     pub target: PathBuf,
     pub owner: Option<UnixUser>,
+    pub group: Option<UnixGroup>,
     pub append: Option<String>,
     pub prepend: Option<String>,
     #[serde(rename = "if")]
     pub condition: Option<String>,
+    pub merge: Option<MergeStrategy>,
+    /// Mode of the target. If not set, it is copied from the source
+    pub mode: Option<Mode>,
+    /// Mode of the target's parent directories, if they have to be created
+    pub dir_mode: Option<Mode>,
+    /// Command run after deploying, if the target's contents changed
+    pub on_change: Option<String>,
+}
+
+/// A file which is deployed by copying its contents as they are, without rendering them
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(deny_unknown_fields)]
+pub struct CopyTarget {
+    pub target: PathBuf,
+    pub owner: Option<UnixUser>,
+    pub group: Option<UnixGroup>,
+    #[serde(rename = "if")]
+    pub condition: Option<String>,
+    /// Mode of the target. If not set, it is copied from the source
+    pub mode: Option<Mode>,
+    /// Mode of the target's parent directories, if they have to be created
+    pub dir_mode: Option<Mode>,
+    /// Command run after deploying, if the target's contents changed
+    pub on_change: Option<String>,
+}
+
+/// A file which is deployed by hard linking the target to the source,
+/// for programs that don't follow (or replace) symlinks
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(deny_unknown_fields)]
+pub struct HardlinkTarget {
+    pub target: PathBuf,
+    #[serde(rename = "if")]
+    pub condition: Option<String>,
+    /// Mode of the target's parent directories, if they have to be created
+    pub dir_mode: Option<Mode>,
+    /// Command run after deploying, if the hard link had to be (re)created or its contents
+    /// were edited since
+    pub on_change: Option<String>,
+}
+
+/// Unix file permissions, written in octal (like `mode = "0600"`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Mode(pub u32);
+
+impl std::str::FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Mode> {
+        let digits = s.strip_prefix("0o").unwrap_or(s);
+        let mode = u32::from_str_radix(digits, 8)
+            .with_context(|| format!("parse mode {:?} as an octal number", s))?;
+        anyhow::ensure!(mode <= 0o7777, "mode {:?} is out of range", s);
+        Ok(Mode(mode))
+    }
+}
+
+impl Serialize for Mode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:04o}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Mode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Mode, D::Error> {
+        // An integer like `mode = 644` is read as if it was written in octal
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            String(String),
+            Integer(u32),
+        }
+
+        let s = match Repr::deserialize(deserializer)? {
+            Repr::String(s) => s,
+            Repr::Integer(i) => i.to_string(),
+        };
+        s.parse().map_err(serde::de::Error::custom)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -42,6 +174,8 @@ pub enum FileTarget {
     Symbolic(SymbolicTarget),
     #[serde(rename = "template")]
     ComplexTemplate(TemplateTarget),
+    Copy(CopyTarget),
+    Hardlink(HardlinkTarget),
 }
 
 // Shims to allow Serde to represent FileTarget::Automatic as untagged while the
@@ -59,6 +193,8 @@ enum FileTargetInnerRepr {
     Symbolic(SymbolicTarget),
     #[serde(rename = "template")]
     ComplexTemplate(TemplateTarget),
+    Copy(CopyTarget),
+    Hardlink(HardlinkTarget),
 }
 
 pub type Files = BTreeMap<PathBuf, FileTarget>;
@@ -71,6 +207,10 @@ pub struct Configuration {
     pub variables: Variables,
     pub helpers: Helpers,
     pub packages: Vec<String>,
+    /// Files declared by each enabled package, before directories are expanded
+    pub package_files: BTreeMap<String, Vec<PathBuf>>,
+    /// Hooks of the enabled packages that have any, after the packages they depend on
+    pub hooks: Vec<PackageHooks>,
 
     /// If the source is a directory, or a symlink to a directory,
     /// and this option is true, the source will be recursed and
@@ -88,6 +228,55 @@ pub struct Package {
     files: Files,
     #[serde(default)]
     variables: Variables,
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    hooks: Hooks,
+}
+
+/// Hooks a package runs when it's deployed or undeployed, in addition to the global ones
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    pub pre_deploy: Option<Hook>,
+    pub post_deploy: Option<Hook>,
+    pub pre_undeploy: Option<Hook>,
+    pub post_undeploy: Option<Hook>,
+}
+
+impl Hooks {
+    fn is_empty(&self) -> bool {
+        *self == Hooks::default()
+    }
+
+    /// Replaces the hooks that `other` declares
+    fn extend(&mut self, other: Hooks) {
+        let Hooks {
+            pre_deploy,
+            post_deploy,
+            pre_undeploy,
+            post_undeploy,
+        } = other;
+        self.pre_deploy = pre_deploy.or(self.pre_deploy.take());
+        self.post_deploy = post_deploy.or(self.post_deploy.take());
+        self.pre_undeploy = pre_undeploy.or(self.pre_undeploy.take());
+        self.post_undeploy = post_undeploy.or(self.post_undeploy.take());
+    }
+}
+
+/// Either a command run by the shell, or a script that's rendered and run like the global hooks
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Hook {
+    Command(String),
+    Script { script: PathBuf },
+}
+
+/// An enabled package's hooks, with the variables they're rendered with: the package's own,
+/// overridden by local.toml and the patch like every other variable
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageHooks {
+    pub package: String,
+    pub hooks: Hooks,
+    pub variables: Variables,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -110,32 +299,22 @@ struct LocalConfig {
     files: Files,
     #[serde(default)]
     variables: Variables,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    escalate: Option<Escalation>,
 }
 
 pub fn load_configuration(
     local_config: &Path,
     global_config: &Path,
     patch: Option<Package>,
+    root: Option<&Path>,
 ) -> Result<Configuration> {
     let global: GlobalConfig = filesystem::load_file(global_config)
         .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
         .with_context(|| format!("load global config {:?}", global_config))?;
     trace!("Global config: {:#?}", global);
 
-    // If local.toml can't be found, look for a file named <hostname>.toml instead
-    let mut local_config_buf = local_config.to_path_buf();
-    if !local_config_buf.exists() {
-        let hostname = hostname::get()
-            .context("failed to get the computer hostname")?
-            .into_string()
-            .expect("hostname cannot be converted to string");
-        info!(
-            "{:?} not found, using {}.toml instead (based on hostname)",
-            local_config, hostname
-        );
-        local_config_buf.set_file_name(&format!("{}.toml", hostname));
-    }
-
+    let local_config_buf = local_config_path(local_config)?;
     let local: LocalConfig = filesystem::load_file(local_config_buf.as_path())
         .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
         .with_context(|| format!("load local config {:?}", local_config))?;
@@ -158,7 +337,10 @@ pub fn load_configuration(
             let path = shellexpand::full(&path.to_string_lossy())
                 .context("failed to expand file path")?
                 .to_string();
-            v.set_path(path);
+            match root {
+                Some(root) => v.set_path(rebase(root, Path::new(&path))),
+                None => v.set_path(path),
+            }
             Ok((k, v))
         })
         .collect::<Result<_, _>>()?;
@@ -170,11 +352,249 @@ pub fn load_configuration(
     Ok(merged_config)
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+/// Places a path inside `root`, as if `root` was the root directory.
+/// Relative paths are placed inside it as well. `..` is resolved first, and like in the root
+/// directory can't go above `root`
+fn rebase(root: &Path, path: &Path) -> PathBuf {
+    let mut inside = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {
+                inside.pop();
+            }
+            Component::Normal(part) => inside.push(part),
+        }
+    }
+    root.join(inside)
+}
+
+/// If local.toml can't be found, look for a file named <hostname>.toml instead
+fn local_config_path(local_config: &Path) -> Result<PathBuf> {
+    let mut local_config_buf = local_config.to_path_buf();
+    if !local_config_buf.exists() {
+        let hostname = hostname::get()
+            .context("failed to get the computer hostname")?
+            .into_string()
+            .expect("hostname cannot be converted to string");
+        info!(
+            "{:?} not found, using {}.toml instead (based on hostname)",
+            local_config, hostname
+        );
+        local_config_buf.set_file_name(format!("{}.toml", hostname));
+    }
+    Ok(local_config_buf)
+}
+
+/// The `escalate` setting of the local configuration, which is needed before (or without)
+/// loading the rest of it. Any problem with the file other than an invalid setting is left for
+/// the full load to report
+pub fn configured_escalation(local_config: &Path) -> Result<Option<Escalation>> {
+    let local = match local_config_path(local_config)
+        .and_then(|path| filesystem::load_file::<toml::Value>(&path))
+    {
+        Ok(Some(local)) => local,
+        _ => return Ok(None),
+    };
+    local
+        .get("escalate")
+        .map(|escalate| {
+            escalate
+                .clone()
+                .try_into()
+                .context("parse `escalate` setting")
+        })
+        .transpose()
+}
+
+/// Version of the cache's schema. Bump it and add a migration to `CACHE_MIGRATIONS` whenever
+/// `Cache` changes in a way older versions can't read
+pub const CACHE_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Cache {
+    pub version: u32,
     pub symlinks: BTreeMap<PathBuf, PathBuf>,
-    pub templates: BTreeMap<PathBuf, PathBuf>,
+    pub templates: BTreeMap<PathBuf, CachedFile>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hardlinks: BTreeMap<PathBuf, PathBuf>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub copies: BTreeMap<PathBuf, CachedFile>,
+    /// Hashes of the contents of linked sources with an `on_change` command, as of when they
+    /// were last deployed
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub source_hashes: BTreeMap<PathBuf, String>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            version: CACHE_VERSION,
+            symlinks: BTreeMap::new(),
+            templates: BTreeMap::new(),
+            hardlinks: BTreeMap::new(),
+            copies: BTreeMap::new(),
+            source_hashes: BTreeMap::new(),
+        }
+    }
+}
+
+/// Where a file was deployed to, and the hash of the contents it was deployed with
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CachedFile {
+    pub target: PathBuf,
+    pub hash: String,
+    /// Size of the target right after it was deployed, if it had exactly those contents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Modification time of the target right after it was deployed, if it had exactly those
+    /// contents, in nanoseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+}
+
+impl CachedFile {
+    pub fn new(target: PathBuf, hash: String, stamp: Option<filesystem::FileStamp>) -> CachedFile {
+        CachedFile {
+            target,
+            hash,
+            size: stamp.map(|s| s.size),
+            mtime: stamp.map(|s| s.mtime),
+        }
+    }
+
+    pub fn stamp(&self) -> Option<filesystem::FileStamp> {
+        Some(filesystem::FileStamp {
+            size: self.size?,
+            mtime: self.mtime?,
+        })
+    }
+}
+
+/// Upgrades a cache from one version to the next, starting with version 1
+/// (which had no `version` field)
+type CacheMigration = fn(&mut toml::value::Table, &Path) -> Result<()>;
+
+const CACHE_MIGRATIONS: [CacheMigration; CACHE_VERSION as usize - 1] = [hash_cached_templates];
+
+/// Loads the cache, upgrading it to the current version if it was written by an older one.
+/// If `upgrade_in_place` is set, an upgraded cache is saved over the old one, which is backed up
+/// next to it first.
+/// Returns Ok(None) if the cache file was not found.
+pub fn load_cache(
+    cache_file: &Path,
+    cache_directory: &Path,
+    upgrade_in_place: bool,
+) -> Result<Option<Cache>> {
+    let cache: toml::value::Table = match filesystem::load_file(cache_file)? {
+        Some(cache) => cache,
+        None => return Ok(None),
+    };
+
+    let (cache, upgraded_from) = parse_cache(cache, cache_directory)?;
+    if let (Some(version), true) = (upgraded_from, upgrade_in_place) {
+        let mut backup = cache_file.as_os_str().to_owned();
+        backup.push(format!(".v{}.bak", version));
+        let backup = PathBuf::from(backup);
+        fs::copy(cache_file, &backup)
+            .with_context(|| format!("back up old cache to {:?}", backup))?;
+        filesystem::save_file(cache_file, &cache).context("save upgraded cache")?;
+        info!(
+            "Upgraded cache from version {} to {}. The old one was backed up to {:?}",
+            version, CACHE_VERSION, backup
+        );
+    }
+
+    Ok(Some(cache))
+}
+
+/// Parses a cache of any version up to the current one, upgrading it if needed.
+/// Also returns the version it was upgraded from, if it was
+fn parse_cache(
+    mut cache: toml::value::Table,
+    cache_directory: &Path,
+) -> Result<(Cache, Option<u32>)> {
+    let version = match cache.get("version") {
+        None => 1,
+        Some(toml::Value::Integer(version)) if *version >= 1 => {
+            u32::try_from(*version).context("cache version is too large")?
+        }
+        Some(version) => anyhow::bail!("invalid cache version {}", version),
+    };
+    anyhow::ensure!(
+        version <= CACHE_VERSION,
+        "cache was written by a newer version of dotter (cache version {}, but this one only understands up to {}). Upgrade dotter to use it",
+        version,
+        CACHE_VERSION
+    );
+
+    for from in version..CACHE_VERSION {
+        debug!("Upgrading cache from version {} to {}", from, from + 1);
+        CACHE_MIGRATIONS[from as usize - 1](&mut cache, cache_directory)
+            .with_context(|| format!("upgrade cache from version {} to {}", from, from + 1))?;
+    }
+    cache.insert("version".into(), toml::Value::Integer(CACHE_VERSION.into()));
+
+    let parsed = toml::Value::Table(cache)
+        .try_into()
+        .context("parse cache")?;
+    Ok((parsed, Some(version).filter(|v| *v != CACHE_VERSION)))
+}
+
+/// Version 1 -> 2: templates were cached as just their target, with a rendered copy of each in
+/// `cache_directory`, instead of the hash of what they were deployed with
+fn hash_cached_templates(cache: &mut toml::value::Table, cache_directory: &Path) -> Result<()> {
+    let templates = match cache.get_mut("templates") {
+        Some(toml::Value::Table(templates)) => templates,
+        _ => return Ok(()),
+    };
+
+    for (source, template) in std::mem::take(templates) {
+        let target = match template {
+            toml::Value::String(target) => PathBuf::from(target),
+            // Written by a version that hashed templates before the cache was versioned
+            hashed => {
+                templates.insert(source, hashed);
+                continue;
+            }
+        };
+        let cached = migrate_template(Path::new(&source), target, cache_directory)
+            .with_context(|| format!("migrate cached template {:?}", source))?;
+        if let Some(cached) = cached {
+            let cached = toml::Value::try_from(cached).context("serialize cached template")?;
+            templates.insert(source, cached);
+        }
+    }
+    Ok(())
+}
+
+fn migrate_template(
+    source: &Path,
+    target: PathBuf,
+    cache_directory: &Path,
+) -> Result<Option<CachedFile>> {
+    let copy = cache_directory.join(source);
+    debug!("Migrating cached template {:?} using {:?}", source, copy);
+    // The copy is kept, since it's needed as the base of three-way merges
+    let hash = if copy.is_file() {
+        filesystem::hash_file(&copy).context("hash cached copy")?
+    } else if target.is_file() {
+        warn!(
+            "Cached copy of template {:?} is missing. Assuming {:?} wasn't changed since it was deployed.",
+            source, target
+        );
+        filesystem::hash_file(&target).context("hash target")?
+    } else {
+        warn!(
+            "Cached copy of template {:?} and its target {:?} are both missing. Forgetting about it.",
+            source, target
+        );
+        return Ok(None);
+    };
+
+    Ok(Some(CachedFile::new(target, hash, None)))
 }
 
 pub fn save_dummy_config(
@@ -187,6 +607,7 @@ pub fn save_dummy_config(
         files: files.into_iter().map(|f| (f.into(), "".into())).collect(),
         variables: Variables::new(),
         depends: vec![],
+        hooks: Hooks::default(),
     };
     trace!("Default package: {:#?}", package);
 
@@ -211,6 +632,7 @@ pub fn save_dummy_config(
         packages: vec!["default".into()],
         files: Files::default(),
         variables: Variables::default(),
+        escalate: None,
     };
     trace!("Local config: {:#?}", local_config);
     filesystem::save_file(local_config_path, local_config).context("save local config")?;
@@ -218,6 +640,34 @@ pub fn save_dummy_config(
     Ok(())
 }
 
+/// Names of the packages, each after the ones it depends on.
+/// A cycle of dependencies is broken where it's first entered, going by name
+fn dependency_order(packages: &BTreeMap<String, Package>) -> Vec<String> {
+    fn visit(
+        name: &str,
+        packages: &BTreeMap<String, Package>,
+        visited: &mut BTreeSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        if !visited.insert(name.to_string()) {
+            return;
+        }
+        if let Some(package) = packages.get(name) {
+            for dependency in &package.depends {
+                visit(dependency, packages, visited, order);
+            }
+            order.push(name.to_string());
+        }
+    }
+
+    let mut visited = BTreeSet::new();
+    let mut order = Vec::new();
+    for name in packages.keys() {
+        visit(name, packages, &mut visited, &mut order);
+    }
+    order
+}
+
 fn recursive_extend_map(
     original: &mut BTreeMap<String, toml::Value>,
     new: BTreeMap<String, toml::Value>,
@@ -262,6 +712,7 @@ fn merge_configuration_files(
                 if let Some(package_included) = included.remove(package_name) {
                     package_global.files.extend(package_included.files);
                     recursive_extend_map(&mut package_global.variables, package_included.variables);
+                    package_global.hooks.extend(package_included.hooks);
                 }
             }
 
@@ -301,11 +752,30 @@ fn merge_configuration_files(
     // Apply packages filter
     global.packages.retain(|k, _| enabled_packages.contains(k));
 
+    let mut hooks = dependency_order(&global.packages)
+        .into_iter()
+        .map(|name| {
+            let package = &global.packages[&name];
+            PackageHooks {
+                package: name,
+                hooks: package.hooks.clone(),
+                variables: package.variables.clone(),
+            }
+        })
+        .filter(|package| !package.hooks.is_empty())
+        .collect::<Vec<_>>();
+
     let mut output = Configuration {
         helpers: global.helpers,
         files: Files::default(),
         variables: Variables::default(),
         packages: enabled_packages.into_iter().collect(),
+        package_files: global
+            .packages
+            .iter()
+            .map(|(name, package)| (name.clone(), package.files.keys().cloned().collect()))
+            .collect(),
+        hooks: Vec::new(),
         recurse: true,
     };
 
@@ -353,13 +823,20 @@ fn merge_configuration_files(
 
     // Add local.toml's patches
     output.files.extend(local.files);
+    for package in &mut hooks {
+        recursive_extend_map(&mut package.variables, local.variables.clone());
+    }
     recursive_extend_map(&mut output.variables, local.variables);
 
     // Add manual patch
     if let Some(patch) = patch {
         output.files.extend(patch.files);
+        for package in &mut hooks {
+            recursive_extend_map(&mut package.variables, patch.variables.clone());
+        }
         recursive_extend_map(&mut output.variables, patch.variables);
     }
+    output.hooks = hooks;
 
     // Remove files with target = ""
     output.files.retain(|_, v| v.path().to_string_lossy() != "");
@@ -372,7 +849,9 @@ impl FileTarget {
         match self {
             FileTarget::Automatic(path) => path,
             FileTarget::Symbolic(SymbolicTarget { target, .. })
-            | FileTarget::ComplexTemplate(TemplateTarget { target, .. }) => target,
+            | FileTarget::ComplexTemplate(TemplateTarget { target, .. })
+            | FileTarget::Copy(CopyTarget { target, .. })
+            | FileTarget::Hardlink(HardlinkTarget { target, .. }) => target,
         }
     }
 
@@ -380,9 +859,9 @@ impl FileTarget {
         match self {
             FileTarget::Automatic(ref mut path) => *path = new_path.into(),
             FileTarget::Symbolic(SymbolicTarget { target, .. })
-            | FileTarget::ComplexTemplate(TemplateTarget { target, .. }) => {
-                *target = new_path.into()
-            }
+            | FileTarget::ComplexTemplate(TemplateTarget { target, .. })
+            | FileTarget::Copy(CopyTarget { target, .. })
+            | FileTarget::Hardlink(HardlinkTarget { target, .. }) => *target = new_path.into(),
         }
     }
 
@@ -391,6 +870,8 @@ impl FileTarget {
             FileTarget::Automatic(_) => None,
             FileTarget::Symbolic(SymbolicTarget { condition, .. }) => condition.as_ref(),
             FileTarget::ComplexTemplate(TemplateTarget { condition, .. }) => condition.as_ref(),
+            FileTarget::Copy(CopyTarget { condition, .. }) => condition.as_ref(),
+            FileTarget::Hardlink(HardlinkTarget { condition, .. }) => condition.as_ref(),
         }
     }
 }
@@ -409,6 +890,8 @@ impl From<FileTargetOuterRepr> for FileTarget {
             OR::Simple(x) => Self::Automatic(x),
             OR::Complex(IR::Symbolic(x)) => Self::Symbolic(x),
             OR::Complex(IR::ComplexTemplate(x)) => Self::ComplexTemplate(x),
+            OR::Complex(IR::Copy(x)) => Self::Copy(x),
+            OR::Complex(IR::Hardlink(x)) => Self::Hardlink(x),
         }
     }
 }
@@ -420,6 +903,8 @@ impl From<FileTarget> for FileTargetOuterRepr {
             FileTarget::Automatic(x) => Self::Simple(x),
             FileTarget::Symbolic(x) => Self::Complex(IR::Symbolic(x)),
             FileTarget::ComplexTemplate(x) => Self::Complex(IR::ComplexTemplate(x)),
+            FileTarget::Copy(x) => Self::Complex(IR::Copy(x)),
+            FileTarget::Hardlink(x) => Self::Complex(IR::Hardlink(x)),
         }
     }
 }
@@ -429,8 +914,11 @@ impl<T: Into<PathBuf>> From<T> for SymbolicTarget {
         SymbolicTarget {
             target: input.into(),
             owner: None,
+            group: None,
             condition: None,
             recurse: None,
+            dir_mode: None,
+            on_change: None,
         }
     }
 }
@@ -440,9 +928,39 @@ impl<T: Into<PathBuf>> From<T> for TemplateTarget {
         TemplateTarget {
             target: input.into(),
             owner: None,
+            group: None,
             append: None,
             prepend: None,
             condition: None,
+            merge: None,
+            mode: None,
+            dir_mode: None,
+            on_change: None,
+        }
+    }
+}
+
+impl<T: Into<PathBuf>> From<T> for CopyTarget {
+    fn from(input: T) -> Self {
+        CopyTarget {
+            target: input.into(),
+            owner: None,
+            group: None,
+            condition: None,
+            mode: None,
+            dir_mode: None,
+            on_change: None,
+        }
+    }
+}
+
+impl<T: Into<PathBuf>> From<T> for HardlinkTarget {
+    fn from(input: T) -> Self {
+        HardlinkTarget {
+            target: input.into(),
+            condition: None,
+            dir_mode: None,
+            on_change: None,
         }
     }
 }
@@ -452,9 +970,14 @@ impl SymbolicTarget {
         TemplateTarget {
             target: self.target,
             owner: self.owner,
+            group: self.group,
             condition: self.condition,
             prepend: None,
             append: None,
+            merge: None,
+            mode: None,
+            dir_mode: self.dir_mode,
+            on_change: self.on_change,
         }
     }
 }
@@ -493,10 +1016,7 @@ fn expand_directory(source: &Path, target: &FileTarget, config: &Configuration)
     // precedence over the global default
     let recurse = match target {
         FileTarget::Symbolic(SymbolicTarget {
-            target: _,
-            owner: _,
-            condition: _,
-            recurse: Some(rec),
+            recurse: Some(rec), ..
         }) => *rec,
         _ => config.recurse,
     };
@@ -540,10 +1060,26 @@ impl UnixUser {
     }
 }
 
+impl UnixGroup {
+    pub fn as_chown_arg(&self) -> String {
+        match self {
+            UnixGroup::Name(n) => n.clone(),
+            UnixGroup::Gid(id) => format!("{}", id),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn parse_cache_str(cache: &str) -> Result<(Cache, Option<u32>)> {
+        parse_cache(
+            toml::from_str(cache).unwrap(),
+            Path::new("/nonexistent/dotter/cache"),
+        )
+    }
+
     #[test]
     fn deserialize_file_target() {
         #[derive(Deserialize)]
@@ -603,14 +1139,327 @@ mod tests {
         assert_eq!(
             parse(
                 r#"
+                    [file]
+                    target = '~/.QuarticCat'
+                    type = 'template'
+                    merge = 'markers'
+                "#,
+            )
+            .unwrap()
+            .file,
+            FileTarget::ComplexTemplate(TemplateTarget {
+                merge: Some(MergeStrategy::Markers),
+                ..PathBuf::from("~/.QuarticCat").into()
+            }),
+        );
+        assert!(parse(
+            r#"
                     [file]
                     target = '~/.QuarticCat'
                     type = 'symbolic'
                     append = 'whatever'
                 "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn deserialize_mode() {
+        #[derive(Deserialize)]
+        struct Helper {
+            file: FileTarget,
+        }
+
+        let parse = |s| toml::from_str::<Helper>(s);
+
+        assert_eq!(
+            parse(
+                r#"
+                    [file]
+                    target = '~/.ssh/config'
+                    type = 'template'
+                    mode = '0600'
+                    dir_mode = 700
+                "#,
+            )
+            .unwrap()
+            .file,
+            FileTarget::ComplexTemplate(TemplateTarget {
+                mode: Some(Mode(0o600)),
+                dir_mode: Some(Mode(0o700)),
+                ..PathBuf::from("~/.ssh/config").into()
+            }),
+        );
+        assert!(parse(
+            r#"
+                    [file]
+                    target = '~/.ssh/config'
+                    type = 'template'
+                    mode = '0800'
+                "#,
+        )
+        .is_err());
+        assert_eq!(toml::to_string(&Mode(0o644)).unwrap(), "\"0644\"");
+    }
+
+    #[test]
+    fn deserialize_copy() {
+        #[derive(Deserialize)]
+        struct Helper {
+            file: FileTarget,
+        }
+
+        let parse = |s| toml::from_str::<Helper>(s);
+
+        assert_eq!(
+            parse(
+                r#"
+                    [file]
+                    target = '~/.local/share/fonts/font.ttf'
+                    type = 'copy'
+                "#,
+            )
+            .unwrap()
+            .file,
+            FileTarget::Copy(PathBuf::from("~/.local/share/fonts/font.ttf").into()),
+        );
+        assert!(parse(
+            r#"
+                    [file]
+                    target = '~/.local/share/fonts/font.ttf'
+                    type = 'copy'
+                    prepend = 'whatever'
+                "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn deserialize_hardlink() {
+        #[derive(Deserialize)]
+        struct Helper {
+            file: FileTarget,
+        }
+
+        let parse = |s| toml::from_str::<Helper>(s);
+
+        assert_eq!(
+            parse(
+                r#"
+                    [file]
+                    target = '~/.config/app/config'
+                    type = 'hardlink'
+                "#,
             )
-            .is_err(),
-            true
+            .unwrap()
+            .file,
+            FileTarget::Hardlink(PathBuf::from("~/.config/app/config").into()),
+        );
+        assert!(parse(
+            r#"
+                    [file]
+                    target = '~/.config/app/config'
+                    type = 'hardlink'
+                    owner = 'root'
+                "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn upgrade_unversioned_cache() {
+        let (cache, upgraded_from) = parse_cache_str(
+            r#"
+                [symlinks]
+                [templates]
+                old = '/nonexistent/dotter/old'
+
+                [templates.new]
+                target = '/home/user/new'
+                hash = 'abc'
+                size = 3
+                mtime = 1700000000000000000
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(upgraded_from, Some(1));
+        assert_eq!(cache.version, CACHE_VERSION);
+        // Nothing left to hash, so there's nothing to compare the target with later
+        assert!(!cache.templates.contains_key(Path::new("old")));
+        assert_eq!(
+            cache.templates[Path::new("new")].stamp(),
+            Some(filesystem::FileStamp {
+                size: 3,
+                mtime: 1_700_000_000_000_000_000
+            })
+        );
+    }
+
+    #[test]
+    fn parse_current_cache() {
+        let (cache, upgraded_from) =
+            parse_cache_str(&toml::to_string(&Cache::default()).unwrap()).unwrap();
+        assert_eq!(upgraded_from, None);
+        assert_eq!(cache.version, CACHE_VERSION);
+    }
+
+    #[test]
+    fn refuse_newer_cache() {
+        let error = parse_cache_str(&format!(
+            "version = {}\n[symlinks]\n[templates]\n",
+            CACHE_VERSION + 1
+        ))
+        .unwrap_err();
+        assert!(error.to_string().contains("newer version of dotter"));
+    }
+
+    #[test]
+    fn deserialize_group() {
+        #[derive(Deserialize)]
+        struct Helper {
+            file: FileTarget,
+        }
+
+        let parse = |s| toml::from_str::<Helper>(s).unwrap().file;
+
+        assert_eq!(
+            parse(
+                r#"
+                    [file]
+                    target = '/etc/sudoers.d/dotter'
+                    type = 'template'
+                    owner = 'root'
+                    group = 'wheel'
+                "#,
+            ),
+            FileTarget::ComplexTemplate(TemplateTarget {
+                owner: Some(UnixUser::Name("root".into())),
+                group: Some(UnixGroup::Name("wheel".into())),
+                ..PathBuf::from("/etc/sudoers.d/dotter").into()
+            }),
+        );
+        assert_eq!(
+            parse(
+                r#"
+                    [file]
+                    target = '/etc/profile.d/dotter.sh'
+                    type = 'symbolic'
+                    group = 10
+                "#,
+            ),
+            FileTarget::Symbolic(SymbolicTarget {
+                group: Some(UnixGroup::Gid(10)),
+                ..PathBuf::from("/etc/profile.d/dotter.sh").into()
+            }),
+        );
+    }
+
+    #[test]
+    fn escalation_from_local_config() {
+        let directory = tempfile::tempdir().unwrap();
+        let local = directory.path().join("local.toml");
+
+        std::fs::write(&local, "packages = []\nescalate = 'doas'\n").unwrap();
+        assert_eq!(
+            configured_escalation(&local).unwrap(),
+            Some(Escalation::Doas)
+        );
+
+        std::fs::write(&local, "packages = []\nescalate = 'never'\n").unwrap();
+        assert_eq!(
+            configured_escalation(&local).unwrap(),
+            Some(Escalation::Never)
+        );
+
+        std::fs::write(&local, "packages = []\n").unwrap();
+        assert_eq!(configured_escalation(&local).unwrap(), None);
+
+        std::fs::write(&local, "packages = []\nescalate = 'su'\n").unwrap();
+        assert!(configured_escalation(&local).is_err());
+    }
+
+    #[test]
+    fn rebase_into_root() {
+        let root = Path::new("/srv/image");
+        assert_eq!(
+            rebase(root, Path::new("/home/user/.bashrc")),
+            Path::new("/srv/image/home/user/.bashrc")
+        );
+        assert_eq!(
+            rebase(root, Path::new("relative/file")),
+            Path::new("/srv/image/relative/file")
+        );
+        assert_eq!(
+            rebase(root, Path::new("/../etc/profile")),
+            Path::new("/srv/image/etc/profile")
+        );
+        assert_eq!(
+            rebase(root, Path::new("/home/user/../../../etc/./profile")),
+            Path::new("/srv/image/etc/profile")
+        );
+        assert_eq!(
+            rebase(root, Path::new("../../file")),
+            Path::new("/srv/image/file")
+        );
+    }
+
+    #[test]
+    fn package_hooks_in_dependency_order() {
+        let global: GlobalConfig = toml::from_str(
+            r#"
+                [app]
+                depends = ["base"]
+                [app.hooks]
+                pre_deploy = "echo {{name}}"
+                post_undeploy = { script = "app.sh" }
+                [app.variables]
+                name = "app"
+
+                [base.hooks]
+                post_deploy = "echo {{shell}}"
+                [base.variables]
+                shell = "sh"
+
+                [quiet.variables]
+                other = 1
+
+                [unused.hooks]
+                pre_deploy = "false"
+            "#,
+        )
+        .unwrap();
+        let local: LocalConfig = toml::from_str(
+            r#"
+                packages = ["app", "quiet"]
+                [variables]
+                shell = "zsh"
+            "#,
+        )
+        .unwrap();
+
+        let config = merge_configuration_files(global, local, None).unwrap();
+        let hooks = config
+            .hooks
+            .iter()
+            .map(|package| package.package.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(hooks, ["base", "app"]);
+
+        let (base, app) = (&config.hooks[0], &config.hooks[1]);
+        assert_eq!(
+            base.hooks.post_deploy,
+            Some(Hook::Command("echo {{shell}}".into()))
+        );
+        // Only their own variables, with local.toml's overrides
+        assert_eq!(base.variables.get("shell"), Some(&"zsh".into()));
+        assert_eq!(base.variables.get("name"), None);
+        assert_eq!(app.variables.get("name"), Some(&"app".into()));
+        assert_eq!(
+            app.hooks.post_undeploy,
+            Some(Hook::Script {
+                script: "app.sh".into()
+            })
         );
     }
 }
@@ -1,222 +1,329 @@
 use anyhow::{Context, Result};
+use handlebars::Handlebars;
 
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::io::{self, Read};
 use std::path::PathBuf;
 
-use crate::actions::{self, ActionRunner, RealActionRunner};
-use crate::args::Options;
-use crate::config::{self, Cache, FileTarget, SymbolicTarget, TemplateTarget};
+use crate::actions::{ActionRunner, RealActionRunner};
+use crate::args::{Options, OutputFormat};
+use crate::backup::{self, BackupStore};
+use crate::config::{
+    self, Cache, CachedFile, Configuration, CopyTarget, FileTarget, Files, HardlinkTarget,
+    PackageHooks, SymbolicTarget, TemplateTarget, Variables,
+};
+use crate::difference::DiffSink;
 use crate::display_error;
-use crate::filesystem::{self, load_file, Filesystem};
+use crate::exit::{self, ExitStatus, Failure};
+use crate::filesystem::{self, Filesystem};
 use crate::handlebars_helpers::create_new_handlebars;
-use crate::hooks;
+use crate::hooks::{self, Stage};
+use crate::report::{self, ReportingActionRunner, Summary};
+use crate::transaction::{self, Transaction};
 
-/// Returns true if an error was printed
-pub fn deploy(opt: &Options) -> Result<bool> {
-    // === Load configuration ===
-    let mut patch = None;
-    if opt.patch {
-        debug!("Reading manual patch from stdin...");
-        let mut patch_str = String::new();
-        io::stdin()
-            .read_to_string(&mut patch_str)
-            .context("read patch from stdin")?;
-        patch = Some(toml::from_str(&patch_str).context("parse patch into package")?);
-    }
-    trace!("Manual patch: {:#?}", patch);
+/// Returns the status to exit with if an error was printed
+pub fn deploy(opt: &Options) -> Result<ExitStatus> {
+    report::with_summary(opt, |summary| {
+        // === Load configuration ===
+        let (mut config, cache) = load_configuration_and_cache(opt)?;
 
-    let mut config = config::load_configuration(&opt.local_config, &opt.global_config, patch)
-        .context("get a configuration")?;
-
-    let mut cache = if let Some(cache) = load_file(&opt.cache_file)? {
-        cache
-    } else {
-        warn!("Cache file not found. Assuming cache is empty.");
-        config::Cache::default()
-    };
+        let handlebars = create_new_handlebars(&mut config)
+            .context(Failure::new(ExitStatus::Config, "initialize handlebars"))?;
 
-    // === Pre-deploy ===
+        // === Re-structure configuration ===
 
-    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+        let desired = desired_files(config.files).context("sort files by how they're deployed")?;
 
-    debug!("Running pre-deploy hook");
-    if !opt.dry_run {
-        hooks::run_hook(
-            &opt.pre_deploy,
-            &opt.cache_directory,
+        deploy_with(
+            opt,
+            summary,
             &handlebars,
             &config.variables,
+            &config.hooks,
+            cache,
+            |runner, cache| run_deploy(runner, &desired, cache, opt),
         )
-        .context("run pre-deploy hook")?;
+    })
+}
+
+/// Runs the deploy hooks (global and `hooks` of packages) around `run`, which performs actions using a runner set up according
+/// to `opt` and returns `(suggest_force, failure)` like `run_deploy`.
+/// The `on_change` commands of the files it changed run before the post-deploy hooks.
+/// Saves the resulting cache and backups, and rolls everything back on failure if the deployment
+/// is transactional.
+/// Returns the status to exit with if an error was printed
+pub(crate) fn deploy_with<F>(
+    opt: &Options,
+    summary: &mut Summary,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+    hooks: &[PackageHooks],
+    mut cache: Cache,
+    run: F,
+) -> Result<ExitStatus>
+where
+    F: FnOnce(&mut dyn ActionRunner, &mut Cache) -> (bool, Option<ExitStatus>),
+{
+    // === Pre-deploy ===
+
+    debug!("Running pre-deploy hooks");
+    if !opt.dry_run {
+        hooks::run_hooks(Stage::PreDeploy, opt, hooks, handlebars, variables)?;
     }
 
-    let (mut real_fs, mut dry_run_fs);
-    let fs: &mut dyn Filesystem = if !opt.dry_run {
-        real_fs = crate::filesystem::RealFilesystem::new(opt.noconfirm);
+    let (mut real_fs, mut dry_run_fs, mut memory_fs);
+    let fs: &mut dyn Filesystem = if opt.in_memory {
+        memory_fs = crate::filesystem::MemoryFilesystem::read_through()
+            .context("set up in-memory filesystem")?
+            .with_root(opt.root.clone());
+        &mut memory_fs
+    } else if !opt.dry_run {
+        real_fs =
+            crate::filesystem::RealFilesystem::new(opt.noconfirm, opt.escalate.unwrap_or_default())
+                .with_root(opt.root.clone());
         &mut real_fs
     } else {
-        dry_run_fs = crate::filesystem::DryRunFilesystem::new();
+        dry_run_fs = crate::filesystem::DryRunFilesystem::new().with_root(opt.root.clone());
         &mut dry_run_fs
     };
 
-    // === Re-structure configuration ===
+    // === Perform deployment ===
 
-    // On Windows, you need developer mode to create symlinks.
-    let symlinks_enabled = if filesystem::symlinks_enabled(&PathBuf::from("DOTTER_SYMLINK_TEST"))
-        .context("check whether symlinks are enabled")?
-    {
-        true
+    let mut backups =
+        BackupStore::open(backup::backup_directory(opt)).context("open backup store")?;
+
+    let mut transaction = None;
+    let fs: &mut dyn Filesystem = if opt.transactional && !opt.dry_run {
+        transaction.insert(
+            Transaction::begin(
+                fs,
+                transaction::journal_directory(opt),
+                &[&opt.cache_file, &backup::manifest_file(opt)],
+            )
+            .context("begin transaction")?,
+        )
     } else {
-        warn!(
-            "No permission to create symbolic links.\n
-On Windows, in order to create symbolic links you need to enable Developer Mode.\n
-Proceeding by copying instead of symlinking."
-        );
-        false
+        fs
     };
 
-    let mut desired_symlinks = BTreeMap::<PathBuf, SymbolicTarget>::new();
-    let mut desired_templates = BTreeMap::<PathBuf, TemplateTarget>::new();
-
-    for (source, target) in config.files {
-        if symlinks_enabled {
-            match target {
-                FileTarget::Automatic(target) => {
-                    if filesystem::is_template(&source)
-                        .context(format!("check whether {:?} is a template", source))?
-                    {
-                        desired_templates.insert(source, target.into());
-                    } else {
-                        desired_symlinks.insert(source, target.into());
-                    }
-                }
-                FileTarget::Symbolic(target) => {
-                    desired_symlinks.insert(source, target);
-                }
-                FileTarget::ComplexTemplate(target) => {
-                    desired_templates.insert(source, target);
-                }
-            }
-        } else {
-            match target {
-                FileTarget::Automatic(target) => {
-                    desired_templates.insert(source, target.into());
-                }
-                FileTarget::Symbolic(target) => {
-                    desired_templates.insert(source, target.into_template());
-                }
-                FileTarget::ComplexTemplate(target) => {
-                    desired_templates.insert(source, target);
-                }
-            }
-        }
-    }
-
-    // === Perform deployment ===
-
     let mut runner = RealActionRunner::new(
         fs,
-        &handlebars,
-        &config.variables,
+        &mut backups,
+        handlebars,
+        variables,
         opt.force,
+        opt.merge,
         opt.diff_context_lines,
-    );
-
-    let (suggest_force, mut error_occurred) = run_deploy(
-        &mut runner,
-        &desired_symlinks,
-        &desired_templates,
-        &mut cache,
-        opt,
-    );
+        DiffSink::of(opt),
+    )
+    .with_source_hashes(std::mem::take(&mut cache.source_hashes));
+
+    let (suggest_force, failure) = {
+        let mut reporter;
+        let runner: &mut dyn ActionRunner = if opt.output == OutputFormat::Json {
+            reporter = ReportingActionRunner::new(
+                &mut runner,
+                handlebars,
+                variables,
+                opt.diff_context_lines,
+                summary,
+            );
+            &mut reporter
+        } else {
+            &mut runner
+        };
+        run(runner, &mut cache)
+    };
+    let changes = runner.take_changes();
+    cache.source_hashes = changes.source_hashes;
+
+    if let Some(failure) = failure {
+        if let Some(transaction) = transaction.take() {
+            transaction.rollback().context("roll back deployment")?;
+            error!("Deployment failed, so all of its changes were rolled back.");
+            summary.rolled_back = true;
+            summary.emit(opt, false);
+            return Ok(failure);
+        }
+    }
 
     // === Post-deploy ===
 
     if suggest_force {
         error!("Some files were skipped. To ignore errors and overwrite unexpected target files, use the --force flag.");
-        error_occurred = true;
+        error!("To merge changes in modified templates instead, use --merge.");
     }
+    let status = exit_status(suggest_force, failure);
 
     if !opt.dry_run {
         filesystem::save_file(&opt.cache_file, cache).context("save cache")?;
+        backups.save().context("save backups")?;
     }
 
-    debug!("Running post-deploy hook");
+    debug!("Running on_change commands and post-deploy hooks");
     if !opt.dry_run {
-        hooks::run_hook(
-            &opt.post_deploy,
-            &opt.cache_directory,
-            &handlebars,
-            &config.variables,
-        )
-        .context("run post-deploy hook")?;
+        let result = hooks::run_change_hooks(&changes.commands, opt, handlebars, variables)
+            .and_then(|()| hooks::run_hooks(Stage::PostDeploy, opt, hooks, handlebars, variables));
+        if result.is_err() {
+            if let Some(transaction) = transaction.take() {
+                transaction.rollback().context("roll back deployment")?;
+                error!(
+                    "Post-deploy hook or on_change command failed, so all of the deployment's changes were rolled back."
+                );
+                summary.rolled_back = true;
+            }
+            summary.emit(opt, false);
+        }
+        result?;
+    }
+
+    if let Some(transaction) = transaction {
+        transaction.commit().context("commit transaction")?;
     }
 
-    Ok(error_occurred)
+    summary.emit(opt, status == ExitStatus::Success);
+    Ok(status)
 }
 
-pub fn undeploy(opt: Options) -> Result<bool> {
-    // === Load configuration ===
-    let mut config = config::load_configuration(&opt.local_config, &opt.global_config, None)
-        .context("get a configuration")?;
+/// A failed action takes precedence over skipped ones
+fn exit_status(suggest_force: bool, failure: Option<ExitStatus>) -> ExitStatus {
+    match failure {
+        Some(failure) => failure,
+        None if suggest_force => ExitStatus::Skipped,
+        None => ExitStatus::Success,
+    }
+}
+
+/// Returns the status to exit with if an error was printed
+pub fn undeploy(opt: Options) -> Result<ExitStatus> {
+    report::with_summary(&opt, |summary| undeploy_with(&opt, summary))
+}
 
-    let mut cache: config::Cache = filesystem::load_file(&opt.cache_file)?
+fn undeploy_with(opt: &Options, summary: &mut Summary) -> Result<ExitStatus> {
+    // === Load configuration ===
+    let mut config = config::load_configuration(
+        &opt.local_config,
+        &opt.global_config,
+        None,
+        opt.root.as_deref(),
+    )
+    .context(Failure::new(ExitStatus::Config, "get a configuration"))?;
+
+    let mut cache = config::load_cache(&opt.cache_file, &opt.cache_directory, opt.writes_cache())
+        .context(Failure::new(ExitStatus::Cache, "load cache"))?
         .context("load cache: Cannot undeploy without a cache.")?;
 
-    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+    let handlebars = create_new_handlebars(&mut config)
+        .context(Failure::new(ExitStatus::Config, "initialize handlebars"))?;
 
     // === Pre-undeploy ===
 
-    debug!("Running pre-undeploy hook");
+    debug!("Running pre-undeploy hooks");
     if !opt.dry_run {
-        hooks::run_hook(
-            &opt.pre_undeploy,
-            &opt.cache_directory,
+        hooks::run_hooks(
+            Stage::PreUndeploy,
+            opt,
+            &config.hooks,
             &handlebars,
             &config.variables,
-        )
-        .context("run pre-undeploy hook")?;
+        )?;
     }
 
     let mut suggest_force = false;
-    let mut error_occurred = false;
-
-    let (mut real_fs, mut dry_run_fs);
-    let fs: &mut dyn Filesystem = if !opt.dry_run {
-        real_fs = crate::filesystem::RealFilesystem::new(opt.noconfirm);
+    let mut failure = None;
+
+    let (mut real_fs, mut dry_run_fs, mut memory_fs);
+    let fs: &mut dyn Filesystem = if opt.in_memory {
+        memory_fs = crate::filesystem::MemoryFilesystem::read_through()
+            .context("set up in-memory filesystem")?
+            .with_root(opt.root.clone());
+        &mut memory_fs
+    } else if !opt.dry_run {
+        real_fs =
+            crate::filesystem::RealFilesystem::new(opt.noconfirm, opt.escalate.unwrap_or_default())
+                .with_root(opt.root.clone());
         &mut real_fs
     } else {
-        dry_run_fs = crate::filesystem::DryRunFilesystem::new();
+        dry_run_fs = crate::filesystem::DryRunFilesystem::new().with_root(opt.root.clone());
         &mut dry_run_fs
     };
 
     // === Perform undeployment ===
 
+    let mut backups =
+        BackupStore::open(backup::backup_directory(opt)).context("open backup store")?;
+
+    let mut runner = RealActionRunner::new(
+        fs,
+        &mut backups,
+        &handlebars,
+        &config.variables,
+        opt.force,
+        opt.merge,
+        opt.diff_context_lines,
+        DiffSink::of(opt),
+    );
+    let mut reporter;
+    let runner: &mut dyn ActionRunner = if opt.output == OutputFormat::Json {
+        reporter = ReportingActionRunner::new(
+            &mut runner,
+            &handlebars,
+            &config.variables,
+            opt.diff_context_lines,
+            summary,
+        );
+        &mut reporter
+    } else {
+        &mut runner
+    };
+
     for (deleted_symlink, target) in cache.symlinks.clone() {
         execute_action(
-            actions::delete_symlink(&deleted_symlink, &target, fs, opt.force),
+            runner.delete_symlink(&deleted_symlink, &target),
             || cache.symlinks.remove(&deleted_symlink),
             || format!("delete symlink {:?} -> {:?}", deleted_symlink, target),
             &mut suggest_force,
-            &mut error_occurred,
+            &mut failure,
         );
     }
 
-    for (deleted_template, target) in cache.templates.clone() {
+    for (deleted_template, template) in cache.templates.clone() {
         execute_action(
-            actions::delete_template(
+            runner.delete_template(
                 &deleted_template,
                 &opt.cache_directory.join(&deleted_template),
-                &target,
-                fs,
-                opt.force,
+                &template,
             ),
             || cache.templates.remove(&deleted_template),
-            || format!("delete template {:?} -> {:?}", deleted_template, target),
+            || {
+                format!(
+                    "delete template {:?} -> {:?}",
+                    deleted_template, template.target
+                )
+            },
+            &mut suggest_force,
+            &mut failure,
+        );
+    }
+
+    for (deleted_hardlink, target) in cache.hardlinks.clone() {
+        execute_action(
+            runner.delete_hardlink(&deleted_hardlink, &target),
+            || cache.hardlinks.remove(&deleted_hardlink),
+            || format!("delete hardlink {:?} -> {:?}", deleted_hardlink, target),
             &mut suggest_force,
-            &mut error_occurred,
+            &mut failure,
+        );
+    }
+
+    for (deleted_copy, copy) in cache.copies.clone() {
+        execute_action(
+            runner.delete_copy(&deleted_copy, &copy),
+            || cache.copies.remove(&deleted_copy),
+            || format!("delete copy {:?} -> {:?}", deleted_copy, copy.target),
+            &mut suggest_force,
+            &mut failure,
         );
     }
 
@@ -224,38 +331,160 @@ pub fn undeploy(opt: Options) -> Result<bool> {
 
     if suggest_force {
         error!("Some files were skipped. To ignore errors and overwrite unexpected target files, use the --force flag.");
-        error_occurred = true;
     }
 
+    let Cache {
+        symlinks,
+        hardlinks,
+        source_hashes,
+        ..
+    } = &mut cache;
+    source_hashes
+        .retain(|source, _| symlinks.contains_key(source) || hardlinks.contains_key(source));
+
     if !opt.dry_run {
         // Should be empty if everything went well, but if some things were skipped this contains
         // them.
         filesystem::save_file(&opt.cache_file, cache).context("save cache")?;
+        backups.save().context("save backups")?;
     }
 
-    debug!("Running post-undeploy hook");
+    debug!("Running post-undeploy hooks");
     if !opt.dry_run {
-        hooks::run_hook(
-            &opt.post_undeploy,
-            &opt.cache_directory,
+        hooks::run_hooks(
+            Stage::PostUndeploy,
+            opt,
+            &config.hooks,
             &handlebars,
             &config.variables,
-        )
-        .context("run post-undeploy hook")?;
+        )?;
+    }
+
+    let status = exit_status(suggest_force, failure);
+    summary.emit(opt, status == ExitStatus::Success);
+    Ok(status)
+}
+
+/// Loads the merged configuration (including the stdin patch if `--patch` was given) and the
+/// cache, which is assumed to be empty if the cache file doesn't exist.
+pub(crate) fn load_configuration_and_cache(opt: &Options) -> Result<(Configuration, Cache)> {
+    let mut patch = None;
+    if opt.patch {
+        debug!("Reading manual patch from stdin...");
+        let mut patch_str = String::new();
+        io::stdin()
+            .read_to_string(&mut patch_str)
+            .context("read patch from stdin")?;
+        patch = Some(toml::from_str(&patch_str).context("parse patch into package")?);
     }
+    trace!("Manual patch: {:#?}", patch);
 
-    Ok(error_occurred)
+    let config = config::load_configuration(
+        &opt.local_config,
+        &opt.global_config,
+        patch,
+        opt.root.as_deref(),
+    )
+    .context(Failure::new(ExitStatus::Config, "get a configuration"))?;
+
+    let cache = if let Some(cache) =
+        config::load_cache(&opt.cache_file, &opt.cache_directory, opt.writes_cache())
+            .context(Failure::new(ExitStatus::Cache, "load cache"))?
+    {
+        cache
+    } else {
+        warn!("Cache file not found. Assuming cache is empty.");
+        config::Cache::default()
+    };
+
+    Ok((config, cache))
 }
 
-fn run_deploy<A: ActionRunner>(
+/// The files that should be deployed, sorted by how they are deployed
+#[derive(Debug, Default)]
+pub(crate) struct DesiredFiles {
+    pub symlinks: BTreeMap<PathBuf, SymbolicTarget>,
+    pub templates: BTreeMap<PathBuf, TemplateTarget>,
+    pub copies: BTreeMap<PathBuf, CopyTarget>,
+    pub hardlinks: BTreeMap<PathBuf, HardlinkTarget>,
+}
+
+/// Sorts the configured files by how they should be deployed,
+/// detecting automatic files and falling back to templates if symlinks aren't available.
+pub(crate) fn desired_files(files: Files) -> Result<DesiredFiles> {
+    // On Windows, you need developer mode to create symlinks.
+    let symlinks_enabled = if filesystem::symlinks_enabled(&PathBuf::from("DOTTER_SYMLINK_TEST"))
+        .context("check whether symlinks are enabled")?
+    {
+        true
+    } else {
+        warn!(
+            "No permission to create symbolic links.\n
+On Windows, in order to create symbolic links you need to enable Developer Mode.\n
+Proceeding by copying instead of symlinking."
+        );
+        false
+    };
+
+    let mut desired = DesiredFiles::default();
+
+    for (source, target) in files {
+        if symlinks_enabled {
+            match target {
+                FileTarget::Automatic(target) => {
+                    if filesystem::is_template(&source)
+                        .context(format!("check whether {:?} is a template", source))?
+                    {
+                        desired.templates.insert(source, target.into());
+                    } else {
+                        desired.symlinks.insert(source, target.into());
+                    }
+                }
+                FileTarget::Symbolic(target) => {
+                    desired.symlinks.insert(source, target);
+                }
+                FileTarget::ComplexTemplate(target) => {
+                    desired.templates.insert(source, target);
+                }
+                FileTarget::Copy(target) => {
+                    desired.copies.insert(source, target);
+                }
+                FileTarget::Hardlink(target) => {
+                    desired.hardlinks.insert(source, target);
+                }
+            }
+        } else {
+            match target {
+                FileTarget::Automatic(target) => {
+                    desired.templates.insert(source, target.into());
+                }
+                FileTarget::Symbolic(target) => {
+                    desired.templates.insert(source, target.into_template());
+                }
+                FileTarget::ComplexTemplate(target) => {
+                    desired.templates.insert(source, target);
+                }
+                FileTarget::Copy(target) => {
+                    desired.copies.insert(source, target);
+                }
+                FileTarget::Hardlink(target) => {
+                    desired.hardlinks.insert(source, target);
+                }
+            }
+        }
+    }
+
+    Ok(desired)
+}
+
+pub(crate) fn run_deploy<A: ActionRunner + ?Sized>(
     runner: &mut A,
-    desired_symlinks: &BTreeMap<PathBuf, SymbolicTarget>,
-    desired_templates: &BTreeMap<PathBuf, TemplateTarget>,
+    desired: &DesiredFiles,
     cache: &mut Cache,
     opt: &Options,
-) -> (bool, bool) {
+) -> (bool, Option<ExitStatus>) {
     let mut suggest_force = false;
-    let mut error_occurred = false;
+    let mut failure = None;
 
     // Index by both source and target location
     let existing_symlinks: BTreeSet<(PathBuf, PathBuf)> = cache
@@ -266,14 +495,36 @@ fn run_deploy<A: ActionRunner>(
     let existing_templates: BTreeSet<(PathBuf, PathBuf)> = cache
         .templates
         .iter()
+        .map(|(k, v)| (k.clone(), v.target.clone()))
+        .collect();
+    let existing_hardlinks: BTreeSet<(PathBuf, PathBuf)> = cache
+        .hardlinks
+        .iter()
         .map(|(k, v)| (k.clone(), v.clone()))
         .collect();
+    let existing_copies: BTreeSet<(PathBuf, PathBuf)> = cache
+        .copies
+        .iter()
+        .map(|(k, v)| (k.clone(), v.target.clone()))
+        .collect();
 
-    let desired_symlinks: BTreeMap<(PathBuf, PathBuf), _> = desired_symlinks
+    let desired_symlinks: BTreeMap<(PathBuf, PathBuf), _> = desired
+        .symlinks
+        .iter()
+        .map(|(k, v)| ((k.clone(), v.target.clone()), v))
+        .collect();
+    let desired_templates: BTreeMap<(PathBuf, PathBuf), _> = desired
+        .templates
         .iter()
         .map(|(k, v)| ((k.clone(), v.target.clone()), v))
         .collect();
-    let desired_templates: BTreeMap<(PathBuf, PathBuf), _> = desired_templates
+    let desired_hardlinks: BTreeMap<(PathBuf, PathBuf), _> = desired
+        .hardlinks
+        .iter()
+        .map(|(k, v)| ((k.clone(), v.target.clone()), v))
+        .collect();
+    let desired_copies: BTreeMap<(PathBuf, PathBuf), _> = desired
+        .copies
         .iter()
         .map(|(k, v)| ((k.clone(), v.target.clone()), v))
         .collect();
@@ -289,7 +540,7 @@ fn run_deploy<A: ActionRunner>(
             || resulting_cache.symlinks.remove(source),
             || format!("delete symlink {:?} -> {:?}", source, target),
             &mut suggest_force,
-            &mut error_occurred,
+            &mut failure,
         );
     }
 
@@ -297,11 +548,37 @@ fn run_deploy<A: ActionRunner>(
         existing_templates.difference(&desired_templates.keys().cloned().collect())
     {
         execute_action(
-            runner.delete_template(source, &opt.cache_directory.join(source), target),
+            runner.delete_template(
+                source,
+                &opt.cache_directory.join(source),
+                &cache.templates[source],
+            ),
             || resulting_cache.templates.remove(source),
             || format!("delete template {:?} -> {:?}", source, target),
             &mut suggest_force,
-            &mut error_occurred,
+            &mut failure,
+        );
+    }
+
+    for (source, target) in
+        existing_hardlinks.difference(&desired_hardlinks.keys().cloned().collect())
+    {
+        execute_action(
+            runner.delete_hardlink(source, target),
+            || resulting_cache.hardlinks.remove(source),
+            || format!("delete hardlink {:?} -> {:?}", source, target),
+            &mut suggest_force,
+            &mut failure,
+        );
+    }
+
+    for (source, target) in existing_copies.difference(&desired_copies.keys().cloned().collect()) {
+        execute_action(
+            runner.delete_copy(source, &cache.copies[source]),
+            || resulting_cache.copies.remove(source),
+            || format!("delete copy {:?} -> {:?}", source, target),
+            &mut suggest_force,
+            &mut failure,
         );
     }
 
@@ -323,7 +600,7 @@ fn run_deploy<A: ActionRunner>(
             },
             || format!("create symlink {:?} -> {:?}", source, target_path),
             &mut suggest_force,
-            &mut error_occurred,
+            &mut failure,
         );
     }
 
@@ -336,16 +613,59 @@ fn run_deploy<A: ActionRunner>(
         let target = desired_templates
             .get(&(source.into(), target_path.into()))
             .unwrap();
+        let mut cached = CachedFile::new(target_path.clone(), String::new(), None);
+        execute_action(
+            runner.create_template(
+                source,
+                &opt.cache_directory.join(source),
+                target,
+                &mut cached,
+            ),
+            || resulting_cache.templates.insert(source.clone(), cached),
+            || format!("create template {:?} -> {:?}", source, target_path),
+            &mut suggest_force,
+            &mut failure,
+        );
+    }
+
+    for (source, target_path) in desired_hardlinks
+        .keys()
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .difference(&existing_hardlinks)
+    {
+        let target = desired_hardlinks
+            .get(&(source.into(), target_path.into()))
+            .unwrap();
         execute_action(
-            runner.create_template(source, &opt.cache_directory.join(source), target),
+            runner.create_hardlink(source, target),
             || {
                 resulting_cache
-                    .templates
+                    .hardlinks
                     .insert(source.clone(), target_path.clone())
             },
-            || format!("create template {:?} -> {:?}", source, target_path),
+            || format!("create hardlink {:?} -> {:?}", source, target_path),
+            &mut suggest_force,
+            &mut failure,
+        );
+    }
+
+    for (source, target_path) in desired_copies
+        .keys()
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .difference(&existing_copies)
+    {
+        let target = desired_copies
+            .get(&(source.into(), target_path.into()))
+            .unwrap();
+        let mut cached = CachedFile::new(target_path.clone(), String::new(), None);
+        execute_action(
+            runner.create_copy(source, target, &mut cached),
+            || resulting_cache.copies.insert(source.clone(), cached),
+            || format!("create copy {:?} -> {:?}", source, target_path),
             &mut suggest_force,
-            &mut error_occurred,
+            &mut failure,
         );
     }
 
@@ -360,7 +680,7 @@ fn run_deploy<A: ActionRunner>(
             || (),
             || format!("update symlink {:?} -> {:?}", source, target_path),
             &mut suggest_force,
-            &mut error_occurred,
+            &mut failure,
         );
     }
 
@@ -370,27 +690,65 @@ fn run_deploy<A: ActionRunner>(
         let target = desired_templates
             .get(&(source.into(), target_path.into()))
             .unwrap();
+        let mut cached = cache.templates[source].clone();
         execute_action(
-            runner.update_template(source, &opt.cache_directory.join(source), target),
-            || (),
+            runner.update_template(
+                source,
+                &opt.cache_directory.join(source),
+                target,
+                &mut cached,
+            ),
+            || resulting_cache.templates.insert(source.clone(), cached),
             || format!("update template {:?} -> {:?}", source, target_path),
             &mut suggest_force,
-            &mut error_occurred,
+            &mut failure,
+        );
+    }
+
+    for (source, target_path) in
+        existing_hardlinks.intersection(&desired_hardlinks.keys().cloned().collect())
+    {
+        let target = desired_hardlinks
+            .get(&(source.into(), target_path.into()))
+            .unwrap();
+        execute_action(
+            runner.update_hardlink(source, target),
+            || (),
+            || format!("update hardlink {:?} -> {:?}", source, target_path),
+            &mut suggest_force,
+            &mut failure,
+        );
+    }
+
+    for (source, target_path) in
+        existing_copies.intersection(&desired_copies.keys().cloned().collect())
+    {
+        let target = desired_copies
+            .get(&(source.into(), target_path.into()))
+            .unwrap();
+        let mut cached = cache.copies[source].clone();
+        execute_action(
+            runner.update_copy(source, target, &mut cached),
+            || resulting_cache.copies.insert(source.clone(), cached),
+            || format!("update copy {:?} -> {:?}", source, target_path),
+            &mut suggest_force,
+            &mut failure,
         );
     }
 
     *cache = resulting_cache;
 
-    (suggest_force, error_occurred)
+    (suggest_force, failure)
 }
 
-/// Used to remove duplication
-fn execute_action<T, S: FnOnce() -> T, E: FnOnce() -> String>(
+/// Used to remove duplication.
+/// Records the exit status of the first action that fails in `failure`
+pub(crate) fn execute_action<T, S: FnOnce() -> T, E: FnOnce() -> String>(
     result: Result<bool>,
     success: S,
     context: E,
     suggest_force: &mut bool,
-    error_occurred: &mut bool,
+    failure: &mut Option<ExitStatus>,
 ) {
     match result {
         Ok(true) => {
@@ -400,15 +758,17 @@ fn execute_action<T, S: FnOnce() -> T, E: FnOnce() -> String>(
             *suggest_force = true;
         }
         Err(e) => {
+            // Only the first failure determines the exit status
+            failure.get_or_insert(exit::status_of(&e));
             display_error(e.context(context()));
-            *error_occurred = true;
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::filesystem::{SymlinkComparison, TemplateComparison};
+    use crate::actions;
+    use crate::filesystem::{HardlinkComparison, SymlinkComparison, TemplateComparison};
 
     use std::path::{Path, PathBuf};
 
@@ -421,6 +781,15 @@ mod test {
         move |actual| actual == expected
     }
 
+    fn cached_target_eq(expected: &str) -> impl Fn(&CachedFile) -> bool {
+        let expected = PathBuf::from(expected);
+        move |actual| actual.target == expected
+    }
+
+    fn cached(target: &str) -> CachedFile {
+        CachedFile::new(target.into(), "hash".into(), None)
+    }
+
     #[test]
     fn high_level_simple() {
         // State
@@ -452,14 +821,18 @@ mod test {
                 function(path_eq("b_in")),
                 function(path_eq("cache/b_in")),
                 eq(b_out),
+                always(),
             )
             .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(true));
+            .returning(|_, _, _, _| Ok(true));
 
-        let (suggest_force, error_occurred) = run_deploy(
+        let (suggest_force, failure) = run_deploy(
             &mut runner,
-            &desired_symlinks,
-            &desired_templates,
+            &DesiredFiles {
+                symlinks: desired_symlinks,
+                templates: desired_templates,
+                ..DesiredFiles::default()
+            },
             &mut cache,
             &Options {
                 cache_directory: "cache".into(),
@@ -468,8 +841,8 @@ mod test {
             },
         );
 
-        assert_eq!(suggest_force, false);
-        assert_eq!(error_occurred, false);
+        assert!(!suggest_force);
+        assert_eq!(failure, None);
 
         assert!(cache.symlinks.contains_key(&PathBuf::from("a_in")));
         assert!(cache.templates.contains_key(&PathBuf::from("b_in")));
@@ -508,15 +881,19 @@ mod test {
                 function(path_eq("b_in")),
                 function(path_eq("cache/b_in")),
                 eq(b_out),
+                always(),
             )
             .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(false));
+            .returning(|_, _, _, _| Ok(false));
 
         // Reality
-        let (suggest_force, error_occurred) = run_deploy(
+        let (suggest_force, failure) = run_deploy(
             &mut runner,
-            &desired_symlinks,
-            &desired_templates,
+            &DesiredFiles {
+                symlinks: desired_symlinks,
+                templates: desired_templates,
+                ..DesiredFiles::default()
+            },
             &mut cache,
             &Options {
                 cache_directory: "cache".into(),
@@ -525,8 +902,8 @@ mod test {
             },
         );
 
-        assert_eq!(suggest_force, true);
-        assert_eq!(error_occurred, true);
+        assert!(suggest_force);
+        assert_eq!(failure, Some(ExitStatus::Error));
 
         assert_eq!(cache.symlinks.len(), 0);
         assert_eq!(cache.templates.len(), 0);
@@ -548,6 +925,7 @@ mod test {
                 PathBuf::from("a_in") => "a_out_old".into()
             },
             templates: BTreeMap::new(),
+            ..Cache::default()
         };
 
         // Expectation
@@ -565,10 +943,12 @@ mod test {
             .returning(|_, _| Ok(true));
 
         // Reality
-        let (suggest_force, error_occurred) = run_deploy(
+        let (suggest_force, failure) = run_deploy(
             &mut runner,
-            &desired_symlinks,
-            &BTreeMap::new(),
+            &DesiredFiles {
+                symlinks: desired_symlinks,
+                ..DesiredFiles::default()
+            },
             &mut cache,
             &Options {
                 cache_directory: "cache".into(),
@@ -577,8 +957,8 @@ mod test {
             },
         );
 
-        assert_eq!(suggest_force, false);
-        assert_eq!(error_occurred, false);
+        assert!(!suggest_force);
+        assert_eq!(failure, None);
 
         assert_eq!(cache.symlinks.len(), 1);
         assert_eq!(cache.templates.len(), 0);
@@ -598,8 +978,9 @@ mod test {
         let mut cache = Cache {
             symlinks: BTreeMap::new(),
             templates: maplit::btreemap! {
-                PathBuf::from("a_in") => "a_out_old".into()
+                PathBuf::from("a_in") => cached("a_out_old")
             },
+            ..Cache::default()
         };
 
         // Expectation
@@ -609,7 +990,7 @@ mod test {
             .with(
                 function(path_eq("a_in")),
                 function(path_eq("cache/a_in")),
-                function(path_eq("a_out_old")),
+                function(cached_target_eq("a_out_old")),
             )
             .in_sequence(&mut seq)
             .returning(|_, _, _| Ok(true));
@@ -621,10 +1002,12 @@ mod test {
             .returning(|_, _| Ok(true));
 
         // Reality
-        let (suggest_force, error_occurred) = run_deploy(
+        let (suggest_force, failure) = run_deploy(
             &mut runner,
-            &desired_symlinks,
-            &BTreeMap::new(),
+            &DesiredFiles {
+                symlinks: desired_symlinks,
+                ..DesiredFiles::default()
+            },
             &mut cache,
             &Options {
                 cache_directory: "cache".into(),
@@ -633,8 +1016,8 @@ mod test {
             },
         );
 
-        assert_eq!(suggest_force, false);
-        assert_eq!(error_occurred, false);
+        assert!(!suggest_force);
+        assert_eq!(failure, None);
 
         assert_eq!(cache.symlinks.len(), 1);
         assert_eq!(cache.templates.len(), 0);
@@ -653,8 +1036,9 @@ mod test {
         let mut cache = Cache {
             symlinks: BTreeMap::new(),
             templates: maplit::btreemap! {
-                PathBuf::from("a_in") => "a_out_old".into()
+                PathBuf::from("a_in") => cached("a_out_old")
             },
+            ..Cache::default()
         };
 
         // Expectation
@@ -664,16 +1048,18 @@ mod test {
             .with(
                 function(path_eq("a_in")),
                 function(path_eq("cache/a_in")),
-                function(path_eq("a_out_old")),
+                function(cached_target_eq("a_out_old")),
             )
             .in_sequence(&mut seq)
             .returning(|_, _, _| Ok(false));
 
         // Reality
-        let (suggest_force, error_occurred) = run_deploy(
+        let (suggest_force, failure) = run_deploy(
             &mut runner,
-            &desired_symlinks,
-            &BTreeMap::new(),
+            &DesiredFiles {
+                symlinks: desired_symlinks,
+                ..DesiredFiles::default()
+            },
             &mut cache,
             &Options {
                 cache_directory: "cache".into(),
@@ -682,8 +1068,8 @@ mod test {
             },
         );
 
-        assert_eq!(suggest_force, false);
-        assert_eq!(error_occurred, false);
+        assert!(!suggest_force);
+        assert_eq!(failure, None);
 
         assert_eq!(cache.symlinks.len(), 1);
         assert_eq!(cache.templates.len(), 0);
@@ -708,49 +1094,44 @@ mod test {
             .returning(|_, _| Ok(SymlinkComparison::OnlySourceExists));
         fs.expect_create_dir_all()
             .times(1)
-            .with(function(path_eq("")), eq(None)) // parent of a_out
+            .with(function(path_eq("")), eq(None), eq(None)) // parent of a_out
             .in_sequence(&mut seq)
-            .returning(|_, _| Ok(()));
+            .returning(|_, _, _| Ok(()));
         fs.expect_make_symlink()
             .times(1)
             .with(
                 function(path_eq("a_out")),
                 function(path_eq("a_in")),
                 eq(None),
+                eq(None),
             )
             .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(()));
+            .returning(|_, _, _, _| Ok(()));
 
         // create_template
-        fs.expect_compare_template()
+        fs.expect_compare_contents()
             .times(1)
-            .with(
-                function(path_eq("b_out")),
-                function(path_eq("cache/b_cache")),
-            )
+            .with(function(path_eq("b_out")), always(), eq(None))
             .in_sequence(&mut seq)
-            .returning(|_, _| Ok(TemplateComparison::BothMissing));
+            .returning(|_, _, _| Ok(TemplateComparison::BothMissing));
         fs.expect_create_dir_all()
             .times(1)
-            .with(function(path_eq("")), eq(None)) // parent of b_out
+            .with(function(path_eq("")), eq(None), eq(None)) // parent of b_out
             .in_sequence(&mut seq)
-            .returning(|_, _| Ok(()));
-        fs.expect_read_to_string()
+            .returning(|_, _, _| Ok(()));
+        fs.expect_read()
             .times(1)
             .with(function(path_eq("b_in")))
             .in_sequence(&mut seq)
-            .returning(|_| Ok("Hello!".into()));
+            .returning(|_| Ok(b"Hello!".to_vec()));
         fs.expect_create_dir_all()
             .times(1)
-            .with(function(path_eq("cache")), eq(None))
+            .with(function(path_eq("cache")), eq(None), eq(None))
             .in_sequence(&mut seq)
-            .returning(|_, _| Ok(()));
+            .returning(|_, _, _| Ok(()));
         fs.expect_write()
             .times(1)
-            .with(
-                function(path_eq("cache/b_cache")),
-                eq(String::from("Hello!")),
-            )
+            .with(function(path_eq("cache/b_cache")), eq(b"Hello!".to_vec()))
             .in_sequence(&mut seq)
             .returning(|_, _| Ok(()));
         fs.expect_copy_file()
@@ -759,9 +1140,10 @@ mod test {
                 function(path_eq("cache/b_cache")),
                 function(path_eq("b_out")),
                 eq(None),
+                eq(None),
             )
             .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(()));
+            .returning(|_, _, _, _| Ok(()));
         fs.expect_copy_permissions()
             .times(1)
             .with(
@@ -771,25 +1153,214 @@ mod test {
             )
             .in_sequence(&mut seq)
             .returning(|_, _, _| Ok(()));
+        fs.expect_exists()
+            .times(1)
+            .with(function(path_eq("cache/b_cache")))
+            .in_sequence(&mut seq)
+            .returning(|_| false);
+        fs.expect_stamp()
+            .times(1)
+            .with(function(path_eq("b_out")))
+            .in_sequence(&mut seq)
+            .returning(|_| None);
 
         // Reality
+        let mut backups = BackupStore::new("backups".into(), Default::default());
         let mut runner = actions::RealActionRunner::new(
             &mut fs,
+            &mut backups,
             &handlebars,
             &variables,
             opt.force,
+            opt.merge,
             opt.diff_context_lines,
+            DiffSink::Stdout,
         );
         assert!(runner
             .create_symlink(&PathBuf::from("a_in"), &PathBuf::from("a_out").into())
             .unwrap());
+        let mut b_cached = cached("b_out");
         assert!(runner
             .create_template(
                 &PathBuf::from("b_in"),
                 &PathBuf::from("cache/b_cache"),
                 &PathBuf::from("b_out").into(),
+                &mut b_cached,
             )
             .unwrap());
+        // Only the hash of the render is cached
+        assert_eq!(b_cached.hash, filesystem::hash_contents(b"Hello!"));
+    }
+
+    #[test]
+    fn low_level_mode() {
+        // Setup
+        let mut fs = crate::filesystem::MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+
+        let opt = Options::default();
+        let handlebars = handlebars::Handlebars::new();
+        let variables = Default::default();
+
+        let b_out = TemplateTarget {
+            mode: Some(crate::config::Mode(0o600)),
+            dir_mode: Some(crate::config::Mode(0o700)),
+            ..PathBuf::from("missing_parent/b_out").into()
+        };
+
+        // Expectation:
+        // create_template, setting modes instead of copying them
+        fs.expect_compare_contents()
+            .times(1)
+            .returning(|_, _, _| Ok(TemplateComparison::BothMissing));
+        fs.expect_exists()
+            .times(1)
+            .with(function(path_eq("missing_parent")))
+            .in_sequence(&mut seq)
+            .returning(|_| false);
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("missing_parent")), eq(None), eq(None))
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_set_mode()
+            .times(1)
+            .with(function(path_eq("missing_parent")), eq(0o700), eq(None))
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_read()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(b"Hello!".to_vec()));
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("cache")), eq(None), eq(None))
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_write()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        fs.expect_copy_file()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _| Ok(()));
+        fs.expect_set_mode()
+            .times(1)
+            .with(
+                function(path_eq("missing_parent/b_out")),
+                eq(0o600),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_exists()
+            .times(1)
+            .with(function(path_eq("cache/b_cache")))
+            .in_sequence(&mut seq)
+            .returning(|_| false);
+        fs.expect_stamp()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| None);
+        fs.expect_copy_permissions().never();
+
+        // Reality
+        let mut backups = BackupStore::new("backups".into(), Default::default());
+        let mut runner = actions::RealActionRunner::new(
+            &mut fs,
+            &mut backups,
+            &handlebars,
+            &variables,
+            opt.force,
+            opt.merge,
+            opt.diff_context_lines,
+            DiffSink::Stdout,
+        );
+        assert!(runner
+            .create_template(
+                &PathBuf::from("b_in"),
+                &PathBuf::from("cache/b_cache"),
+                &b_out,
+                &mut cached("missing_parent/b_out"),
+            )
+            .unwrap());
+    }
+
+    #[test]
+    fn low_level_hardlink() {
+        // Setup
+        let mut fs = crate::filesystem::MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+
+        let opt = Options::default();
+        let handlebars = handlebars::Handlebars::new();
+        let variables = Default::default();
+
+        // Expectation:
+        // create_hardlink
+        fs.expect_compare_hardlink()
+            .times(1)
+            .with(function(path_eq("a_in")), function(path_eq("a_out")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(HardlinkComparison::OnlySourceExists));
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("")), eq(None), eq(None)) // parent of a_out
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_make_hardlink()
+            .times(1)
+            .with(function(path_eq("a_out")), function(path_eq("a_in")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+
+        // update_hardlink, with a target that was replaced
+        fs.expect_compare_hardlink()
+            .times(1)
+            .with(function(path_eq("b_in")), function(path_eq("b_out")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(HardlinkComparison::Changed));
+
+        // delete_hardlink
+        fs.expect_compare_hardlink()
+            .times(1)
+            .with(function(path_eq("c_in")), function(path_eq("c_out")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(HardlinkComparison::Identical));
+        fs.expect_remove_file()
+            .times(1)
+            .with(function(path_eq("c_out")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        fs.expect_delete_parents()
+            .times(1)
+            .with(function(path_eq("c_out")), eq(false))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+
+        // Reality
+        let mut backups = BackupStore::new("backups".into(), Default::default());
+        let mut runner = actions::RealActionRunner::new(
+            &mut fs,
+            &mut backups,
+            &handlebars,
+            &variables,
+            opt.force,
+            opt.merge,
+            opt.diff_context_lines,
+            DiffSink::Stdout,
+        );
+
+        assert!(runner
+            .create_hardlink(&PathBuf::from("a_in"), &PathBuf::from("a_out").into())
+            .unwrap());
+        assert!(!runner
+            .update_hardlink(&PathBuf::from("b_in"), &PathBuf::from("b_out").into())
+            .unwrap());
+        assert!(runner
+            .delete_hardlink(&PathBuf::from("c_in"), &PathBuf::from("c_out"))
+            .unwrap());
     }
 
     #[test]
@@ -811,22 +1382,23 @@ mod test {
             .returning(|_, _| Ok(SymlinkComparison::Changed));
 
         // create_template
-        fs.expect_compare_template()
+        fs.expect_compare_contents()
             .times(1)
-            .with(
-                function(path_eq("b_out")),
-                function(path_eq("cache/b_cache")),
-            )
+            .with(function(path_eq("b_out")), always(), eq(None))
             .in_sequence(&mut seq)
-            .returning(|_, _| Ok(TemplateComparison::Changed));
+            .returning(|_, _, _| Ok(TemplateComparison::OnlyTargetExists));
 
         // Reality
+        let mut backups = BackupStore::new("backups".into(), Default::default());
         let mut runner = actions::RealActionRunner::new(
             &mut fs,
+            &mut backups,
             &handlebars,
             &variables,
             opt.force,
+            opt.merge,
             opt.diff_context_lines,
+            DiffSink::Stdout,
         );
 
         // Both should skip
@@ -838,7 +1410,107 @@ mod test {
                 &PathBuf::from("b_in"),
                 &PathBuf::from("cache/b_cache"),
                 &PathBuf::from("b_out").into(),
+                &mut cached("b_out"),
             )
             .unwrap());
     }
+
+    #[test]
+    fn memory_deploy_update_undeploy() {
+        use crate::config::{Mode, UnixUser};
+        use crate::filesystem::MemoryFilesystem;
+
+        let mut fs = MemoryFilesystem::new();
+        fs.create_dir_all(Path::new("dots"), &None, &None).unwrap();
+        fs.create_dir_all(Path::new("/home/u"), &None, &None)
+            .unwrap();
+        fs.write(Path::new("dots/rc"), "rc".into()).unwrap();
+        fs.write(Path::new("dots/conf"), "name = {{name}}".into())
+            .unwrap();
+        fs.write(Path::new("dots/key"), "secret".into()).unwrap();
+
+        let root = UnixUser::Name("root".into());
+        let desired = DesiredFiles {
+            symlinks: maplit::btreemap! {
+                PathBuf::from("dots/rc") => PathBuf::from("/home/u/.rc").into(),
+            },
+            templates: maplit::btreemap! {
+                PathBuf::from("dots/conf") => TemplateTarget {
+                    owner: Some(root.clone()),
+                    ..PathBuf::from("/etc/app/conf").into()
+                },
+            },
+            copies: maplit::btreemap! {
+                PathBuf::from("dots/key") => CopyTarget {
+                    mode: Some(Mode(0o600)),
+                    ..PathBuf::from("/home/u/.ssh/key").into()
+                },
+            },
+            hardlinks: BTreeMap::new(),
+        };
+        let opt = Options {
+            cache_directory: "cache".into(),
+            ..Options::default()
+        };
+        let handlebars = Handlebars::new();
+        let mut variables = Variables::new();
+        variables.insert("name".into(), "dotter".into());
+
+        let mut cache = Cache::default();
+        let deploy = |fs: &mut MemoryFilesystem, desired: &DesiredFiles, cache: &mut Cache| {
+            let mut backups = BackupStore::new("backups".into(), Default::default());
+            let mut runner = actions::RealActionRunner::new(
+                fs,
+                &mut backups,
+                &handlebars,
+                &variables,
+                opt.force,
+                opt.merge,
+                opt.diff_context_lines,
+                DiffSink::Stdout,
+            );
+            run_deploy(&mut runner, desired, cache, &opt)
+        };
+
+        assert_eq!(deploy(&mut fs, &desired, &mut cache), (false, None));
+        assert_eq!(
+            fs.compare_symlink(Path::new("dots/rc"), Path::new("/home/u/.rc"))
+                .unwrap(),
+            SymlinkComparison::Identical
+        );
+        let conf = fs.node(Path::new("/etc/app/conf")).unwrap().unwrap();
+        assert_eq!(conf.contents(), Some(&b"name = dotter"[..]));
+        assert_eq!(conf.owner, root);
+        let key = fs.node(Path::new("/home/u/.ssh/key")).unwrap().unwrap();
+        assert_eq!((key.owner, key.mode), (UnixUser::Uid(1000), 0o600));
+
+        // Like `watch` does once the template changes
+        fs.write(Path::new("dots/conf"), "user = {{name}}".into())
+            .unwrap();
+        assert_eq!(deploy(&mut fs, &desired, &mut cache), (false, None));
+        let conf = fs.node(Path::new("/etc/app/conf")).unwrap().unwrap();
+        assert_eq!(conf.contents(), Some(&b"user = dotter"[..]));
+        assert_eq!(conf.owner, root);
+
+        // Modes are restored even though the contents are unchanged
+        fs.set_mode(Path::new("/home/u/.ssh/key"), 0o644, &None)
+            .unwrap();
+        assert_eq!(deploy(&mut fs, &desired, &mut cache), (false, None));
+        let key = fs.node(Path::new("/home/u/.ssh/key")).unwrap().unwrap();
+        assert_eq!(key.mode, 0o600);
+
+        // Undeploying removes the targets and the directories they leave empty
+        let nothing = DesiredFiles {
+            symlinks: BTreeMap::new(),
+            templates: BTreeMap::new(),
+            copies: BTreeMap::new(),
+            hardlinks: BTreeMap::new(),
+        };
+        assert_eq!(deploy(&mut fs, &nothing, &mut cache), (false, None));
+        for target in ["/home/u/.rc", "/etc/app", "/home/u/.ssh"] {
+            assert!(!fs.exists(Path::new(target)), "{} still exists", target);
+        }
+        assert!(fs.exists(Path::new("dots/conf")));
+        assert!(cache.symlinks.is_empty() && cache.templates.is_empty() && cache.copies.is_empty());
+    }
 }
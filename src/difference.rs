@@ -4,9 +4,41 @@ use handlebars::Handlebars;
 
 use std::cmp::{max, min};
 use std::fs;
-use std::path::Path;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 
-use crate::config::{TemplateTarget, Variables};
+use crate::args::{Options, OutputFormat};
+use crate::config::{Configuration, TemplateTarget, Variables};
+use crate::deploy::{desired_files, load_configuration_and_cache};
+use crate::exit::{ExitStatus, Failure};
+use crate::filesystem::{self, Filesystem};
+use crate::handlebars_helpers::create_new_handlebars;
+
+/// Where diffs are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffSink {
+    Stdout,
+    /// For when stdout is reserved for machine-readable output
+    Stderr,
+}
+
+impl DiffSink {
+    /// Where diffs of the actions performed with `opt` go
+    pub fn of(opt: &Options) -> DiffSink {
+        if opt.output == OutputFormat::Json {
+            DiffSink::Stderr
+        } else {
+            DiffSink::Stdout
+        }
+    }
+
+    fn println(self, line: std::fmt::Arguments) {
+        match self {
+            DiffSink::Stdout => println!("{}", line),
+            DiffSink::Stderr => eprintln!("{}", line),
+        }
+    }
+}
 
 pub type Diff = Vec<diff::Result<String>>;
 pub type HunkDiff = Vec<(usize, usize, Diff)>;
@@ -14,12 +46,14 @@ pub type HunkDiff = Vec<(usize, usize, Diff)>;
 pub fn print_template_diff(
     source: &Path,
     target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
     diff_context_lines: usize,
+    sink: DiffSink,
 ) {
     if log_enabled!(log::Level::Info) {
-        match generate_template_diff(source, target, handlebars, variables, true) {
+        match generate_template_diff(source, target, fs, handlebars, variables, true) {
             Ok(diff) => {
                 if diff_nonempty(&diff) {
                     info!(
@@ -28,7 +62,7 @@ pub fn print_template_diff(
                         source,
                         target.target
                     );
-                    print_diff(diff, diff_context_lines);
+                    print_diff(diff, diff_context_lines, sink);
                 }
             }
             Err(e) => {
@@ -41,29 +75,84 @@ pub fn print_template_diff(
     }
 }
 
+/// Renders the template source with the target's `append`/`prepend` actions applied
+pub fn render_template(
+    source: &Path,
+    target: &TemplateTarget,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+) -> Result<Vec<u8>> {
+    let file_contents = fs::read(source).context("read template source file")?;
+    render_contents(source, file_contents, target, handlebars, variables)
+}
+
+/// Renders the contents of a template source with the target's `append`/`prepend` actions applied.
+/// Contents that aren't valid UTF-8 (like binary files) can't be rendered, so they're only
+/// surrounded by the (unrendered) actions
+pub fn render_contents(
+    source: &Path,
+    contents: Vec<u8>,
+    target: &TemplateTarget,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+) -> Result<Vec<u8>> {
+    match String::from_utf8(contents) {
+        Ok(contents) => Ok(handlebars
+            .render_template(&target.apply_actions(contents), variables)
+            .context(Failure::new(ExitStatus::Render, "render template"))?
+            .into_bytes()),
+        Err(e) => {
+            debug!(
+                "Template {:?} is not valid UTF-8, so it's deployed without rendering",
+                source
+            );
+            let mut rendered = target.prepend.clone().unwrap_or_default().into_bytes();
+            rendered.extend(e.into_bytes());
+            rendered.extend(target.append.iter().flat_map(|append| append.bytes()));
+            Ok(rendered)
+        }
+    }
+}
+
 pub fn generate_template_diff(
     source: &Path,
     target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
     source_to_target: bool,
 ) -> Result<Diff> {
-    let file_contents = fs::read_to_string(source).context("read template source file")?;
-    let file_contents = target.apply_actions(file_contents);
-    let rendered = handlebars
-        .render_template(&file_contents, variables)
-        .context("render template")?;
+    let contents = fs.read(source).context("read template source file")?;
+    let rendered = render_contents(source, contents, target, handlebars, variables)?;
 
-    let target_contents =
-        fs::read_to_string(&target.target).context("read template target file")?;
+    let target_contents = fs
+        .read(&target.target)
+        .context("read template target file")?;
 
-    let diff_result = if source_to_target {
-        diff::lines(&target_contents, &rendered)
+    Ok(if source_to_target {
+        diff_contents(&target_contents, &rendered)
     } else {
-        diff::lines(&rendered, &target_contents)
-    };
+        diff_contents(&rendered, &target_contents)
+    })
+}
 
-    Ok(diff_result.into_iter().map(to_owned_diff_result).collect())
+/// Diffs the contents line by line, or as a whole if either of them isn't valid UTF-8
+pub fn diff_contents(old: &[u8], new: &[u8]) -> Diff {
+    match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+        (Ok(old), Ok(new)) => diff_strings(old, new),
+        _ if old == new => Vec::new(),
+        _ => vec![
+            diff::Result::Left(format!("<binary contents, {} bytes>", old.len())),
+            diff::Result::Right(format!("<binary contents, {} bytes>", new.len())),
+        ],
+    }
+}
+
+pub fn diff_strings(old: &str, new: &str) -> Diff {
+    diff::lines(old, new)
+        .into_iter()
+        .map(to_owned_diff_result)
+        .collect()
 }
 
 fn to_owned_diff_result(from: diff::Result<&str>) -> diff::Result<String> {
@@ -86,7 +175,7 @@ pub fn diff_nonempty(diff: &[diff::Result<String>]) -> bool {
     false
 }
 
-fn hunkify_diff(diff: Diff, extra_lines: usize) -> HunkDiff {
+pub fn hunkify_diff(diff: Diff, extra_lines: usize) -> HunkDiff {
     let mut hunks = vec![];
 
     let mut left_line_number: usize = 1;
@@ -153,45 +242,51 @@ fn is_different(diff: &diff::Result<String>) -> bool {
     !matches!(diff, diff::Result::Both(..))
 }
 
-fn print_hunk(mut left_line: usize, mut right_line: usize, hunk: Diff, max_digits: usize) {
+pub fn print_hunk(
+    mut left_line: usize,
+    mut right_line: usize,
+    hunk: Diff,
+    max_digits: usize,
+    sink: DiffSink,
+) {
     for line in hunk {
         match line {
             diff::Result::Left(l) => {
-                println!(
+                sink.println(format_args!(
                     " {:>width$} | {:>width$} | {}",
                     left_line.to_string().red(),
                     "",
                     l.red(),
                     width = max_digits
-                );
+                ));
                 left_line += 1;
             }
             diff::Result::Both(l, _) => {
-                println!(
+                sink.println(format_args!(
                     " {:>width$} | {:>width$} | {}",
                     left_line.to_string().dark_grey(),
                     right_line.to_string().dark_grey(),
                     l,
                     width = max_digits
-                );
+                ));
                 left_line += 1;
                 right_line += 1;
             }
             diff::Result::Right(r) => {
-                println!(
+                sink.println(format_args!(
                     " {:>width$} | {:>width$} | {}",
                     "",
                     right_line.to_string().green(),
                     r.green(),
                     width = max_digits
-                );
+                ));
                 right_line += 1;
             }
         }
     }
 }
 
-pub fn print_diff(diff: Diff, extra_lines: usize) {
+pub fn print_diff(diff: Diff, extra_lines: usize, sink: DiffSink) {
     let mut diff = hunkify_diff(diff, extra_lines);
 
     let last_hunk = diff.pop().expect("at least one hunk");
@@ -199,9 +294,438 @@ pub fn print_diff(diff: Diff, extra_lines: usize) {
     let max_possible_digits = max_possible_line.to_string().len(); // yes I could log10, whatever
 
     for hunk in diff {
-        print_hunk(hunk.0, hunk.1, hunk.2, max_possible_digits);
-        println!();
+        print_hunk(hunk.0, hunk.1, hunk.2, max_possible_digits, sink);
+        sink.println(format_args!(""));
+    }
+
+    print_hunk(
+        last_hunk.0,
+        last_hunk.1,
+        last_hunk.2,
+        max_possible_digits,
+        sink,
+    );
+}
+
+/// Replaces the lines on the left side of `hunk` with the lines on its right side inside `text`.
+/// If the hunk's context lines can't be found (for example because they were rendered from
+/// a template expression), retries with less context.
+/// Returns None if the change can't be located in `text` unambiguously.
+pub fn apply_hunk(text: &str, hunk: &[diff::Result<String>]) -> Option<String> {
+    let separator = if text.contains("\r\n") { "\r\n" } else { "\n" };
+
+    // Split the same way `diff::lines` does, so that the trailing newline is preserved
+    let mut lines = text.lines().collect::<Vec<_>>();
+    if text.ends_with('\n') {
+        lines.push("");
+    }
+
+    let first_change = hunk.iter().position(is_different)?;
+    let last_change = hunk.iter().rposition(is_different)?;
+    let leading = first_change;
+    let trailing = hunk.len() - 1 - last_change;
+
+    // Try with as much context as possible first
+    for trimmed in 0..=(leading + trailing) {
+        for trim_start in trimmed.saturating_sub(trailing)..=trimmed.min(leading) {
+            let trim_end = trimmed - trim_start;
+            let hunk = &hunk[trim_start..hunk.len() - trim_end];
+            if let Some(applied) = apply_hunk_exact(&lines, hunk, separator) {
+                return Some(applied);
+            }
+        }
+    }
+
+    None
+}
+
+fn apply_hunk_exact(
+    lines: &[&str],
+    hunk: &[diff::Result<String>],
+    separator: &str,
+) -> Option<String> {
+    let mut old = vec![];
+    let mut new = vec![];
+    for line in hunk {
+        match line {
+            diff::Result::Left(l) => old.push(l.as_str()),
+            diff::Result::Right(r) => new.push(r.as_str()),
+            diff::Result::Both(l, _) => {
+                old.push(l.as_str());
+                new.push(l.as_str());
+            }
+        }
+    }
+
+    let position = if old.is_empty() {
+        if !lines.is_empty() {
+            return None;
+        }
+        0
+    } else {
+        let mut matches = lines
+            .windows(old.len())
+            .enumerate()
+            .filter(|(_, window)| *window == old.as_slice())
+            .map(|(i, _)| i);
+        match (matches.next(), matches.next()) {
+            (Some(i), None) => i,
+            _ => return None,
+        }
+    };
+
+    let mut lines = lines.to_vec();
+    lines.splice(position..position + old.len(), new);
+    Some(lines.join(separator))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct MergeResult {
+    pub text: String,
+    /// Amount of regions that were changed differently on both sides
+    pub conflicts: usize,
+}
+
+/// Three-way merges the line-based changes from `base` to `ours` with the changes from `base`
+/// to `theirs`. Regions which were changed differently on both sides are surrounded by
+/// conflict markers.
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> MergeResult {
+    let separator = if ours.contains("\r\n") { "\r\n" } else { "\n" };
+    let split = |text: &str| {
+        let mut lines = text.lines().map(String::from).collect::<Vec<_>>();
+        if text.ends_with('\n') {
+            lines.push(String::new());
+        }
+        lines
+    };
+    let (base, ours, theirs) = (split(base), split(ours), split(theirs));
+    let ours_matches = matching_lines(&base, &ours);
+    let theirs_matches = matching_lines(&base, &theirs);
+
+    let mut output: Vec<String> = vec![];
+    let mut conflicts = 0;
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        // Next line of base that's unchanged on both sides
+        let stable = (b..base.len())
+            .find_map(|i| Some((i, ours_matches[i]?, theirs_matches[i]?)))
+            .unwrap_or((base.len(), ours.len(), theirs.len()));
+
+        let (base_chunk, ours_chunk, theirs_chunk) =
+            (&base[b..stable.0], &ours[o..stable.1], &theirs[t..stable.2]);
+        if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            output.extend_from_slice(theirs_chunk);
+        } else if theirs_chunk == base_chunk {
+            output.extend_from_slice(ours_chunk);
+        } else {
+            conflicts += 1;
+            output.push("<<<<<<< target".into());
+            output.extend_from_slice(ours_chunk);
+            output.push("||||||| last deploy".into());
+            output.extend_from_slice(base_chunk);
+            output.push("=======".into());
+            output.extend_from_slice(theirs_chunk);
+            output.push(">>>>>>> source".into());
+        }
+
+        if stable.0 == base.len() {
+            break;
+        }
+        output.push(base[stable.0].clone());
+        b = stable.0 + 1;
+        o = stable.1 + 1;
+        t = stable.2 + 1;
+    }
+
+    MergeResult {
+        text: output.join(separator),
+        conflicts,
+    }
+}
+
+/// For every line in `base`, the index of the same line in `other` if it's unchanged
+fn matching_lines(base: &[String], other: &[String]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+    let (mut b, mut o) = (0, 0);
+    for line in diff::slice(base, other) {
+        match line {
+            diff::Result::Left(_) => b += 1,
+            diff::Result::Right(_) => o += 1,
+            diff::Result::Both(..) => {
+                matches[b] = Some(o);
+                b += 1;
+                o += 1;
+            }
+        }
+    }
+    matches
+}
+
+/// Prints the difference between the deployed and freshly rendered version of every template,
+/// without writing anything or running hooks.
+/// If `paths` or `packages` are nonempty, only templates matching at least one of them are shown.
+/// Returns true if any differences were found
+pub fn show_diffs(opt: &Options, paths: &[PathBuf], packages: &[String]) -> Result<bool> {
+    let (mut config, cache) = load_configuration_and_cache(opt)?;
+    let filter = file_filter(&config, paths, packages)?;
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+    let desired = desired_files(config.files).context("sort files by how they're deployed")?;
+
+    let mut differences_found = false;
+    for (source, target) in desired
+        .templates
+        .iter()
+        .filter(|(source, target)| filter(source, &target.target))
+    {
+        let rendered = render_template(source, target, &handlebars, &config.variables)
+            .with_context(|| format!("render template {:?}", source))?;
+        let target_contents = read_optional(&target.target)
+            .with_context(|| format!("read target {:?}", target.target))?;
+
+        let diff = diff_contents(target_contents.as_deref().unwrap_or_default(), &rendered);
+        if diff_nonempty(&diff) {
+            differences_found = true;
+            println!(
+                "{} template {:?} -> {:?}{}",
+                "[~]".yellow(),
+                source,
+                target.target,
+                if target_contents.is_none() {
+                    " (target missing)"
+                } else {
+                    ""
+                }
+            );
+            print_diff(diff, opt.diff_context_lines, DiffSink::Stdout);
+            println!();
+        }
+
+        // The cache copy (kept for templates that can be merged) is what was rendered on the last
+        // deploy - if the target was edited since then, show what the source changed compared to
+        // it as well
+        if cache.templates.get(source).map(|template| &template.target) == Some(&target.target) {
+            let cache_file = opt.cache_directory.join(source);
+            let cache_contents = read_optional(&cache_file)
+                .with_context(|| format!("read cache {:?}", cache_file))?;
+            if let Some(cache_contents) = cache_contents {
+                if Some(&cache_contents) != target_contents.as_ref() {
+                    let diff = diff_contents(&cache_contents, &rendered);
+                    if diff_nonempty(&diff) {
+                        differences_found = true;
+                        println!(
+                            "{} cache {:?} -> rendered {:?}",
+                            "[~]".yellow(),
+                            cache_file,
+                            source
+                        );
+                        print_diff(diff, opt.diff_context_lines, DiffSink::Stdout);
+                        println!();
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(differences_found)
+}
+
+/// Returns a predicate on (source, target) which selects files matching the given paths or
+/// declared by the given packages
+fn file_filter(
+    config: &Configuration,
+    paths: &[PathBuf],
+    packages: &[String],
+) -> Result<impl Fn(&Path, &Path) -> bool> {
+    let mut prefixes = paths.to_vec();
+    for package in packages {
+        prefixes.extend(
+            config
+                .package_files
+                .get(package)
+                .with_context(|| format!("package {:?} is not enabled", package))?
+                .iter()
+                .cloned(),
+        );
+    }
+    let filtered = !paths.is_empty() || !packages.is_empty();
+    let inside = inside_any(&prefixes)?;
+
+    Ok(move |source: &Path, target: &Path| !filtered || inside(source, target))
+}
+
+/// Returns a predicate on (source, target) which selects files whose source or target is inside
+/// one of `prefixes`. Sources are relative to the current directory and targets are absolute,
+/// so both sides are compared as absolute paths
+pub(crate) fn inside_any(prefixes: &[PathBuf]) -> Result<impl Fn(&Path, &Path) -> bool> {
+    let current_dir = std::env::current_dir().context("get current directory")?;
+    let prefixes = prefixes
+        .iter()
+        .map(|prefix| filesystem::normalize_path(&current_dir, prefix))
+        .collect::<Vec<_>>();
+
+    Ok(move |source: &Path, target: &Path| {
+        let (source, target) = (
+            filesystem::normalize_path(&current_dir, source),
+            filesystem::normalize_path(&current_dir, target),
+        );
+        prefixes
+            .iter()
+            .any(|prefix| source.starts_with(prefix) || target.starts_with(prefix))
+    })
+}
+
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("read file"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply_hunk_simple() {
+        let hunk = hunkify_diff(diff_strings("a\nb\nc\n", "a\nB\nc\n"), 1)
+            .pop()
+            .unwrap()
+            .2;
+        assert_eq!(
+            apply_hunk("x\na\nb\nc\ny\n", &hunk).unwrap(),
+            "x\na\nB\nc\ny\n"
+        );
+        assert_eq!(
+            apply_hunk("x\r\na\r\nb\r\nc\r\n", &hunk).unwrap(),
+            "x\r\na\r\nB\r\nc\r\n"
+        );
+    }
+
+    #[test]
+    fn apply_hunk_mismatch() {
+        let hunk = hunkify_diff(diff_strings("a\nb\nc\n", "a\nB\nc\n"), 1)
+            .pop()
+            .unwrap()
+            .2;
+        // Changed line was rendered from a template expression
+        assert_eq!(apply_hunk("a\n{{b}}\nc\n", &hunk), None);
+        // Ambiguous position
+        assert_eq!(apply_hunk("a\nb\nc\na\nb\nc\n", &hunk), None);
+    }
+
+    #[test]
+    fn merge3_clean() {
+        let base = "a\nb\nc\nd\n";
+        let ours = "a\nB\nc\nd\n";
+        let theirs = "a\nb\nc\nD\ne\n";
+        assert_eq!(
+            merge3(base, ours, theirs),
+            MergeResult {
+                text: "a\nB\nc\nD\ne\n".into(),
+                conflicts: 0
+            }
+        );
+        // Same change on both sides
+        assert_eq!(merge3(base, ours, ours).text, ours);
+    }
+
+    #[test]
+    fn merge3_conflict() {
+        let result = merge3("a\nb\nc\n", "a\nX\nc\n", "a\nY\nc\n");
+        assert_eq!(result.conflicts, 1);
+        assert_eq!(
+            result.text,
+            "a\n<<<<<<< target\nX\n||||||| last deploy\nb\n=======\nY\n>>>>>>> source\nc\n"
+        );
+    }
+
+    #[test]
+    fn apply_hunk_templated_context() {
+        let hunk = hunkify_diff(diff_strings("a\nb\nc\n", "a\nB\nc\n"), 1)
+            .pop()
+            .unwrap()
+            .2;
+        assert_eq!(apply_hunk("{{a}}\nb\nc\n", &hunk).unwrap(), "{{a}}\nB\nc\n");
+        assert_eq!(
+            apply_hunk("{{a}}\nb\n{{c}}\n", &hunk).unwrap(),
+            "{{a}}\nB\n{{c}}\n"
+        );
     }
 
-    print_hunk(last_hunk.0, last_hunk.1, last_hunk.2, max_possible_digits);
+    #[test]
+    fn render_binary_contents() {
+        let handlebars = Handlebars::new();
+        let target = TemplateTarget {
+            prepend: Some("{{a}}".into()),
+            append: Some("\n".into()),
+            ..PathBuf::from("target").into()
+        };
+        let contents = b"\xff{{b}}\x00".to_vec();
+        assert_eq!(
+            render_contents(
+                Path::new("source"),
+                contents,
+                &target,
+                &handlebars,
+                &Variables::new()
+            )
+            .unwrap(),
+            b"{{a}}\xff{{b}}\x00\n"
+        );
+    }
+
+    #[test]
+    fn diff_binary_contents() {
+        assert!(!diff_nonempty(&diff_contents(b"\xff\x00", b"\xff\x00")));
+        assert!(diff_nonempty(&diff_contents(b"\xff\x00", b"\xfe\x00")));
+        assert!(diff_nonempty(&diff_contents(b"text", b"\xfe\x00")));
+        assert_eq!(diff_contents(b"a\n", b"b\n"), diff_strings("a\n", "b\n"));
+    }
+
+    #[test]
+    fn filter_files() {
+        let config = Configuration {
+            files: Default::default(),
+            variables: Variables::new(),
+            helpers: Default::default(),
+            packages: vec!["shell".into(), "git".into()],
+            package_files: maplit::btreemap! {
+                "shell".into() => vec![PathBuf::from("shell")],
+                "git".into() => vec![PathBuf::from("gitconfig")],
+            },
+            hooks: Vec::new(),
+            recurse: true,
+        };
+        let (bashrc, gitconfig) = (
+            (Path::new("shell/bashrc"), Path::new("/home/u/.bashrc")),
+            (
+                Path::new("gitconfig"),
+                Path::new("/home/u/.config/git/config"),
+            ),
+        );
+        let selects = |paths: &[&str], packages: &[&str]| {
+            let paths = paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+            let packages = packages.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            let filter = file_filter(&config, &paths, &packages).unwrap();
+            (filter(bashrc.0, bashrc.1), filter(gitconfig.0, gitconfig.1))
+        };
+
+        assert_eq!(selects(&[], &[]), (true, true));
+        assert_eq!(selects(&["shell"], &[]), (true, false));
+        assert_eq!(selects(&["/home/u/.config"], &[]), (false, true));
+        assert_eq!(selects(&["/home/u/.bash"], &[]), (false, false));
+        assert_eq!(selects(&[], &["git"]), (false, true));
+        assert_eq!(selects(&["shell"], &["git"]), (true, true));
+        assert_eq!(selects(&["./shell"], &[]), (true, false));
+        assert_eq!(selects(&["./gitconfig/"], &[]), (false, true));
+
+        // A relative target-side path is relative to the current directory
+        let to_root = "../".repeat(std::env::current_dir().unwrap().components().count());
+        assert_eq!(
+            selects(&[&format!("{}home/u/.config", to_root)], &[]),
+            (false, true)
+        );
+        assert!(file_filter(&config, &[], &["unused".into()]).is_err());
+    }
 }
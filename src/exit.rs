@@ -0,0 +1,100 @@
+/// Statuses Dotter exits with. Keep in sync with `EXIT_STATUS_HELP`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Success = 0,
+    /// Any other error, or `status`/`diff`/`adopt` found something out of sync
+    Error = 1,
+    // 2 is used by clap for invalid arguments
+    Config = 3,
+    Render = 4,
+    Skipped = 5,
+    Hook = 6,
+    Permission = 7,
+    Cache = 8,
+}
+
+pub const EXIT_STATUS_HELP: &str = "Exit statuses:
+  0  Success
+  1  Other error, or `status`/`diff`/`adopt` found files out of sync, or `cache repair`
+     could not reconcile some files
+  2  Invalid command line arguments
+  3  Configuration could not be loaded or is invalid
+  4  A template could not be rendered
+  5  Some files were skipped because their target was modified (see --force and --merge)
+  6  A hook failed
+  7  Permission denied, or elevating permissions failed or is disabled (see --escalate)
+  8  The cache file is corrupted (see `cache repair`), or was written by a newer version of Dotter";
+
+/// An error (or context on an error) which determines the status Dotter exits with
+#[derive(Debug)]
+pub struct Failure {
+    pub status: ExitStatus,
+    message: String,
+}
+
+impl Failure {
+    pub fn new(status: ExitStatus, message: impl Into<String>) -> Failure {
+        Failure {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.message.fmt(f)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Finds the status for an error - from the outermost `Failure` in its chain,
+/// or `Permission` if it was caused by being denied access to a file
+pub fn status_of(error: &anyhow::Error) -> ExitStatus {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        return failure.status;
+    }
+
+    let permission_denied = error.chain().any(|e| {
+        e.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+    });
+    if permission_denied {
+        ExitStatus::Permission
+    } else {
+        ExitStatus::Error
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn outermost_failure_wins() {
+        let error: anyhow::Result<()> = Err(Failure::new(ExitStatus::Render, "render template"))
+            .context("deploy template")
+            .context(Failure::new(ExitStatus::Hook, "run pre-deploy hook"))
+            .context("deploy");
+        let error = error.unwrap_err();
+        assert_eq!(status_of(&error), ExitStatus::Hook);
+        assert_eq!(
+            error.chain().nth(1).unwrap().to_string(),
+            "run pre-deploy hook"
+        );
+    }
+
+    #[test]
+    fn permission_denied() {
+        let error: anyhow::Result<()> =
+            Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied)).context("remove file");
+        assert_eq!(status_of(&error.unwrap_err()), ExitStatus::Permission);
+        assert_eq!(
+            status_of(&anyhow::anyhow!("something else")),
+            ExitStatus::Error
+        );
+    }
+}
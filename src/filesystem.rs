@@ -2,15 +2,19 @@ use anyhow::{Context, Result};
 
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
+use sha2::{Digest, Sha256};
 
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fs::{self, File};
 use std::io::{self, ErrorKind, Read};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 #[cfg(unix)]
 use std::process::Command;
 
-use crate::config::UnixUser;
+use crate::config::{Escalation, UnixGroup, UnixUser};
+#[cfg(unix)]
+use crate::exit::{ExitStatus, Failure};
 
 // === Serialize/deserialize files ===
 
@@ -36,7 +40,131 @@ where
     T: Serialize,
 {
     let data = toml::to_string(&data).context("serialize data")?;
-    fs::write(filename, data).context("write to file")
+    if let Some(parent) = filename.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).context("create parent directory")?;
+    }
+    write_atomically(filename, data.as_bytes(), None).context("write to file")
+}
+
+// === Atomic writes ===
+
+/// Replaces the contents of a file without ever leaving it truncated: the contents are written
+/// to a temporary file next to it, synced to disk and renamed over it.
+/// The new file gets `permissions` if given, or otherwise the permissions (and, on Unix, the
+/// owner and group) of the file it replaces. Symlinks are followed, like `fs::write` does.
+pub fn write_atomically(
+    path: &Path,
+    contents: &[u8],
+    permissions: Option<fs::Permissions>,
+) -> Result<()> {
+    use std::io::Write;
+
+    let path = match path.symlink_metadata() {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            fs::canonicalize(path).context("follow symlink")?
+        }
+        _ => path.to_path_buf(),
+    };
+    let existing = optional_metadata(path.metadata()).context("get metadata of file")?;
+
+    #[cfg(unix)]
+    if let Some(existing) = &existing {
+        use std::os::unix::fs::MetadataExt;
+        let process_uid = unsafe { libc::geteuid() };
+        if existing.uid() != process_uid && process_uid != 0 {
+            // Only root can give the replacement someone else's ownership, so it's written in
+            // place instead
+            warn!(
+                "Writing {:?} in place since it's owned by another user, so it's left truncated if writing it fails",
+                path
+            );
+            fs::write(&path, contents).context("write to file")?;
+            if let Some(permissions) = permissions {
+                fs::set_permissions(&path, permissions).context("set permissions")?;
+            }
+            return Ok(());
+        }
+    }
+
+    let permissions = permissions.or_else(|| existing.as_ref().map(|m| m.permissions()));
+    let temporary = temporary_path(&path)?;
+    let result = (|| {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // So that the contents are never more accessible than the file they end up in. The
+        // umask can only take permissions away, so they're set exactly once written
+        #[cfg(unix)]
+        if let Some(permissions) = &permissions {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(permissions.mode() & 0o7777);
+        }
+        let mut file = options.open(&temporary).context("create temporary file")?;
+        file.write_all(contents)
+            .context("write to temporary file")?;
+
+        if let Some(permissions) = permissions {
+            file.set_permissions(permissions)
+                .context("set permissions of temporary file")?;
+        }
+        #[cfg(unix)]
+        if let Some(existing) = &existing {
+            use std::os::unix::fs::MetadataExt;
+            use std::os::unix::io::AsRawFd;
+            if unsafe { libc::fchown(file.as_raw_fd(), existing.uid(), existing.gid()) } != 0 {
+                debug!(
+                    "Failed to keep owner and group of {:?}: {}",
+                    path,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+
+        file.sync_all().context("sync temporary file to disk")?;
+        fs::rename(&temporary, &path).context("rename temporary file over file")
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result?;
+
+    sync_parent(&path);
+    Ok(())
+}
+
+/// Like `fs::copy`, which gives the target the source's permissions, but atomic
+fn copy_atomically(source: &Path, target: &Path) -> Result<()> {
+    let contents = fs::read(source).context("read source file")?;
+    let permissions = source
+        .metadata()
+        .context("get source metadata")?
+        .permissions();
+    write_atomically(target, &contents, Some(permissions))
+}
+
+/// A path next to the file (so it can be renamed over it) that's hidden and unlikely to clash
+fn temporary_path(path: &Path) -> Result<PathBuf> {
+    let name = path.file_name().context("get file name")?;
+    let mut temporary = std::ffi::OsString::from(".");
+    temporary.push(name);
+    temporary.push(format!(".dotter-{}.tmp", std::process::id()));
+    Ok(path.with_file_name(temporary))
+}
+
+/// Makes a rename in the file's directory durable. Best-effort, since it only matters on crashes
+fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        if let Err(e) = File::open(parent).and_then(|directory| directory.sync_all()) {
+            debug!("Failed to sync directory {:?}: {}", parent, e);
+        }
+    }
+    #[cfg(windows)]
+    let _ = path;
 }
 
 // === Mockable filesystem ===
@@ -46,35 +174,83 @@ pub trait Filesystem {
     /// Check state of expected symlink on disk
     fn compare_symlink(&mut self, source: &Path, link: &Path) -> Result<SymlinkComparison>;
 
-    /// Check state of expected symbolic link on disk
-    fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison>;
+    /// Check state of a templated or copied file on disk against the hash of the contents it was
+    /// last deployed with (if any). If its size and modification time still match `stamp`,
+    /// it's assumed to be unchanged without reading it
+    // The lifetime can't be elided for mockall
+    #[allow(clippy::needless_lifetimes)]
+    fn compare_contents<'a>(
+        &mut self,
+        target: &Path,
+        hash: Option<&'a str>,
+        stamp: Option<FileStamp>,
+    ) -> Result<TemplateComparison>;
+
+    /// Check state of expected hard link on disk
+    fn compare_hardlink(&mut self, source: &Path, link: &Path) -> Result<HardlinkComparison>;
+
+    /// Size and modification time of a regular file, if they can be read
+    fn stamp(&mut self, path: &Path) -> Option<FileStamp>;
+
+    /// Whether anything, even a broken symlink, is at the path
+    fn exists(&mut self, path: &Path) -> bool;
+
+    /// Permission bits of the file (following symlinks), if it exists and has any
+    fn mode(&mut self, path: &Path) -> Option<u32>;
 
     /// Removes a file or folder, elevating privileges if needed
     fn remove_file(&mut self, path: &Path) -> Result<()>;
 
-    /// Read contents of file into a string
-    fn read_to_string(&mut self, path: &Path) -> Result<String>;
+    /// Read contents of file
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>>;
 
-    /// Write string to file, without elevating privileges
-    fn write(&mut self, path: &Path, content: String) -> Result<()>;
+    /// Write contents to file, without elevating privileges
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()>;
 
     /// Delete parents of target file if they're empty
     fn delete_parents(&mut self, path: &Path, no_ask: bool) -> Result<()>;
 
-    /// Makes a symlink owned by the selected user, elevating privileges as needed
-    fn make_symlink(&mut self, link: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()>;
+    /// Makes a symlink owned by the selected user and group, elevating privileges as needed
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()>;
+
+    /// Makes a hard link to a file, without elevating privileges.
+    /// Fails if they are on different filesystems
+    fn make_hardlink(&mut self, link: &Path, target: &Path) -> Result<()>;
 
-    /// Create directory (and its parents) owned by the selected user,
+    /// Create directory (and its parents) owned by the selected user and group,
     /// elevating privileges as needed
-    fn create_dir_all(&mut self, path: &Path, owner: &Option<UnixUser>) -> Result<()>;
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()>;
 
     /// Copy readable file to target existing location.
-    /// Target file will be owned by the selected user. Privileges elevated as needed.
-    fn copy_file(&mut self, source: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()>;
+    /// Target file will be owned by the selected user and group. Privileges elevated as needed.
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()>;
 
     /// If owner.is_some, elevates privileges and sets file to that owner
     /// If owner.is_none, ensures file is owned by the current user (elevating privileges if needed)
-    fn set_owner(&mut self, file: &Path, owner: &Option<UnixUser>) -> Result<()>;
+    /// If group.is_some, also sets the file's group (elevating privileges if needed)
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()>;
 
     /// Copy file mode, elevating privileges as needed. (Does not change owner)
     fn copy_permissions(
@@ -83,6 +259,12 @@ pub trait Filesystem {
         target: &Path,
         owner: &Option<UnixUser>,
     ) -> Result<()>;
+
+    /// Set file mode, elevating privileges as needed. (Does not change owner)
+    fn set_mode(&mut self, path: &Path, mode: u32, owner: &Option<UnixUser>) -> Result<()>;
+
+    /// Move a file or folder to a location whose parent exists, elevating privileges as needed
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()>;
 }
 
 // == Windows Filesystem ==
@@ -90,12 +272,23 @@ pub trait Filesystem {
 #[cfg(windows)]
 pub struct RealFilesystem {
     noconfirm: bool,
+    root: Option<PathBuf>,
 }
 
 #[cfg(windows)]
 impl RealFilesystem {
-    pub fn new(noconfirm: bool) -> RealFilesystem {
-        RealFilesystem { noconfirm }
+    pub fn new(noconfirm: bool, _escalation: Escalation) -> RealFilesystem {
+        RealFilesystem {
+            noconfirm,
+            root: None,
+        }
+    }
+
+    /// Makes symlinks point to where their sources are inside `root`, which files are
+    /// being deployed into
+    pub fn with_root(mut self, root: Option<PathBuf>) -> RealFilesystem {
+        self.root = root;
+        self
     }
 }
 
@@ -107,16 +300,34 @@ impl Filesystem for RealFilesystem {
         let link_state = get_file_state(link).context("get link state")?;
         trace!("Link state: {:#?}", link_state);
 
-        compare_symlink(source, source_state, link_state)
+        compare_symlink(source_state, link_state, || {
+            link_destination(source, self.root.as_deref())
+        })
+    }
+
+    fn compare_contents(
+        &mut self,
+        target: &Path,
+        hash: Option<&str>,
+        stamp: Option<FileStamp>,
+    ) -> Result<TemplateComparison> {
+        compare_contents(target, hash, stamp)
+    }
+
+    fn compare_hardlink(&mut self, source: &Path, link: &Path) -> Result<HardlinkComparison> {
+        compare_hardlink(source, link)
     }
 
-    fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
-        let target_state = get_file_state(target).context("get state of target")?;
-        trace!("Target state: {:#?}", target_state);
-        let cache_state = get_file_state(cache).context("get state of cache")?;
-        trace!("Cache state: {:#?}", cache_state);
+    fn stamp(&mut self, path: &Path) -> Option<FileStamp> {
+        stamp(path)
+    }
+
+    fn exists(&mut self, path: &Path) -> bool {
+        path.symlink_metadata().is_ok()
+    }
 
-        Ok(compare_template(target_state, cache_state))
+    fn mode(&mut self, path: &Path) -> Option<u32> {
+        mode_of(path).ok()
     }
 
     fn remove_file(&mut self, path: &Path) -> Result<()> {
@@ -128,12 +339,12 @@ impl Filesystem for RealFilesystem {
         }
     }
 
-    fn read_to_string(&mut self, path: &Path) -> Result<String> {
-        fs::read_to_string(path).context("read from file")
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>> {
+        fs::read(path).context("read from file")
     }
 
-    fn write(&mut self, path: &Path, content: String) -> Result<()> {
-        fs::write(path, content).context("write to file")
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        write_atomically(path, &content, None).context("write to file")
     }
 
     fn delete_parents(&mut self, path: &Path, no_ask: bool) -> Result<()> {
@@ -158,48 +369,74 @@ impl Filesystem for RealFilesystem {
         Ok(())
     }
 
-    fn make_symlink(&mut self, link: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         use std::os::windows::fs;
 
-        if let Some(owner) = owner {
+        if owner.is_some() || group.is_some() {
             warn!(
-                "Ignoring `owner`={:?} when creating symlink {:?} -> {:?}",
-                owner, link, target
+                "Ignoring `owner`={:?} and `group`={:?} when creating symlink {:?} -> {:?}",
+                owner, group, link, target
             );
         }
         let real_source_path = real_path(target).context("get real path of source file")?;
+        let destination = link_destination(target, self.root.as_deref())?;
         if real_source_path.is_dir() {
-            fs::symlink_dir(real_source_path, link)
+            fs::symlink_dir(destination, link)
         } else {
-            fs::symlink_file(real_source_path, link)
+            fs::symlink_file(destination, link)
         }
         .context("create symlink")
     }
 
-    fn create_dir_all(&mut self, path: &Path, owner: &Option<UnixUser>) -> Result<()> {
-        if let Some(owner) = owner {
+    fn make_hardlink(&mut self, link: &Path, target: &Path) -> Result<()> {
+        hard_link(link, target)
+    }
+
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        if owner.is_some() || group.is_some() {
             warn!(
-                "Ignoring `owner`={:?} when creating directory {:?}",
-                owner, path
+                "Ignoring `owner`={:?} and `group`={:?} when creating directory {:?}",
+                owner, group, path
             );
         }
         std::fs::create_dir_all(path).context("create directories")
     }
 
-    fn copy_file(&mut self, source: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
-        if let Some(owner) = owner {
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        if owner.is_some() || group.is_some() {
             warn!(
-                "Ignoring `owner`={:?} when copying {:?} -> {:?}",
-                owner, source, target
+                "Ignoring `owner`={:?} and `group`={:?} when copying {:?} -> {:?}",
+                owner, group, source, target
             );
         }
-        std::fs::copy(source, target).context("copy file")?;
-        Ok(())
+        copy_atomically(source, target).context("copy file")
     }
 
-    fn set_owner(&mut self, file: &Path, owner: &Option<UnixUser>) -> Result<()> {
-        if owner.is_some() {
-            warn!("ignoring `owner` field on file {:?}", file);
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        if owner.is_some() || group.is_some() {
+            warn!("ignoring `owner` and `group` fields on file {:?}", file);
         }
         Ok(())
     }
@@ -225,6 +462,15 @@ impl Filesystem for RealFilesystem {
         )
         .context("set target permissions")
     }
+
+    fn set_mode(&mut self, path: &Path, mode: u32, _owner: &Option<UnixUser>) -> Result<()> {
+        warn!("Ignoring `mode`={:04o} on file {:?}", mode, path);
+        Ok(())
+    }
+
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()> {
+        std::fs::rename(source, target).context("rename file")
+    }
 }
 
 // == Unix Filesystem ==
@@ -232,29 +478,108 @@ impl Filesystem for RealFilesystem {
 #[cfg(unix)]
 pub struct RealFilesystem {
     noconfirm: bool,
-    sudo_occurred: bool,
+    escalation: Escalation,
+    root: Option<PathBuf>,
+    elevated_before: bool,
 }
 
 #[cfg(unix)]
 impl RealFilesystem {
-    pub fn new(noconfirm: bool) -> RealFilesystem {
+    pub fn new(noconfirm: bool, escalation: Escalation) -> RealFilesystem {
         RealFilesystem {
-            sudo_occurred: false,
+            elevated_before: false,
             noconfirm,
+            escalation,
+            root: None,
         }
     }
 
-    fn sudo(&mut self, goal: impl AsRef<str>) -> Command {
-        if !self.sudo_occurred {
+    /// Makes symlinks point to where their sources are inside `root`, which files are
+    /// being deployed into
+    pub fn with_root(mut self, root: Option<PathBuf>) -> RealFilesystem {
+        self.root = root;
+        self
+    }
+
+    /// Starts a command that runs the rest of its arguments with elevated permissions,
+    /// as `user` or root. Fails if escalation is disabled
+    fn elevate(&mut self, goal: impl AsRef<str>, user: Option<&UnixUser>) -> Result<Command> {
+        anyhow::ensure!(
+            self.escalation != Escalation::Never,
+            Failure::new(
+                ExitStatus::Permission,
+                format!(
+                    "elevated permissions are needed ({}), but escalation is disabled",
+                    goal.as_ref()
+                )
+            )
+        );
+
+        if !self.elevated_before {
             warn!("Elevating permissions ({})", goal.as_ref());
             if !log_enabled!(log::Level::Debug) {
                 warn!("To see more than the first time elevated permissions are used, use verbosity 2 or more (-vv)");
             }
-            self.sudo_occurred = true;
+            self.elevated_before = true;
         } else {
             debug!("Elevating permissions ({})", goal.as_ref());
         }
-        Command::new("sudo")
+
+        let mut command = match self.escalation {
+            Escalation::Sudo => Command::new("sudo"),
+            Escalation::Doas => Command::new("doas"),
+            Escalation::Run0 => Command::new("run0"),
+            Escalation::Pkexec => Command::new("pkexec"),
+            Escalation::Never => unreachable!(),
+        };
+        if let Some(user) = user {
+            match self.escalation {
+                Escalation::Sudo => command.arg("-u").arg(user.as_sudo_arg()),
+                Escalation::Doas => command.arg("-u").arg(user_name(user)),
+                Escalation::Run0 => command.arg(format!("--user={}", user.as_chown_arg())),
+                Escalation::Pkexec => command.arg("--user").arg(user_name(user)),
+                Escalation::Never => unreachable!(),
+            };
+        }
+        Ok(command)
+    }
+
+    /// Sets the group of a file (not following symlinks). Only elevates privileges if the
+    /// current user can't do it, which is when it doesn't own the file or isn't in the group
+    fn set_group(&mut self, path: &Path, group: &UnixGroup) -> Result<()> {
+        debug!("Setting group of {:?} to {:?} as current user", path, group);
+        let success = Command::new("chgrp")
+            .arg("-h") // no-dereference
+            .arg(group.as_chown_arg())
+            .arg(path)
+            .stderr(std::process::Stdio::null())
+            .status()
+            .context("run chgrp command")?
+            .success();
+        if success {
+            return Ok(());
+        }
+
+        let success = self
+            .elevate(
+                format!("Setting group of {:?} to {:?}...", path, group),
+                None,
+            )?
+            .arg("chgrp")
+            .arg("-h")
+            .arg(group.as_chown_arg())
+            .arg(path)
+            .spawn()
+            .context("spawn elevated chgrp command")?
+            .wait()
+            .context("wait for elevated chgrp command")?
+            .success();
+
+        anyhow::ensure!(
+            success,
+            Failure::new(ExitStatus::Permission, "elevated chgrp command failed")
+        );
+        Ok(())
     }
 
     fn is_owned_by_user(&self, path: &Path) -> Result<bool> {
@@ -271,14 +596,34 @@ impl Filesystem for RealFilesystem {
         let source_state = get_file_state(source).context("get source state")?;
         let link_state = get_file_state(link).context("get link state")?;
 
-        compare_symlink(source, source_state, link_state)
+        compare_symlink(source_state, link_state, || {
+            link_destination(source, self.root.as_deref())
+        })
+    }
+
+    fn compare_contents(
+        &mut self,
+        target: &Path,
+        hash: Option<&str>,
+        stamp: Option<FileStamp>,
+    ) -> Result<TemplateComparison> {
+        compare_contents(target, hash, stamp)
+    }
+
+    fn compare_hardlink(&mut self, source: &Path, link: &Path) -> Result<HardlinkComparison> {
+        compare_hardlink(source, link)
+    }
+
+    fn stamp(&mut self, path: &Path) -> Option<FileStamp> {
+        stamp(path)
     }
 
-    fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
-        let target_state = get_file_state(target).context("get state of target")?;
-        let cache_state = get_file_state(cache).context("get state of cache")?;
+    fn exists(&mut self, path: &Path) -> bool {
+        path.symlink_metadata().is_ok()
+    }
 
-        Ok(compare_template(target_state, cache_state))
+    fn mode(&mut self, path: &Path) -> Option<u32> {
+        mode_of(path).ok()
     }
 
     fn remove_file(&mut self, path: &Path) -> Result<()> {
@@ -292,29 +637,32 @@ impl Filesystem for RealFilesystem {
             Ok(()) => Ok(()),
             Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
                 let success = self
-                    .sudo(format!("removing file {:?} as root", path))
+                    .elevate(format!("removing file {:?} as root", path), None)?
                     .arg("rm")
                     .arg("-r")
                     .arg(path)
                     .spawn()
-                    .context("spawn sudo rm command")?
+                    .context("spawn elevated rm command")?
                     .wait()
-                    .context("wait for sudo rm command")?
+                    .context("wait for elevated rm command")?
                     .success();
 
-                anyhow::ensure!(success, "sudo rm command failed");
+                anyhow::ensure!(
+                    success,
+                    Failure::new(ExitStatus::Permission, "elevated rm command failed")
+                );
                 Ok(())
             }
             Err(e) => Err(e).context("remove file"),
         }
     }
 
-    fn read_to_string(&mut self, path: &Path) -> Result<String> {
-        fs::read_to_string(path).context("read from file")
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>> {
+        fs::read(path).context("read from file")
     }
 
-    fn write(&mut self, path: &Path, content: String) -> Result<()> {
-        fs::write(path, content).context("write to file")
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        write_atomically(path, &content, None).context("write to file")
     }
 
     fn delete_parents(&mut self, path: &Path, no_ask: bool) -> Result<()> {
@@ -336,16 +684,19 @@ impl Filesystem for RealFilesystem {
                     Ok(()) => {}
                     Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
                         let success = self
-                            .sudo(format!("removing directory {:?}", path))
+                            .elevate(format!("removing directory {:?}", path), None)?
                             .arg("rmdir")
                             .arg(path)
                             .spawn()
-                            .context("spawn sudo rmdir")?
+                            .context("spawn elevated rmdir")?
                             .wait()
-                            .context("wait for sudo rmdir")?
+                            .context("wait for elevated rmdir")?
                             .success();
 
-                        anyhow::ensure!(success, "sudo rmdir failed");
+                        anyhow::ensure!(
+                            success,
+                            Failure::new(ExitStatus::Permission, "elevated rmdir failed")
+                        );
                     }
                     Err(e) => {
                         Err(e).context("remove dir")?;
@@ -357,87 +708,131 @@ impl Filesystem for RealFilesystem {
         Ok(())
     }
 
-    fn make_symlink(&mut self, link: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         use std::os::unix::fs;
 
         if let Some(owner) = owner {
             let success = self
-                .sudo(format!(
-                    "creating symlink {:?} -> {:?} from user {:?}",
-                    link, target, owner
-                ))
-                .arg("-u")
-                .arg(owner.as_sudo_arg())
+                .elevate(
+                    format!(
+                        "creating symlink {:?} -> {:?} from user {:?}",
+                        link, target, owner
+                    ),
+                    Some(owner),
+                )?
                 .arg("ln")
                 .arg("-s")
-                .arg(real_path(target).context("get real path of source file")?)
+                .arg(link_destination(target, self.root.as_deref())?)
                 .arg(link)
                 .spawn()
-                .context("spawn sudo ln")?
+                .context("spawn elevated ln")?
                 .wait()
-                .context("wait for sudo ln")?
+                .context("wait for elevated ln")?
                 .success();
 
-            anyhow::ensure!(success, "sudo ln failed");
+            anyhow::ensure!(
+                success,
+                Failure::new(ExitStatus::Permission, "elevated ln failed")
+            );
         } else {
             debug!(
                 "Creating symlink {:?} -> {:?} as current user...",
                 link, target
             );
-            fs::symlink(
-                real_path(target).context("get real path of source file")?,
-                link,
-            )
-            .context("create symlink")?;
+            fs::symlink(link_destination(target, self.root.as_deref())?, link)
+                .context("create symlink")?;
+        }
+
+        if let Some(group) = group {
+            self.set_group(link, group)
+                .context("set group of symlink")?;
         }
         Ok(())
     }
 
-    fn create_dir_all(&mut self, path: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn make_hardlink(&mut self, link: &Path, target: &Path) -> Result<()> {
+        hard_link(link, target)
+    }
+
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        let missing = path
+            .ancestors()
+            .take_while(|p| !p.as_os_str().is_empty() && p.symlink_metadata().is_err())
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+
         if let Some(owner) = owner {
             let success = self
-                .sudo(format!(
-                    "Creating directory {:?} from user {:?}...",
-                    path, owner
-                ))
-                .arg("-u")
-                .arg(owner.as_sudo_arg())
+                .elevate(
+                    format!("Creating directory {:?} from user {:?}...", path, owner),
+                    Some(owner),
+                )?
                 .arg("mkdir")
                 .arg("-p")
                 .arg(path)
                 .spawn()
-                .context("spawn sudo mkdir")?
+                .context("spawn elevated mkdir")?
                 .wait()
-                .context("wait for sudo mkdir")?
+                .context("wait for elevated mkdir")?
                 .success();
 
-            anyhow::ensure!(success, "sudo mkdir failed");
+            anyhow::ensure!(
+                success,
+                Failure::new(ExitStatus::Permission, "elevated mkdir failed")
+            );
         } else {
             debug!("Creating directory {:?} as current user...", path);
             std::fs::create_dir_all(path).context("create directories")?;
         }
+
+        if let Some(group) = group {
+            for dir in missing {
+                self.set_group(&dir, group)
+                    .context("set group of directory")?;
+            }
+        }
         Ok(())
     }
 
-    fn copy_file(&mut self, source: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         use std::io::Write;
 
         if let Some(owner) = owner {
-            let contents = std::fs::read_to_string(source)
-                .context("read source file contents as current user")?;
+            let contents =
+                std::fs::read(source).context("read source file contents as current user")?;
+            // Written next to the target and moved over it, so it's never left truncated.
+            // Only its owner can read it until it's given its mode
+            let temporary = temporary_path(target)?;
             let mut child = self
-                .sudo(format!(
-                    "Copying {:?} -> {:?} as user {:?}",
-                    source, target, owner
-                ))
-                .arg("-u")
-                .arg(owner.as_sudo_arg())
-                .arg("tee")
-                .arg(target)
+                .elevate(
+                    format!("Copying {:?} -> {:?} as user {:?}", source, target, owner),
+                    Some(owner),
+                )?
+                .arg("sh")
+                .arg("-c")
+                .arg("umask 077 && exec tee -- \"$0\"")
+                .arg(&temporary)
                 .stdin(std::process::Stdio::piped())
                 .stdout(std::process::Stdio::null())
                 .spawn()
-                .context("spawn sudo tee")?;
+                .context("spawn elevated tee")?;
 
             // At this point we should've gone through another sudo at the mkdir step already,
             // so sudo will not ask for the password
@@ -445,21 +840,90 @@ impl Filesystem for RealFilesystem {
                 .stdin
                 .as_ref()
                 .expect("has stdin")
-                .write_all(contents.as_bytes())
+                .write_all(&contents)
                 .context("give input to tee")?;
 
-            let success = child.wait().context("wait for sudo tee")?.success();
+            let success = child.wait().context("wait for elevated tee")?.success();
+
+            anyhow::ensure!(
+                success,
+                Failure::new(ExitStatus::Permission, "elevated tee failed")
+            );
+
+            // Syncing doesn't need write access, so this works unless the file isn't readable
+            if let Err(e) = File::open(&temporary).and_then(|file| file.sync_all()) {
+                debug!("Failed to sync {:?} to disk: {}", temporary, e);
+            }
+
+            // The mode of the file it replaces, or else the source's like `fs::copy` gives
+            let mode = match target.metadata() {
+                Ok(_) => mode_of(target),
+                Err(_) => mode_of(source),
+            }?;
+            let mut success = self
+                .elevate(
+                    format!("Setting mode of {:?} as user {:?}", temporary, owner),
+                    Some(owner),
+                )?
+                .arg("chmod")
+                .arg(format!("{:o}", mode))
+                .arg(&temporary)
+                .spawn()
+                .context("spawn elevated chmod command")?
+                .wait()
+                .context("wait for elevated chmod command")?
+                .success();
+            if success {
+                success = self
+                    .elevate(
+                        format!("Moving {:?} -> {:?} as user {:?}", temporary, target, owner),
+                        Some(owner),
+                    )?
+                    .arg("mv")
+                    .arg("-f")
+                    .arg(&temporary)
+                    .arg(target)
+                    .spawn()
+                    .context("spawn elevated mv command")?
+                    .wait()
+                    .context("wait for elevated mv command")?
+                    .success();
+            }
+            if !success {
+                if let Ok(mut command) = self.elevate(
+                    format!("Removing {:?} as user {:?}", temporary, owner),
+                    Some(owner),
+                ) {
+                    let _ = command.arg("rm").arg("-f").arg(&temporary).status();
+                }
+            }
 
-            anyhow::ensure!(success, "sudo tee failed");
+            anyhow::ensure!(
+                success,
+                Failure::new(ExitStatus::Permission, "elevated mv failed")
+            );
+            sync_parent(target);
         } else {
             debug!("Copying {:?} -> {:?} as current user", source, target);
-            std::fs::copy(source, target).context("copy file")?;
+            copy_atomically(source, target).context("copy file")?;
         }
 
+        if let Some(group) = group {
+            self.set_group(target, group).context("set group of file")?;
+        }
         Ok(())
     }
 
-    fn set_owner(&mut self, file: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        if let Some(group) = group {
+            self.set_group(file, group).context("set group of file")?;
+        }
+
         if self
             .is_owned_by_user(file)
             .context("detect if file is owned by the current user")?
@@ -474,18 +938,24 @@ impl Filesystem for RealFilesystem {
         ));
 
         let success = self
-            .sudo(format!("Setting owner of {:?} to {:?}...", file, owner))
+            .elevate(
+                format!("Setting owner of {:?} to {:?}...", file, owner),
+                None,
+            )?
             .arg("chown")
-            .arg(owner.as_chown_arg())
             .arg("-h") // no-dereference
+            .arg(owner.as_chown_arg())
             .arg(file)
             .spawn()
-            .context("spawn sudo chown command")?
+            .context("spawn elevated chown command")?
             .wait()
-            .context("wait for sudo chown command")?
+            .context("wait for elevated chown command")?
             .success();
 
-        anyhow::ensure!(success, "sudo chown command failed");
+        anyhow::ensure!(
+            success,
+            Failure::new(ExitStatus::Permission, "elevated chown command failed")
+        );
         Ok(())
     }
 
@@ -497,21 +967,26 @@ impl Filesystem for RealFilesystem {
     ) -> Result<()> {
         if let Some(owner) = owner {
             let success = self
-                .sudo(format!(
-                    "Copying permissions {:?} -> {:?} as user {:?}",
-                    source, target, owner
-                ))
+                .elevate(
+                    format!(
+                        "Copying permissions {:?} -> {:?} as user {:?}",
+                        source, target, owner
+                    ),
+                    None,
+                )?
                 .arg("chmod")
-                .arg("--reference")
-                .arg(source)
+                .arg(format!("{:o}", mode_of(source)?))
                 .arg(target)
                 .spawn()
-                .context("spawn sudo chmod command")?
+                .context("spawn elevated chmod command")?
                 .wait()
-                .context("wait for sudo chmod command")?
+                .context("wait for elevated chmod command")?
                 .success();
 
-            anyhow::ensure!(success, "sudo chmod failed");
+            anyhow::ensure!(
+                success,
+                Failure::new(ExitStatus::Permission, "elevated chmod failed")
+            );
         } else {
             debug!(
                 "Copying permissions {:?} -> {:?} as current user",
@@ -528,29 +1003,129 @@ impl Filesystem for RealFilesystem {
         }
         Ok(())
     }
+
+    fn set_mode(&mut self, path: &Path, mode: u32, owner: &Option<UnixUser>) -> Result<()> {
+        if let Some(owner) = owner {
+            let success = self
+                .elevate(
+                    format!(
+                        "Setting mode of {:?} to {:04o} as user {:?}",
+                        path, mode, owner
+                    ),
+                    None,
+                )?
+                .arg("chmod")
+                .arg(format!("{:o}", mode))
+                .arg(path)
+                .spawn()
+                .context("spawn elevated chmod command")?
+                .wait()
+                .context("wait for elevated chmod command")?
+                .success();
+
+            anyhow::ensure!(
+                success,
+                Failure::new(ExitStatus::Permission, "elevated chmod failed")
+            );
+        } else {
+            use std::os::unix::fs::PermissionsExt;
+            debug!("Setting mode of {:?} to {:04o} as current user", path, mode);
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .context("set permissions")?;
+        }
+        Ok(())
+    }
+
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()> {
+        match std::fs::rename(source, target) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                let success = self
+                    .elevate(format!("moving {:?} -> {:?} as root", source, target), None)?
+                    .arg("mv")
+                    .arg(source)
+                    .arg(target)
+                    .spawn()
+                    .context("spawn elevated mv command")?
+                    .wait()
+                    .context("wait for elevated mv command")?
+                    .success();
+
+                anyhow::ensure!(
+                    success,
+                    Failure::new(ExitStatus::Permission, "elevated mv command failed")
+                );
+                Ok(())
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                debug!(
+                    "Moving {:?} -> {:?} across filesystems as current user",
+                    source, target
+                );
+                let success = Command::new("mv")
+                    .arg(source)
+                    .arg(target)
+                    .spawn()
+                    .context("spawn mv command")?
+                    .wait()
+                    .context("wait for mv command")?
+                    .success();
+
+                anyhow::ensure!(success, "mv command failed");
+                Ok(())
+            }
+            Err(e) => Err(e).context("rename file"),
+        }
+    }
 }
 
 // == Dry run Filesystem ==
 pub struct DryRunFilesystem {
     file_states: BTreeMap<PathBuf, FileState>,
+    /// Sources of the hard links made so far, by link
+    hardlinks: BTreeMap<PathBuf, PathBuf>,
+    root: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone, PartialEq)]
 enum FileState {
-    /// None if file is invalid UTF-8
-    File(Option<String>),
+    File(Vec<u8>),
     SymbolicLink(PathBuf),
     Directory,
     Missing,
 }
 
+// Files are shown by their hash, as they may be large or binary
+impl std::fmt::Debug for FileState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            FileState::File(contents) => f
+                .debug_tuple("File")
+                .field(&hash_contents(contents))
+                .finish(),
+            FileState::SymbolicLink(target) => f.debug_tuple("SymbolicLink").field(target).finish(),
+            FileState::Directory => f.write_str("Directory"),
+            FileState::Missing => f.write_str("Missing"),
+        }
+    }
+}
+
 impl DryRunFilesystem {
     pub fn new() -> DryRunFilesystem {
         DryRunFilesystem {
             file_states: BTreeMap::new(),
+            hardlinks: BTreeMap::new(),
+            root: None,
         }
     }
 
+    /// Expects symlinks to point to where their sources are inside `root`, which files are
+    /// being deployed into
+    pub fn with_root(mut self, root: Option<PathBuf>) -> DryRunFilesystem {
+        self.root = root;
+        self
+    }
+
     fn get_state(&mut self, path: &Path) -> Result<FileState> {
         match self.file_states.get(path) {
             Some(state) => Ok(state.clone()),
@@ -578,48 +1153,95 @@ impl Filesystem for DryRunFilesystem {
             state
         };
 
-        compare_symlink(source, source_state, link_state)
+        compare_symlink(source_state, link_state, || {
+            link_destination(source, self.root.as_deref())
+        })
     }
 
-    fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
-        let target_state = if let Some(state) = self.file_states.get(target) {
-            debug!("Cached (probably not actual) target state: {:?}", state);
-            state.clone()
-        } else {
-            let state = get_file_state(target).context("get state of target")?;
-            debug!("Target state: {:?}", state);
-            state
-        };
-        let cache_state = if let Some(state) = self.file_states.get(cache) {
-            debug!("Cached (probably not actual) cache state: {:?}", state);
-            state.clone()
-        } else {
-            let state = get_file_state(cache).context("get state of cache")?;
-            debug!("Cache state: {:?}", state);
-            state
-        };
+    fn compare_contents(
+        &mut self,
+        target: &Path,
+        hash: Option<&str>,
+        stamp: Option<FileStamp>,
+    ) -> Result<TemplateComparison> {
+        match self.file_states.get(target) {
+            Some(FileState::Missing) => Ok(compare_hashes(None, hash)),
+            Some(FileState::File(contents)) => {
+                Ok(compare_hashes(Some(&hash_contents(contents)), hash))
+            }
+            Some(FileState::SymbolicLink(_)) | Some(FileState::Directory) => {
+                Ok(TemplateComparison::TargetNotRegularFile)
+            }
+            None => compare_contents(target, hash, stamp),
+        }
+    }
+
+    fn compare_hardlink(&mut self, source: &Path, link: &Path) -> Result<HardlinkComparison> {
+        let source_missing =
+            self.get_state(source).context("get source state")? == FileState::Missing;
+        Ok(match self.file_states.get(link) {
+            Some(FileState::Missing) if source_missing => HardlinkComparison::BothMissing,
+            Some(FileState::Missing) => HardlinkComparison::OnlySourceExists,
+            Some(FileState::SymbolicLink(_)) | Some(FileState::Directory) => {
+                HardlinkComparison::TargetNotRegularFile
+            }
+            Some(FileState::File(_)) if source_missing => HardlinkComparison::OnlyTargetExists,
+            Some(FileState::File(_))
+                if self.hardlinks.get(link).map(PathBuf::as_path) == Some(source) =>
+            {
+                HardlinkComparison::Identical
+            }
+            Some(FileState::File(_)) => HardlinkComparison::Changed,
+            None => compare_hardlink(source, link)?,
+        })
+    }
+
+    fn stamp(&mut self, path: &Path) -> Option<FileStamp> {
+        match self.file_states.get(path) {
+            // Only known from simulated changes, which have no size or modification time
+            Some(_) => None,
+            None => stamp(path),
+        }
+    }
+
+    fn exists(&mut self, path: &Path) -> bool {
+        match self.file_states.get(path) {
+            Some(state) => *state != FileState::Missing,
+            None => path.symlink_metadata().is_ok(),
+        }
+    }
 
-        Ok(compare_template(target_state, cache_state))
+    fn mode(&mut self, path: &Path) -> Option<u32> {
+        match self.file_states.get(path) {
+            // Simulated changes don't keep track of modes
+            Some(_) => None,
+            None => mode_of(path).ok(),
+        }
     }
 
     fn remove_file(&mut self, path: &Path) -> Result<()> {
         debug!("Removing file {:?}", path);
         self.file_states.insert(path.into(), FileState::Missing);
+        self.hardlinks.remove(path);
         Ok(())
     }
 
-    fn read_to_string(&mut self, path: &Path) -> Result<String> {
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>> {
         debug!("Reading contents of file {:?}", path);
         match self.get_state(path).context("get file state")? {
-            FileState::File(s) => Ok(s.context("invalid utf-8 in template source")?),
-            _ => anyhow::bail!("writing to non-file"),
+            FileState::File(contents) => Ok(contents),
+            _ => anyhow::bail!("reading from non-file"),
         }
     }
 
-    fn write(&mut self, path: &Path, content: String) -> Result<()> {
-        debug!("Writing contents {:?} to file {:?}", content, path);
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        debug!(
+            "Writing contents {:?} to file {:?}",
+            String::from_utf8_lossy(&content),
+            path
+        );
         self.file_states
-            .insert(path.into(), FileState::File(Some(content)));
+            .insert(path.into(), FileState::File(content));
         Ok(())
     }
 
@@ -631,18 +1253,44 @@ impl Filesystem for DryRunFilesystem {
         Ok(())
     }
 
-    fn make_symlink(&mut self, link: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         debug!(
-            "Making symlink {:?} -> {:?} (owned by {:?})",
-            link, target, owner
+            "Making symlink {:?} -> {:?} (owned by {:?}, group {:?})",
+            link, target, owner, group
         );
         self.file_states
             .insert(link.into(), FileState::SymbolicLink(target.into()));
         Ok(())
     }
 
-    fn create_dir_all(&mut self, mut path: &Path, owner: &Option<UnixUser>) -> Result<()> {
-        debug!("Creating directory {:?} (owned by {:?})", path, owner);
+    fn make_hardlink(&mut self, link: &Path, target: &Path) -> Result<()> {
+        debug!("Making hard link {:?} -> {:?}", link, target);
+        match self.get_state(target).context("get state of source file")? {
+            state @ FileState::File(_) => {
+                self.file_states.insert(link.into(), state);
+                self.hardlinks.insert(link.into(), target.into());
+                Ok(())
+            }
+            s => anyhow::bail!("file is not regular file but is a {:?}", s),
+        }
+    }
+
+    fn create_dir_all(
+        &mut self,
+        mut path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        debug!(
+            "Creating directory {:?} (owned by {:?}, group {:?})",
+            path, owner, group
+        );
         self.file_states.insert(path.into(), FileState::Directory);
         while path.parent().is_some() {
             path = path.parent().unwrap();
@@ -651,10 +1299,16 @@ impl Filesystem for DryRunFilesystem {
         Ok(())
     }
 
-    fn copy_file(&mut self, source: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         debug!(
-            "Copying file {:?} -> {:?} (target owned by {:?})",
-            source, target, owner
+            "Copying file {:?} -> {:?} (target owned by {:?}, group {:?})",
+            source, target, owner, group
         );
         match self.get_state(source).context("get state of source file")? {
             FileState::File(content) => {
@@ -676,8 +1330,584 @@ impl Filesystem for DryRunFilesystem {
         }
     }
 
-    fn set_owner(&mut self, file: &Path, owner: &Option<UnixUser>) -> Result<()> {
-        debug!("Setting owner of file {:?} to {:?}", file, owner);
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        debug!(
+            "Setting owner of file {:?} to {:?} (group {:?})",
+            file, owner, group
+        );
+        Ok(())
+    }
+
+    fn copy_permissions(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+    ) -> Result<()> {
+        debug!(
+            "Copying permissions on files {:?} -> {:?} (target owned by {:?})",
+            source, target, owner
+        );
+        Ok(())
+    }
+
+    fn set_mode(&mut self, path: &Path, mode: u32, owner: &Option<UnixUser>) -> Result<()> {
+        debug!(
+            "Setting mode of {:?} to {:04o} (owned by {:?})",
+            path, mode, owner
+        );
+        Ok(())
+    }
+
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()> {
+        debug!("Moving {:?} -> {:?}", source, target);
+        let state = self.get_state(source).context("get state of source file")?;
+        anyhow::ensure!(state != FileState::Missing, "source file is missing");
+        self.file_states.insert(target.into(), state);
+        self.file_states.insert(source.into(), FileState::Missing);
+        self.hardlinks.remove(source);
+        self.hardlinks.remove(target);
+        Ok(())
+    }
+}
+
+// == In-memory Filesystem ==
+
+/// How many symlinks are followed before giving up, like Linux does
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Device number of the files created in memory, so they're never the same as one on disk
+const MEMORY_DEVICE: u64 = u64::MAX;
+
+/// A filesystem that only exists in memory, so deployments can be run end to end without
+/// touching the disk. Unlike `DryRunFilesystem` it keeps track of owners, groups and modes, and
+/// it's deterministic: new files belong to a fixed user and group instead of whoever runs Dotter,
+/// and their modification times come from a counter.
+/// If it reads through, paths it hasn't seen yet are looked up on disk (as files of that user),
+/// otherwise they're missing
+#[derive(Debug, Clone)]
+pub struct MemoryFilesystem {
+    /// By absolute path without symlinks in its parents. `None` is a removed path, which hides
+    /// whatever is on disk there
+    nodes: BTreeMap<PathBuf, Option<Node>>,
+    read_through: bool,
+    /// What relative paths are relative to
+    current_dir: PathBuf,
+    root: Option<PathBuf>,
+    user: UnixUser,
+    group: UnixGroup,
+    /// Stands in for modification times, increasing with every change
+    clock: i64,
+    next_inode: u64,
+}
+
+/// A file, symlink or directory in a `MemoryFilesystem`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    kind: FileState,
+    pub owner: UnixUser,
+    pub group: UnixGroup,
+    pub mode: u32,
+    modified: i64,
+    /// Device and inode number, which hard links share
+    identity: (u64, u64),
+    /// Whether it was created in memory, so that nothing on disk is below it
+    fresh: bool,
+}
+
+impl Node {
+    pub fn contents(&self) -> Option<&[u8]> {
+        match &self.kind {
+            FileState::File(contents) => Some(contents),
+            _ => None,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileState::Directory
+    }
+}
+
+impl MemoryFilesystem {
+    /// An empty filesystem, with relative paths in its root directory.
+    /// Files belong to user and group 1000 unless they're given an owner or group
+    pub fn new() -> MemoryFilesystem {
+        let mut fs = MemoryFilesystem {
+            nodes: BTreeMap::new(),
+            read_through: false,
+            current_dir: PathBuf::from("/"),
+            root: None,
+            user: UnixUser::Uid(1000),
+            group: UnixGroup::Gid(1000),
+            clock: 0,
+            next_inode: 0,
+        };
+        let root = fs.new_node(FileState::Directory, &None, &None, 0o755);
+        fs.nodes.insert(PathBuf::from("/"), Some(root));
+        fs
+    }
+
+    /// A filesystem that starts out like the disk, with relative paths in the current directory
+    pub fn read_through() -> Result<MemoryFilesystem> {
+        Ok(MemoryFilesystem {
+            nodes: BTreeMap::new(),
+            read_through: true,
+            current_dir: std::env::current_dir().context("get current directory")?,
+            ..MemoryFilesystem::new()
+        })
+    }
+
+    /// Expects symlinks to point to where their sources are inside `root`, which files are
+    /// being deployed into
+    pub fn with_root(mut self, root: Option<PathBuf>) -> MemoryFilesystem {
+        self.root = root;
+        self
+    }
+
+    /// What is at the path, without following it if it's a symlink
+    pub fn node(&mut self, path: &Path) -> Result<Option<Node>> {
+        let path = self.located(path)?;
+        self.get(&path)
+    }
+
+    fn new_node(
+        &mut self,
+        kind: FileState,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+        mode: u32,
+    ) -> Node {
+        self.clock += 1;
+        self.next_inode += 1;
+        Node {
+            kind,
+            owner: owner.clone().unwrap_or_else(|| self.user.clone()),
+            group: group.clone().unwrap_or_else(|| self.group.clone()),
+            mode,
+            modified: self.clock,
+            identity: (MEMORY_DEVICE, self.next_inode),
+            fresh: true,
+        }
+    }
+
+    /// Makes the path absolute and removes `.` and `..` from it, without following symlinks
+    fn absolute(&self, path: &Path) -> PathBuf {
+        normalize_path(&self.current_dir, path)
+    }
+
+    /// The path with the symlinks in its parents followed, so its node can be looked up
+    fn located(&mut self, path: &Path) -> Result<PathBuf> {
+        self.locate(path, &mut 0)
+            .with_context(|| format!("locate {:?}", path))
+    }
+
+    /// The path with all symlinks in it followed, like `fs::canonicalize` (but it may be missing)
+    fn resolved(&mut self, path: &Path) -> Result<PathBuf> {
+        self.resolve(path, &mut 0)
+            .with_context(|| format!("resolve {:?}", path))
+    }
+
+    fn locate(&mut self, path: &Path, hops: &mut usize) -> Result<PathBuf> {
+        let path = self.absolute(path);
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok(self.resolve(parent, hops)?.join(name)),
+            _ => Ok(path),
+        }
+    }
+
+    fn resolve(&mut self, path: &Path, hops: &mut usize) -> Result<PathBuf> {
+        let mut path = self.locate(path, hops)?;
+        while let Some(Node {
+            kind: FileState::SymbolicLink(destination),
+            ..
+        }) = self.get(&path)?
+        {
+            *hops += 1;
+            anyhow::ensure!(
+                *hops <= MAX_SYMLINK_HOPS,
+                "too many levels of symbolic links at {:?}",
+                path
+            );
+            let parent = path
+                .parent()
+                .context("get parent of symlink")?
+                .to_path_buf();
+            path = self.locate(&parent.join(destination), hops)?;
+        }
+        Ok(path)
+    }
+
+    /// The node at a located path, reading it from disk if it hasn't been seen yet
+    fn get(&mut self, path: &Path) -> Result<Option<Node>> {
+        if let Some(node) = self.nodes.get(path) {
+            return Ok(node.clone());
+        }
+        // The closest parent that has been seen decides whether the disk is looked at
+        for parent in path.ancestors().skip(1) {
+            match self.nodes.get(parent) {
+                None => continue,
+                Some(Some(node)) if node.is_dir() && !node.fresh => break,
+                Some(_) => return Ok(None),
+            }
+        }
+        if !self.read_through {
+            return Ok(None);
+        }
+
+        let node = self
+            .load(path)
+            .with_context(|| format!("read {:?} from disk", path))?;
+        self.nodes.insert(path.into(), node.clone());
+        Ok(node)
+    }
+
+    fn load(&mut self, path: &Path) -> Result<Option<Node>> {
+        let metadata = match optional_metadata(path.symlink_metadata()).context("get metadata")? {
+            Some(metadata) => metadata,
+            None => return Ok(None),
+        };
+        let kind = if metadata.file_type().is_symlink() {
+            FileState::SymbolicLink(fs::read_link(path).context("read symlink")?)
+        } else if metadata.is_dir() {
+            FileState::Directory
+        } else {
+            FileState::File(fs::read(path).context("read file")?)
+        };
+
+        let mut node = self.new_node(kind, &None, &None, 0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{MetadataExt, PermissionsExt};
+            node.mode = metadata.permissions().mode() & 0o7777;
+            node.identity = (metadata.dev(), metadata.ino());
+        }
+        #[cfg(windows)]
+        {
+            node.mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+        }
+        node.modified = FileStamp::of(&metadata).map_or(0, |stamp| stamp.mtime);
+        node.fresh = false;
+        Ok(Some(node))
+    }
+
+    /// Loads everything below a located directory from disk, so it can be moved
+    fn load_tree(&mut self, path: &Path) -> Result<()> {
+        match self.get(path)? {
+            Some(node) if self.read_through && node.is_dir() && !node.fresh => {}
+            _ => return Ok(()),
+        }
+        for entry in fs::read_dir(path).context("read directory")? {
+            let child = entry.context("read directory entry")?.path();
+            self.load_tree(&child)?;
+        }
+        Ok(())
+    }
+
+    fn state(&mut self, path: &Path) -> Result<FileState> {
+        Ok(self
+            .node(path)?
+            .map_or(FileState::Missing, |node| node.kind))
+    }
+
+    /// Fails unless the parent of a located path is a directory, like creating a file there would
+    fn check_parent(&mut self, path: &Path) -> Result<()> {
+        let parent = path.parent().context("get parent")?;
+        match self.get(parent)? {
+            Some(node) if node.is_dir() => Ok(()),
+            Some(_) => anyhow::bail!("parent {:?} is not a directory", parent),
+            None => anyhow::bail!("parent {:?} doesn't exist", parent),
+        }
+    }
+
+    /// Whether a located path is a directory without anything in it
+    fn is_empty_directory(&mut self, path: &Path) -> Result<bool> {
+        let node = match self.get(path)? {
+            Some(node) if node.is_dir() => node,
+            _ => return Ok(false),
+        };
+        if self
+            .nodes
+            .iter()
+            .any(|(child, node)| node.is_some() && child.parent() == Some(path))
+        {
+            return Ok(false);
+        }
+        if self.read_through && !node.fresh {
+            for entry in fs::read_dir(path).context("read directory")? {
+                let child = entry.context("read directory entry")?.path();
+                if self.get(&child)?.is_some() {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn destination(&mut self, source: &Path) -> Result<PathBuf> {
+        let real_source = self.resolved(source)?;
+        anyhow::ensure!(
+            self.get(&real_source)?.is_some(),
+            "source {:?} doesn't exist",
+            source
+        );
+        inside_root(real_source, self.root.as_deref())
+    }
+}
+
+impl Default for MemoryFilesystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Filesystem for MemoryFilesystem {
+    fn compare_symlink(&mut self, source: &Path, link: &Path) -> Result<SymlinkComparison> {
+        let source_state = self.state(source).context("get source state")?;
+        debug!("Source state: {:?}", source_state);
+        let link_state = self.state(link).context("get link state")?;
+        debug!("Link state: {:?}", link_state);
+
+        compare_symlink(source_state, link_state, || self.destination(source))
+    }
+
+    fn compare_contents(
+        &mut self,
+        target: &Path,
+        hash: Option<&str>,
+        _stamp: Option<FileStamp>,
+    ) -> Result<TemplateComparison> {
+        // Hashing is cheap in memory, so there's no need to trust the stamp
+        Ok(match self.state(target).context("get target state")? {
+            FileState::Missing => compare_hashes(None, hash),
+            FileState::File(contents) => compare_hashes(Some(&hash_contents(&contents)), hash),
+            FileState::SymbolicLink(_) | FileState::Directory => {
+                TemplateComparison::TargetNotRegularFile
+            }
+        })
+    }
+
+    fn compare_hardlink(&mut self, source: &Path, link: &Path) -> Result<HardlinkComparison> {
+        let source = self.resolved(source)?;
+        let source = self.get(&source).context("get source state")?;
+        let link = self.node(link).context("get link state")?;
+
+        Ok(match (source, link) {
+            (None, None) => HardlinkComparison::BothMissing,
+            (Some(_), None) => HardlinkComparison::OnlySourceExists,
+            (_, Some(l)) if l.contents().is_none() => HardlinkComparison::TargetNotRegularFile,
+            (None, Some(_)) => HardlinkComparison::OnlyTargetExists,
+            (Some(s), Some(l)) if s.identity == l.identity => HardlinkComparison::Identical,
+            (Some(_), Some(_)) => HardlinkComparison::Changed,
+        })
+    }
+
+    fn stamp(&mut self, path: &Path) -> Option<FileStamp> {
+        let node = self.node(path).ok()??;
+        Some(FileStamp {
+            size: u64::try_from(node.contents()?.len()).ok()?,
+            mtime: node.modified,
+        })
+    }
+
+    fn exists(&mut self, path: &Path) -> bool {
+        matches!(self.node(path), Ok(Some(_)))
+    }
+
+    fn mode(&mut self, path: &Path) -> Option<u32> {
+        let path = self.resolved(path).ok()?;
+        Some(self.get(&path).ok()??.mode)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<()> {
+        debug!("Removing file {:?}", path);
+        let path = self.located(path)?;
+        anyhow::ensure!(self.get(&path)?.is_some(), "file doesn't exist");
+        self.nodes.retain(|p, _| !p.starts_with(&path));
+        self.nodes.insert(path, None);
+        Ok(())
+    }
+
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>> {
+        debug!("Reading contents of file {:?}", path);
+        let path = self.resolved(path)?;
+        match self.get(&path)?.map(|node| node.kind) {
+            Some(FileState::File(contents)) => Ok(contents),
+            Some(_) => anyhow::bail!("reading from non-file"),
+            None => anyhow::bail!("file doesn't exist"),
+        }
+    }
+
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        debug!(
+            "Writing contents {:?} to file {:?}",
+            String::from_utf8_lossy(&content),
+            path
+        );
+        let path = self.resolved(path)?;
+        self.check_parent(&path)?;
+        let existing = self.get(&path)?;
+        anyhow::ensure!(
+            !matches!(&existing, Some(node) if node.is_dir()),
+            "file is a directory"
+        );
+
+        // Replacing a file keeps its owner, group and mode, like `write_atomically` does
+        let mut node = self.new_node(FileState::File(content), &None, &None, 0o644);
+        if let Some(existing) = existing {
+            node.owner = existing.owner;
+            node.group = existing.group;
+            node.mode = existing.mode;
+        }
+        self.nodes.insert(path, Some(node));
+        Ok(())
+    }
+
+    fn delete_parents(&mut self, path: &Path, _no_ask: bool) -> Result<()> {
+        debug!(
+            "Recursively deleting parents of {:?} if they're empty",
+            path
+        );
+        let mut path = self
+            .located(path)?
+            .parent()
+            .context("get parent")?
+            .to_path_buf();
+        while path.parent().is_some() && self.is_empty_directory(&path)? {
+            debug!("Removing empty directory {:?}", path);
+            self.nodes.insert(path.clone(), None);
+            path.pop();
+        }
+        Ok(())
+    }
+
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        debug!(
+            "Making symlink {:?} -> {:?} (owned by {:?}, group {:?})",
+            link, target, owner, group
+        );
+        let link = self.located(link)?;
+        self.check_parent(&link)?;
+        anyhow::ensure!(self.get(&link)?.is_none(), "link already exists");
+        let destination = self.destination(target)?;
+
+        let node = self.new_node(FileState::SymbolicLink(destination), owner, group, 0o777);
+        self.nodes.insert(link, Some(node));
+        Ok(())
+    }
+
+    fn make_hardlink(&mut self, link: &Path, target: &Path) -> Result<()> {
+        debug!("Making hard link {:?} -> {:?}", link, target);
+        let source = self.resolved(target)?;
+        let node = match self.get(&source)? {
+            Some(node) if node.contents().is_some() => node,
+            Some(node) => anyhow::bail!("file is not regular file but is a {:?}", node.kind),
+            None => anyhow::bail!("source file doesn't exist"),
+        };
+        let link = self.located(link)?;
+        self.check_parent(&link)?;
+        anyhow::ensure!(self.get(&link)?.is_none(), "link already exists");
+
+        self.nodes.insert(link, Some(node));
+        Ok(())
+    }
+
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        debug!(
+            "Creating directory {:?} (owned by {:?}, group {:?})",
+            path, owner, group
+        );
+        let path = self.absolute(path);
+        let mut current = PathBuf::new();
+        for component in path.components() {
+            current.push(component);
+            current = self.resolved(&current)?;
+            match self.get(&current)? {
+                Some(node) if node.is_dir() => {}
+                Some(_) => anyhow::bail!("{:?} is not a directory", current),
+                None => {
+                    let node = self.new_node(FileState::Directory, owner, group, 0o755);
+                    self.nodes.insert(current.clone(), Some(node));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        debug!(
+            "Copying file {:?} -> {:?} (target owned by {:?}, group {:?})",
+            source, target, owner, group
+        );
+        let source = self.resolved(source)?;
+        let (contents, mode) = match self.get(&source)? {
+            Some(Node {
+                kind: FileState::File(contents),
+                mode,
+                ..
+            }) => (contents, mode),
+            Some(node) => anyhow::bail!("file is not regular file but is a {:?}", node.kind),
+            None => anyhow::bail!("source file doesn't exist"),
+        };
+        let target = self.resolved(target)?;
+        self.check_parent(&target)?;
+        let existing = self.get(&target)?;
+        anyhow::ensure!(
+            !matches!(&existing, Some(node) if node.is_dir()),
+            "target is a directory"
+        );
+
+        // The target gets the source's mode, and keeps its owner unless it's given one, like
+        // `copy_atomically` does
+        let mut node = self.new_node(FileState::File(contents), owner, group, mode);
+        if let (None, Some(existing)) = (owner, existing) {
+            node.owner = existing.owner;
+            if group.is_none() {
+                node.group = existing.group;
+            }
+        }
+        self.nodes.insert(target, Some(node));
+        Ok(())
+    }
+
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        debug!(
+            "Setting owner of file {:?} to {:?} (group {:?})",
+            file, owner, group
+        );
+        let path = self.located(file)?;
+        let mut node = self.get(&path)?.context("file doesn't exist")?;
+        node.owner = owner.clone().unwrap_or_else(|| self.user.clone());
+        if let Some(group) = group {
+            node.group = group.clone();
+        }
+        self.nodes.insert(path, Some(node));
         Ok(())
     }
 
@@ -691,6 +1921,61 @@ impl Filesystem for DryRunFilesystem {
             "Copying permissions on files {:?} -> {:?} (target owned by {:?})",
             source, target, owner
         );
+        let source = self.resolved(source)?;
+        let mode = self.get(&source)?.context("source doesn't exist")?.mode;
+        self.set_mode(target, mode, owner)
+    }
+
+    fn set_mode(&mut self, path: &Path, mode: u32, owner: &Option<UnixUser>) -> Result<()> {
+        debug!(
+            "Setting mode of {:?} to {:04o} (owned by {:?})",
+            path, mode, owner
+        );
+        let path = self.resolved(path)?;
+        let mut node = self.get(&path)?.context("file doesn't exist")?;
+        node.mode = mode & 0o7777;
+        self.nodes.insert(path, Some(node));
+        Ok(())
+    }
+
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()> {
+        debug!("Moving {:?} -> {:?}", source, target);
+        let source = self.located(source)?;
+        let node = self.get(&source)?.context("source file is missing")?;
+        let target = self.located(target)?;
+        self.check_parent(&target)?;
+        anyhow::ensure!(
+            !matches!(self.get(&target)?, Some(node) if node.is_dir()),
+            "target is a directory"
+        );
+
+        self.load_tree(&source)?;
+        let below = self
+            .nodes
+            .iter()
+            .filter_map(|(path, node)| {
+                let relative = path.strip_prefix(&source).ok()?;
+                (!relative.as_os_str().is_empty()).then(|| (relative.to_path_buf(), node.clone()))
+            })
+            .collect::<Vec<_>>();
+        self.nodes
+            .retain(|path, _| !path.starts_with(&source) && !path.starts_with(&target));
+        self.nodes.insert(source, None);
+        // Everything that was moved is now known, so the disk below it isn't looked at
+        for (relative, node) in below {
+            let node = node.map(|node| Node {
+                fresh: true,
+                ..node
+            });
+            self.nodes.insert(target.join(relative), node);
+        }
+        self.nodes.insert(
+            target,
+            Some(Node {
+                fresh: true,
+                ..node
+            }),
+        );
         Ok(())
     }
 }
@@ -706,9 +1991,8 @@ fn get_file_state(path: &Path) -> Result<FileState> {
         return Ok(FileState::Directory);
     }
 
-    match fs::read_to_string(path) {
-        Ok(f) => Ok(FileState::File(Some(f))),
-        Err(e) if e.kind() == ErrorKind::InvalidData => Ok(FileState::File(None)),
+    match fs::read(path) {
+        Ok(f) => Ok(FileState::File(f)),
         Err(e) if e.kind() == ErrorKind::NotFound => Ok(FileState::Missing),
         Err(e) => Err(e).context("read contents of file that isn't symbolic or directory")?,
     }
@@ -739,15 +2023,17 @@ impl std::fmt::Display for SymlinkComparison {
     }
 }
 
+/// `destination` gives what a symlink to the source should point to. It's only called if the
+/// link is a symlink, since it may fail otherwise
 fn compare_symlink(
-    source_path: &Path,
     source_state: FileState,
     link_state: FileState,
+    destination: impl FnOnce() -> Result<PathBuf>,
 ) -> Result<SymlinkComparison> {
     Ok(match (source_state, link_state) {
         (FileState::Missing, FileState::SymbolicLink(_)) => SymlinkComparison::OnlyTargetExists,
         (_, FileState::SymbolicLink(t)) => {
-            if t == real_path(source_path).context("get real path of source")? {
+            if t == destination()? {
                 SymlinkComparison::Identical
             } else {
                 SymlinkComparison::Changed
@@ -773,40 +2059,251 @@ impl std::fmt::Display for TemplateComparison {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         use self::TemplateComparison::*;
         match self {
-            Identical => "target and cache's contents are equal",
+            Identical => "target's contents are the ones it was deployed with",
             OnlyCacheExists => "target doesn't exist",
-            OnlyTargetExists => "cache doesn't exist",
+            OnlyTargetExists => "target wasn't deployed before",
             Changed => "target contents were changed",
             TargetNotRegularFile => "target is a symbolic link or directory",
-            BothMissing => "cache and target are missing",
+            BothMissing => "target is missing and wasn't deployed before",
+        }
+        .fmt(f)
+    }
+}
+
+/// Compares a deployed file's target with the hash of the contents it was deployed with
+fn compare_contents(
+    target: &Path,
+    hash: Option<&str>,
+    stamp: Option<FileStamp>,
+) -> Result<TemplateComparison> {
+    let target_hash = match target.symlink_metadata() {
+        Ok(metadata) if !metadata.is_file() => return Ok(TemplateComparison::TargetNotRegularFile),
+        Ok(metadata) if hash.is_some() && stamp.is_some() && FileStamp::of(&metadata) == stamp => {
+            trace!("Target's size and modification time are unchanged");
+            return Ok(TemplateComparison::Identical);
+        }
+        Ok(_) => Some(hash_contents(&fs::read(target).context("read target")?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context("get metadata of target"),
+    };
+    trace!("Target hash: {:?}", target_hash);
+
+    Ok(compare_hashes(target_hash.as_deref(), hash))
+}
+
+fn compare_hashes(target: Option<&str>, cache: Option<&str>) -> TemplateComparison {
+    match (target, cache) {
+        (Some(t), Some(c)) if t == c => TemplateComparison::Identical,
+        (Some(_), Some(_)) => TemplateComparison::Changed,
+        (Some(_), None) => TemplateComparison::OnlyTargetExists,
+        (None, Some(_)) => TemplateComparison::OnlyCacheExists,
+        (None, None) => TemplateComparison::BothMissing,
+    }
+}
+
+/// Size and modification time of a deployed file, to tell cheaply that it hasn't changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch
+    pub mtime: i64,
+}
+
+impl FileStamp {
+    fn of(metadata: &fs::Metadata) -> Option<FileStamp> {
+        let modified = metadata.modified().ok()?;
+        let mtime = match modified.duration_since(std::time::UNIX_EPOCH) {
+            Ok(d) => i64::try_from(d.as_nanos()).ok()?,
+            Err(e) => -i64::try_from(e.duration().as_nanos()).ok()?,
+        };
+        Some(FileStamp {
+            size: metadata.len(),
+            mtime,
+        })
+    }
+}
+
+/// Size and modification time of a file on disk, if they can be read
+pub fn stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = path.symlink_metadata().ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    FileStamp::of(&metadata)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HardlinkComparison {
+    Identical,
+    OnlySourceExists,
+    OnlyTargetExists,
+    TargetNotRegularFile,
+    Changed,
+    BothMissing,
+}
+
+impl std::fmt::Display for HardlinkComparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        use self::HardlinkComparison::*;
+        match self {
+            Identical => "target is a hard link to source",
+            OnlySourceExists => "target missing",
+            OnlyTargetExists => "source is missing",
+            TargetNotRegularFile => "target is a symbolic link or directory",
+            Changed => "target is a different file than source",
+            BothMissing => "source and target are missing",
         }
         .fmt(f)
     }
 }
 
-fn compare_template(target_state: FileState, cache_state: FileState) -> TemplateComparison {
-    match (target_state, cache_state) {
-        (FileState::File(t), FileState::File(c)) => {
-            if t == c {
-                TemplateComparison::Identical
+fn compare_hardlink(source: &Path, link: &Path) -> Result<HardlinkComparison> {
+    let source_metadata = optional_metadata(source.metadata()).context("get source metadata")?;
+    let link_metadata = optional_metadata(link.symlink_metadata()).context("get link metadata")?;
+
+    Ok(match (source_metadata, link_metadata) {
+        (None, None) => HardlinkComparison::BothMissing,
+        (Some(_), None) => HardlinkComparison::OnlySourceExists,
+        (_, Some(l)) if !l.is_file() => HardlinkComparison::TargetNotRegularFile,
+        (None, Some(_)) => HardlinkComparison::OnlyTargetExists,
+        (Some(s), Some(l)) => {
+            if same_file(source, &s, link, &l).context("compare source and link")? {
+                HardlinkComparison::Identical
             } else {
-                TemplateComparison::Changed
+                HardlinkComparison::Changed
             }
         }
-        (FileState::File(_), FileState::Missing) => TemplateComparison::OnlyTargetExists,
-        (FileState::Missing, FileState::File(_)) => TemplateComparison::OnlyCacheExists,
-        (FileState::Missing, FileState::Missing) => TemplateComparison::BothMissing,
-        _ => TemplateComparison::TargetNotRegularFile,
+    })
+}
+
+fn optional_metadata(metadata: io::Result<fs::Metadata>) -> io::Result<Option<fs::Metadata>> {
+    match metadata {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Permission bits of a file, as passed to `chmod`
+#[cfg(unix)]
+fn mode_of(path: &Path) -> Result<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    let metadata = path.metadata().context("get metadata")?;
+    Ok(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(windows)]
+fn mode_of(_path: &Path) -> Result<u32> {
+    anyhow::bail!("files don't have permission bits on Windows")
+}
+
+/// Name of a user, for escalation tools that don't take a uid
+#[cfg(unix)]
+fn user_name(user: &UnixUser) -> String {
+    let uid = match user {
+        UnixUser::Name(name) => return name.clone(),
+        UnixUser::Uid(uid) => *uid,
+    };
+    // SAFETY: the returned entry is only read before any other call that could overwrite it
+    let name = unsafe {
+        let entry = libc::getpwuid(uid as libc::uid_t);
+        if entry.is_null() {
+            None
+        } else {
+            std::ffi::CStr::from_ptr((*entry).pw_name)
+                .to_str()
+                .ok()
+                .map(String::from)
+        }
+    };
+    name.unwrap_or_else(|| uid.to_string())
+}
+
+#[cfg(unix)]
+fn same_file(_: &Path, a: &fs::Metadata, _: &Path, b: &fs::Metadata) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+// File indices aren't available on stable Rust, so files with the same contents have to do
+#[cfg(windows)]
+fn same_file(a_path: &Path, a: &fs::Metadata, b_path: &Path, b: &fs::Metadata) -> Result<bool> {
+    Ok(a.len() == b.len() && fs::read(a_path)? == fs::read(b_path)?)
+}
+
+// === Utility functions ===
+
+/// Makes `path` absolute by placing it in `base` if it's relative, and removes `.` and `..` from
+/// it without following symlinks. `..` never goes above the root
+pub fn normalize_path(base: &Path, path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in base.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Hard links `link` to `target`, explaining the error if they're on different filesystems
+fn hard_link(link: &Path, target: &Path) -> Result<()> {
+    #[cfg(unix)]
+    const CROSSES_DEVICES: i32 = libc::EXDEV;
+    // ERROR_NOT_SAME_DEVICE
+    #[cfg(windows)]
+    const CROSSES_DEVICES: i32 = 17;
+
+    debug!("Creating hard link {:?} -> {:?}...", link, target);
+    let target = real_path(target).context("get real path of source file")?;
+    match fs::hard_link(&target, link) {
+        Err(e) if e.raw_os_error() == Some(CROSSES_DEVICES) => Err(e).context(format!(
+            "source {:?} and target {:?} are on different filesystems, which a hard link can't span. Deploy it with `type = \"copy\"` instead",
+            target, link
+        )),
+        result => result.context("create hard link"),
     }
 }
 
-/// === Utility functions ===
+pub fn hash_contents(contents: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(contents))
+}
+
+pub fn hash_file(path: &Path) -> Result<String> {
+    Ok(hash_contents(&fs::read(path).context("read file")?))
+}
 
 pub fn real_path(path: &Path) -> Result<PathBuf, io::Error> {
     let path = std::fs::canonicalize(path)?;
     Ok(platform_dunce(&path))
 }
 
+/// What a symlink to `source` points to: its real path, or when deploying into `root`, the path
+/// it has inside the root
+pub fn link_destination(source: &Path, root: Option<&Path>) -> Result<PathBuf> {
+    let real_source = real_path(source).context("get real path of source file")?;
+    inside_root(real_source, root)
+}
+
+/// The path `real_source` has inside `root`, if any
+fn inside_root(real_source: PathBuf, root: Option<&Path>) -> Result<PathBuf> {
+    let root = match root {
+        Some(root) => root,
+        None => return Ok(real_source),
+    };
+    let inside = real_source.strip_prefix(root).ok().with_context(|| {
+        format!(
+            "{:?} is outside of the root {:?}, so a symlink to it would be broken inside the root. Move the repository into the root or deploy the file as a copy",
+            real_source, root
+        )
+    })?;
+    Ok(Path::new("/").join(inside))
+}
+
 pub fn ask_boolean(prompt: &str) -> bool {
     let mut buf = String::from("a"); // enter the loop at least once
     while !(buf.to_lowercase().starts_with('y')
@@ -899,16 +2396,61 @@ mod test {
         let mut fs = DryRunFilesystem::new();
         fs.write(&PathBuf::from("test"), "hello world!".into())
             .unwrap();
+        assert_eq!(fs.read(&PathBuf::from("test")).unwrap(), b"hello world!");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn atomic_write_keeps_mode_and_follows_symlinks() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("file");
+        fs::write(&file, "old contents").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+        let inode = file.metadata().unwrap().ino();
+
+        write_atomically(&file, b"new", None).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"new");
+        assert_eq!(file.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+        // Replaced rather than written in place
+        assert_ne!(file.metadata().unwrap().ino(), inode);
+
+        let link = directory.path().join("link");
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+        write_atomically(&link, b"through link", None).unwrap();
+        assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&file).unwrap(), b"through link");
+
+        let new = directory.path().join("new");
+        write_atomically(&new, b"secret", Some(fs::Permissions::from_mode(0o640))).unwrap();
+        assert_eq!(new.metadata().unwrap().permissions().mode() & 0o777, 0o640);
+
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn link_destination_inside_root() {
+        let root = tempfile::tempdir().unwrap();
+        let root = real_path(root.path()).unwrap();
+        let source = root.join("home/user/dotfiles/bashrc");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "").unwrap();
+
+        assert_eq!(link_destination(&source, None).unwrap(), source);
         assert_eq!(
-            fs.read_to_string(&PathBuf::from("test")).unwrap(),
-            "hello world!"
+            link_destination(&source, Some(&root)).unwrap(),
+            Path::new("/home/user/dotfiles/bashrc")
         );
+        assert!(link_destination(&source, Some(&root.join("home/other"))).is_err());
     }
 
     #[test]
     fn simple_create_dir_all() {
         let mut fs = DryRunFilesystem::new();
-        fs.create_dir_all(&PathBuf::from("/home/user/.config"), &None)
+        fs.create_dir_all(&PathBuf::from("/home/user/.config"), &None, &None)
             .unwrap();
         assert_eq!(
             fs.get_state(&PathBuf::from("/home")).unwrap(),
@@ -933,28 +2475,22 @@ mod test {
         fs.remove_file(&PathBuf::from("target_dir/target")).unwrap();
 
         assert_eq!(
-            fs.compare_template(
-                &PathBuf::from("target_dir/target"),
-                &PathBuf::from("cache_dir/cache")
-            )
-            .unwrap(),
+            fs.compare_contents(&PathBuf::from("target_dir/target"), None, None)
+                .unwrap(),
             TemplateComparison::BothMissing
         );
 
-        fs.create_dir_all(&PathBuf::from("target_dir"), &None)
+        fs.create_dir_all(&PathBuf::from("target_dir"), &None, &None)
             .unwrap();
 
         // perform_template_deploy
-        assert_eq!(
-            fs.read_to_string(&PathBuf::from("source")).unwrap(),
-            "{{name}}"
-        );
+        assert_eq!(fs.read(&PathBuf::from("source")).unwrap(), b"{{name}}");
         let rendered = String::from("John");
 
         // cache
-        fs.create_dir_all(&PathBuf::from("cache_dir"), &None)
+        fs.create_dir_all(&PathBuf::from("cache_dir"), &None, &None)
             .unwrap();
-        fs.write(&PathBuf::from("cache_dir/cache"), rendered)
+        fs.write(&PathBuf::from("cache_dir/cache"), rendered.into())
             .unwrap();
 
         // target
@@ -962,6 +2498,7 @@ mod test {
             &PathBuf::from("cache_dir/cache"),
             &PathBuf::from("target_dir/target"),
             &None,
+            &None,
         )
         .unwrap();
         fs.copy_permissions(
@@ -974,7 +2511,7 @@ mod test {
         // Verify all actions
         assert_eq!(
             fs.file_states.get(&PathBuf::from("source")),
-            Some(&FileState::File(Some("{{name}}".into())))
+            Some(&FileState::File("{{name}}".into()))
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("cache_dir")),
@@ -982,7 +2519,7 @@ mod test {
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("cache_dir/cache")),
-            Some(&FileState::File(Some("John".into())))
+            Some(&FileState::File("John".into()))
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("target_dir")),
@@ -990,7 +2527,7 @@ mod test {
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("target_dir/target")),
-            Some(&FileState::File(Some("John".into())))
+            Some(&FileState::File("John".into()))
         );
     }
 
@@ -1005,13 +2542,326 @@ mod test {
             &PathBuf::from("source"),
             &PathBuf::from("some_dir/target"),
             &None,
+            &None,
         )
         .unwrap_err();
 
         // Source isn't a file
-        fs.make_symlink(&PathBuf::from("link"), &PathBuf::from("target"), &None)
+        fs.make_symlink(
+            &PathBuf::from("link"),
+            &PathBuf::from("target"),
+            &None,
+            &None,
+        )
+        .unwrap();
+        fs.copy_file(
+            &PathBuf::from("link"),
+            &PathBuf::from("link2"),
+            &None,
+            &None,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn dry_run_compare_contents() {
+        let mut fs = DryRunFilesystem::new();
+        let target = PathBuf::from("copy_target");
+        let hash = hash_contents(b"hello world!");
+
+        fs.remove_file(&target).unwrap();
+        assert_eq!(
+            fs.compare_contents(&target, Some(&hash), None).unwrap(),
+            TemplateComparison::OnlyCacheExists
+        );
+        assert_eq!(
+            fs.compare_contents(&target, None, None).unwrap(),
+            TemplateComparison::BothMissing
+        );
+
+        fs.write(&target, "hello world!".into()).unwrap();
+        assert_eq!(
+            fs.compare_contents(&target, Some(&hash), None).unwrap(),
+            TemplateComparison::Identical
+        );
+        assert_eq!(
+            fs.compare_contents(&target, None, None).unwrap(),
+            TemplateComparison::OnlyTargetExists
+        );
+
+        fs.write(&target, "hello there!".into()).unwrap();
+        assert_eq!(
+            fs.compare_contents(&target, Some(&hash), None).unwrap(),
+            TemplateComparison::Changed
+        );
+    }
+
+    #[test]
+    fn dry_run_compare_hardlink() {
+        let mut fs = DryRunFilesystem::new();
+        let (source, link) = (Path::new("source"), Path::new("link"));
+        fs.write(source, "hello world!".into()).unwrap();
+        fs.remove_file(link).unwrap();
+        assert_eq!(
+            fs.compare_hardlink(source, link).unwrap(),
+            HardlinkComparison::OnlySourceExists
+        );
+
+        fs.make_hardlink(link, source).unwrap();
+        assert_eq!(
+            fs.compare_hardlink(source, link).unwrap(),
+            HardlinkComparison::Identical
+        );
+
+        // Replaced by a file with the same contents, like a program saving it atomically would
+        fs.remove_file(link).unwrap();
+        fs.write(link, "hello world!".into()).unwrap();
+        assert_eq!(
+            fs.compare_hardlink(source, link).unwrap(),
+            HardlinkComparison::Changed
+        );
+    }
+
+    #[test]
+    fn dry_run_compare_binary() {
+        let mut fs = DryRunFilesystem::new();
+        let (target, cache) = (Path::new("dir/target"), Path::new("dir/cache"));
+        let hash = hash_contents(b"\xff\x00two");
+        fs.create_dir_all(Path::new("dir"), &None, &None).unwrap();
+        fs.write(target, b"\xff\x00one".to_vec()).unwrap();
+        fs.write(cache, b"\xff\x00two".to_vec()).unwrap();
+        assert_eq!(
+            fs.compare_contents(target, Some(&hash), None).unwrap(),
+            TemplateComparison::Changed
+        );
+
+        fs.copy_file(cache, target, &None, &None).unwrap();
+        assert_eq!(
+            fs.compare_contents(target, Some(&hash), None).unwrap(),
+            TemplateComparison::Identical
+        );
+    }
+
+    #[test]
+    fn memory_owners_and_modes() {
+        let mut fs = MemoryFilesystem::new();
+        let root = Some(UnixUser::Name("root".into()));
+        let wheel = Some(UnixGroup::Name("wheel".into()));
+
+        fs.create_dir_all(Path::new("/etc/app"), &root, &wheel)
             .unwrap();
-        fs.copy_file(&PathBuf::from("link"), &PathBuf::from("link2"), &None)
+        fs.create_dir_all(Path::new("dots"), &None, &None).unwrap();
+        fs.write(Path::new("dots/conf"), "key = 1".into()).unwrap();
+        fs.set_mode(Path::new("dots/conf"), 0o600, &None).unwrap();
+        fs.copy_file(
+            Path::new("/dots/conf"),
+            Path::new("/etc/app/conf"),
+            &root,
+            &None,
+        )
+        .unwrap();
+
+        let dir = fs.node(Path::new("/etc/app")).unwrap().unwrap();
+        assert!(dir.is_dir());
+        assert_eq!((Some(dir.owner), Some(dir.group)), (root.clone(), wheel));
+        let copy = fs.node(Path::new("/etc/app/conf")).unwrap().unwrap();
+        assert_eq!(copy.contents(), Some(&b"key = 1"[..]));
+        assert_eq!(Some(copy.owner), root);
+        assert_eq!(copy.group, UnixGroup::Gid(1000));
+        assert_eq!(copy.mode, 0o600);
+
+        // Rewriting a file keeps its owner and mode, while setting it back gives it to the user
+        fs.write(Path::new("/etc/app/conf"), "key = 2".into())
+            .unwrap();
+        let copy = fs.node(Path::new("/etc/app/conf")).unwrap().unwrap();
+        assert_eq!(copy.mode, 0o600);
+        fs.set_owner(Path::new("/etc/app/conf"), &None, &None)
+            .unwrap();
+        let copy = fs.node(Path::new("/etc/app/conf")).unwrap().unwrap();
+        assert_eq!(copy.owner, UnixUser::Uid(1000));
+
+        assert!(fs
+            .write(Path::new("/missing/conf"), "key = 1".into())
+            .is_err());
+        assert!(fs.write(Path::new("/etc/app"), "key = 1".into()).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn escalation_commands() {
+        use crate::exit::{status_of, ExitStatus};
+
+        fn command_line(escalation: Escalation, user: Option<&UnixUser>) -> Vec<String> {
+            let command = RealFilesystem::new(true, escalation)
+                .elevate("test", user)
+                .unwrap();
+            std::iter::once(command.get_program())
+                .chain(command.get_args())
+                .map(|part| part.to_string_lossy().into_owned())
+                .collect()
+        }
+
+        let alice = UnixUser::Name("alice".into());
+        let root = UnixUser::Uid(0);
+        assert_eq!(command_line(Escalation::Sudo, None), ["sudo"]);
+        assert_eq!(
+            command_line(Escalation::Sudo, Some(&alice)),
+            ["sudo", "-u", "alice"]
+        );
+        assert_eq!(
+            command_line(Escalation::Sudo, Some(&root)),
+            ["sudo", "-u", "#0"]
+        );
+        assert_eq!(command_line(Escalation::Doas, None), ["doas"]);
+        assert_eq!(
+            command_line(Escalation::Doas, Some(&alice)),
+            ["doas", "-u", "alice"]
+        );
+        assert_eq!(
+            command_line(Escalation::Doas, Some(&root)),
+            ["doas", "-u", "root"]
+        );
+        assert_eq!(command_line(Escalation::Run0, None), ["run0"]);
+        assert_eq!(
+            command_line(Escalation::Run0, Some(&alice)),
+            ["run0", "--user=alice"]
+        );
+        assert_eq!(
+            command_line(Escalation::Run0, Some(&root)),
+            ["run0", "--user=0"]
+        );
+        assert_eq!(command_line(Escalation::Pkexec, None), ["pkexec"]);
+        assert_eq!(
+            command_line(Escalation::Pkexec, Some(&alice)),
+            ["pkexec", "--user", "alice"]
+        );
+        assert_eq!(
+            command_line(Escalation::Pkexec, Some(&root)),
+            ["pkexec", "--user", "root"]
+        );
+
+        let error = RealFilesystem::new(true, Escalation::Never)
+            .elevate("test", Some(&alice))
             .unwrap_err();
+        assert_eq!(status_of(&error), ExitStatus::Permission);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn set_group_without_elevating() {
+        use crate::exit::{status_of, ExitStatus};
+        use std::os::unix::fs::MetadataExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("file");
+        fs::write(&file, "contents").unwrap();
+        let dangling = directory.path().join("dangling");
+        std::os::unix::fs::symlink(directory.path().join("missing"), &dangling).unwrap();
+
+        // Elevating would fail, so these have to be done as the current user
+        let mut real = RealFilesystem::new(true, Escalation::Never);
+        let own = UnixGroup::Gid(unsafe { libc::getegid() } as i32);
+        real.set_group(&file, &own).unwrap();
+        // Only works because the link itself is changed, not the file it points to
+        real.set_group(&dangling, &own).unwrap();
+        assert_eq!(dangling.symlink_metadata().unwrap().gid(), unsafe {
+            libc::getegid()
+        });
+
+        // Anyone but root needs elevated permissions to give a file to a group they're not in
+        if unsafe { libc::geteuid() } != 0 {
+            let error = real.set_group(&file, &UnixGroup::Gid(0)).unwrap_err();
+            assert_eq!(status_of(&error), ExitStatus::Permission);
+        }
+
+        // Like `chgrp -h`, a symlink's group is set instead of its target's
+        let mut memory = MemoryFilesystem::new();
+        let wheel = UnixGroup::Name("wheel".into());
+        memory
+            .create_dir_all(Path::new("/dots"), &None, &None)
+            .unwrap();
+        memory.write(Path::new("/dots/rc"), "rc".into()).unwrap();
+        memory
+            .make_symlink(
+                Path::new("/rc"),
+                Path::new("/dots/rc"),
+                &None,
+                &Some(wheel.clone()),
+            )
+            .unwrap();
+        let link = memory.node(Path::new("/rc")).unwrap().unwrap();
+        assert_eq!(link.group, wheel);
+        let source = memory.node(Path::new("/dots/rc")).unwrap().unwrap();
+        assert_eq!(source.group, UnixGroup::Gid(1000));
+    }
+
+    #[test]
+    fn memory_links() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_dir_all(Path::new("/dots"), &None, &None).unwrap();
+        fs.create_dir_all(Path::new("/home/u/.config/app"), &None, &None)
+            .unwrap();
+        fs.write(Path::new("/dots/rc"), "hello".into()).unwrap();
+
+        let (source, link) = (Path::new("/dots/rc"), Path::new("/home/u/.config/app/rc"));
+        assert_eq!(
+            fs.compare_symlink(source, link).unwrap(),
+            SymlinkComparison::OnlySourceExists
+        );
+        fs.make_symlink(link, Path::new("/home/u/../../dots/./rc"), &None, &None)
+            .unwrap();
+        assert_eq!(
+            fs.compare_symlink(source, link).unwrap(),
+            SymlinkComparison::Identical
+        );
+        assert_eq!(fs.read(link).unwrap(), b"hello");
+        assert!(fs.make_symlink(link, source, &None, &None).is_err());
+
+        let hardlink = Path::new("/home/u/rc");
+        fs.make_hardlink(hardlink, link).unwrap();
+        assert_eq!(
+            fs.compare_hardlink(source, hardlink).unwrap(),
+            HardlinkComparison::Identical
+        );
+        fs.copy_file(source, hardlink, &None, &None).unwrap();
+        assert_eq!(
+            fs.compare_hardlink(source, hardlink).unwrap(),
+            HardlinkComparison::Changed
+        );
+
+        // Only the parents that became empty are removed
+        fs.remove_file(link).unwrap();
+        fs.delete_parents(link, false).unwrap();
+        assert!(!fs.exists(Path::new("/home/u/.config")));
+        assert!(fs.exists(Path::new("/home/u")));
+        assert!(fs.exists(source));
+    }
+
+    #[test]
+    fn memory_reads_through_without_writing() {
+        let directory = tempfile::tempdir().unwrap();
+        let dir = directory.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("file"), "on disk").unwrap();
+
+        let mut memory = MemoryFilesystem::read_through().unwrap();
+        assert_eq!(memory.read(&dir.join("file")).unwrap(), b"on disk");
+        assert_eq!(
+            memory
+                .compare_contents(&dir.join("file"), Some(&hash_contents(b"on disk")), None)
+                .unwrap(),
+            TemplateComparison::Identical
+        );
+
+        memory.write(&dir.join("new"), "in memory".into()).unwrap();
+        let moved = directory.path().join("moved");
+        memory.move_file(&dir, &moved).unwrap();
+        assert_eq!(memory.read(&moved.join("file")).unwrap(), b"on disk");
+        assert_eq!(memory.read(&moved.join("new")).unwrap(), b"in memory");
+        assert!(!memory.exists(&dir.join("file")));
+
+        assert!(!directory.path().join("moved").exists());
+        assert!(!dir.join("new").exists());
+        assert_eq!(fs::read(dir.join("file")).unwrap(), b"on disk");
     }
 }
@@ -332,25 +332,17 @@ mod test {
             variables: maplit::btreemap! { "foo".into() => 2.into() },
             helpers: Helpers::new(),
             packages: vec!["default".into()],
+            package_files: Default::default(),
             recurse: true,
+            hooks: Vec::new(),
         };
         let handlebars = create_new_handlebars(&mut config).unwrap();
 
-        assert_eq!(
-            eval_condition(&handlebars, &config.variables, "foo").unwrap(),
-            true
-        );
-        assert_eq!(
-            eval_condition(&handlebars, &config.variables, "bar").unwrap(),
-            false
-        );
-        assert_eq!(
-            eval_condition(&handlebars, &config.variables, "dotter.packages.default").unwrap(),
-            true
-        );
-        assert_eq!(
-            eval_condition(&handlebars, &config.variables, "dotter.packages.nonexist").unwrap(),
-            false
+        assert!(eval_condition(&handlebars, &config.variables, "foo").unwrap());
+        assert!(!eval_condition(&handlebars, &config.variables, "bar").unwrap());
+        assert!(eval_condition(&handlebars, &config.variables, "dotter.packages.default").unwrap());
+        assert!(
+            !eval_condition(&handlebars, &config.variables, "dotter.packages.nonexist").unwrap()
         );
     }
 
@@ -361,22 +353,20 @@ mod test {
             variables: Variables::new(),
             helpers: Helpers::new(),
             packages: vec!["default".into()],
+            package_files: Default::default(),
             recurse: true,
+            hooks: Vec::new(),
         };
         let handlebars = create_new_handlebars(&mut config).unwrap();
 
-        assert_eq!(
-            eval_condition(
-                &handlebars,
-                &config.variables,
-                "(is_executable \"no_such_executable_please\")"
-            )
-            .unwrap(),
-            false
-        );
-        assert_eq!(
-            eval_condition(&handlebars, &config.variables, "(eq (math \"5+5\") \"10\")").unwrap(),
-            true
+        assert!(!eval_condition(
+            &handlebars,
+            &config.variables,
+            "(is_executable \"no_such_executable_please\")"
+        )
+        .unwrap());
+        assert!(
+            eval_condition(&handlebars, &config.variables, "(eq (math \"5+5\") \"10\")").unwrap()
         );
     }
 }
@@ -3,20 +3,193 @@ use handlebars::Handlebars;
 
 use std::path::Path;
 use std::process::Child;
-use std::process::Command;
+use std::process::{Command, Stdio};
 
-pub(crate) fn run_hook(
+use crate::args::{Options, OutputFormat};
+use crate::config::{Hook, PackageHooks, Variables};
+use crate::exit::{ExitStatus, Failure};
+
+/// When hooks run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    PreDeploy,
+    PostDeploy,
+    PreUndeploy,
+    PostUndeploy,
+}
+
+impl Stage {
+    fn script(self, opt: &Options) -> &Path {
+        match self {
+            Stage::PreDeploy => &opt.pre_deploy,
+            Stage::PostDeploy => &opt.post_deploy,
+            Stage::PreUndeploy => &opt.pre_undeploy,
+            Stage::PostUndeploy => &opt.post_undeploy,
+        }
+    }
+
+    fn hook(self, package: &PackageHooks) -> Option<&Hook> {
+        let hooks = &package.hooks;
+        match self {
+            Stage::PreDeploy => hooks.pre_deploy.as_ref(),
+            Stage::PostDeploy => hooks.post_deploy.as_ref(),
+            Stage::PreUndeploy => hooks.pre_undeploy.as_ref(),
+            Stage::PostUndeploy => hooks.post_undeploy.as_ref(),
+        }
+    }
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Stage::PreDeploy => "pre-deploy",
+            Stage::PostDeploy => "post-deploy",
+            Stage::PreUndeploy => "pre-undeploy",
+            Stage::PostUndeploy => "post-undeploy",
+        }
+        .fmt(f)
+    }
+}
+
+/// Runs the global hook of `stage` and the ones packages declare for it. Package hooks run
+/// inside the global ones: after it for pre-hooks and before it for post-hooks.
+/// When deploying, packages run after the packages they depend on, and before them when
+/// undeploying
+pub(crate) fn run_hooks(
+    stage: Stage,
+    opt: &Options,
+    packages: &[PackageHooks],
+    handlebars: &Handlebars,
+    variables: &Variables,
+) -> Result<()> {
+    let global = || {
+        run_hook(stage.script(opt), opt, handlebars, variables).context(Failure::new(
+            ExitStatus::Hook,
+            format!("run {} hook", stage),
+        ))
+    };
+
+    if matches!(stage, Stage::PreDeploy | Stage::PreUndeploy) {
+        global()?;
+    }
+
+    let packages: Box<dyn Iterator<Item = &PackageHooks>> =
+        if matches!(stage, Stage::PreUndeploy | Stage::PostUndeploy) {
+            Box::new(packages.iter().rev())
+        } else {
+            Box::new(packages.iter())
+        };
+    for package in packages {
+        if let Some(hook) = stage.hook(package) {
+            debug!("Running {} hook of package {:?}", stage, package.package);
+            run_package_hook(hook, package, opt, handlebars, variables).with_context(|| {
+                Failure::new(
+                    ExitStatus::Hook,
+                    format!("run {} hook of package {:?}", stage, package.package),
+                )
+            })?;
+        }
+    }
+
+    if matches!(stage, Stage::PostDeploy | Stage::PostUndeploy) {
+        global()?;
+    }
+    Ok(())
+}
+
+fn run_package_hook(
+    hook: &Hook,
+    package: &PackageHooks,
+    opt: &Options,
+    handlebars: &Handlebars,
+    variables: &Variables,
+) -> Result<()> {
+    let mut package_variables = package.variables.clone();
+    if let Some(dotter) = variables.get("dotter") {
+        package_variables.insert("dotter".into(), dotter.clone());
+    }
+
+    match hook {
+        Hook::Command(command) => run_command(command, opt, handlebars, &package_variables),
+        Hook::Script { script } => {
+            // Unlike the global hooks, it was asked for explicitly
+            anyhow::ensure!(script.exists(), "script {:?} doesn't exist", script);
+            run_hook(script, opt, handlebars, &package_variables)
+        }
+    }
+}
+
+/// Runs the `on_change` commands collected while deploying, in the order they were collected
+pub(crate) fn run_change_hooks(
+    commands: &[String],
+    opt: &Options,
+    handlebars: &Handlebars,
+    variables: &Variables,
+) -> Result<()> {
+    for command in commands {
+        debug!("Running on_change command {:?}", command);
+        run_command(command, opt, handlebars, variables).with_context(|| {
+            Failure::new(
+                ExitStatus::Hook,
+                format!("run on_change command {:?}", command),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Where the output of hooks goes. Stdout is reserved for events when JSON output was requested
+fn hook_stdout(opt: &Options) -> Stdio {
+    if opt.output == OutputFormat::Json {
+        std::io::stderr().into()
+    } else {
+        Stdio::inherit()
+    }
+}
+
+/// Renders a command and runs it with the shell
+fn run_command(
+    command: &str,
+    opt: &Options,
+    handlebars: &Handlebars,
+    variables: &Variables,
+) -> Result<()> {
+    let command = handlebars
+        .render_template(command, variables)
+        .context("render command")?;
+    debug!("Running command {:?}", command);
+
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let status = shell
+        .arg(&command)
+        .stdout(hook_stdout(opt))
+        .status()
+        .context("spawn shell")?;
+    anyhow::ensure!(status.success(), "command returned error");
+
+    Ok(())
+}
+
+fn run_hook(
     location: &Path,
-    cache_dir: &Path,
+    opt: &Options,
     handlebars: &Handlebars,
-    variables: &crate::config::Variables,
+    variables: &Variables,
 ) -> Result<()> {
     if !location.exists() {
         debug!("Hook file at {:?} missing", location);
         return Ok(());
     }
 
-    let mut script_file = cache_dir.join(location);
+    let mut script_file = opt.cache_directory.join(location);
     let mut target = std::env::temp_dir().join("dotter_temp");
     if cfg!(windows) {
         script_file.set_extension("bat");
@@ -28,14 +201,17 @@ pub(crate) fn run_hook(
         location,
         &script_file,
         &target.clone().into(),
-        &mut crate::filesystem::RealFilesystem::new(false),
+        // The script is rendered into the user's temporary directory without an owner, so it
+        // never needs elevated permissions
+        &mut crate::filesystem::RealFilesystem::new(false, crate::config::Escalation::Never),
         handlebars,
         variables,
+        false,
     )
     .context("deploy script")?;
 
     debug!("Running script file");
-    let mut child = run_script_file(&target)?;
+    let mut child = run_script_file(&target, hook_stdout(opt))?;
 
     anyhow::ensure!(
         child.wait().context("wait for child shell")?.success(),
@@ -46,21 +222,28 @@ pub(crate) fn run_hook(
 }
 
 #[cfg(unix)]
-fn run_script_file(script: &Path) -> Result<Child> {
+fn run_script_file(script: &Path, stdout: Stdio) -> Result<Child> {
     use std::os::unix::fs::PermissionsExt;
 
     let permissions = script.metadata()?.permissions();
     if !script.is_dir() && permissions.mode() & 0o111 != 0 {
-        Command::new(script).spawn().context("spawn script file")
+        Command::new(script)
+            .stdout(stdout)
+            .spawn()
+            .context("spawn script file")
     } else {
         Command::new("sh")
             .arg(script)
+            .stdout(stdout)
             .spawn()
             .context("spawn shell")
     }
 }
 
 #[cfg(windows)]
-fn run_script_file(script: &Path) -> Result<Child> {
-    Command::new(script).spawn().context("spawn batch file")
+fn run_script_file(script: &Path, stdout: Stdio) -> Result<Child> {
+    Command::new(script)
+        .stdout(stdout)
+        .spawn()
+        .context("spawn batch file")
 }
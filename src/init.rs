@@ -1,7 +1,5 @@
 use anyhow::{Context, Result};
 
-use std::collections::BTreeMap;
-
 use crate::args::Options;
 use crate::config;
 use crate::filesystem::save_file;
@@ -38,14 +36,7 @@ pub fn init(opt: Options) -> Result<()> {
         .context("save dummy config")?;
 
     debug!("Emptying cache...");
-    save_file(
-        &opt.cache_file,
-        config::Cache {
-            symlinks: BTreeMap::default(),
-            templates: BTreeMap::default(),
-        },
-    )
-    .context("save empty cache file")?;
+    save_file(&opt.cache_file, config::Cache::default()).context("save empty cache file")?;
     match std::fs::remove_dir_all(opt.cache_directory) {
         Ok(()) => Ok(()),
         Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
@@ -0,0 +1,178 @@
+use anyhow::{Context, Result};
+
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Advisory lock on the repository, held while Dotter changes the cache or deployed files so
+/// that two instances (like `watch` and a manual deploy) don't race on them.
+/// It's an OS lock on the lock file, so it's released when its holder exits even if it doesn't
+/// get to remove the file. The file contains the PID of the holder, and is removed when the lock
+/// is dropped.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+    /// Holds the lock while it's open
+    _file: File,
+}
+
+impl Lock {
+    /// Takes the lock, failing if another process holds it
+    pub fn acquire(path: &Path) -> Result<Lock> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).context("create parent directory of lock file")?;
+        }
+
+        // The holder removes the file before releasing the lock, so the one that was opened may
+        // have been removed by the time it's locked. Then it's tried again with the new one
+        for _ in 0..3 {
+            let mut file = match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+            {
+                Ok(file) => file,
+                // On Windows, while the holder is removing it
+                Err(e) if e.kind() == ErrorKind::PermissionDenied => break,
+                Err(e) => return Err(e).with_context(|| format!("open lock file {:?}", path)),
+            };
+
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    let mut contents = String::new();
+                    let holder = file
+                        .read_to_string(&mut contents)
+                        .ok()
+                        .and_then(|_| contents.trim().parse::<u32>().ok());
+                    match holder {
+                        Some(pid) => anyhow::bail!(
+                            "another instance of dotter (PID {}) is working on this repository. Wait for it to finish",
+                            pid
+                        ),
+                        None => break,
+                    }
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| format!("lock {:?}", path))
+                }
+            }
+
+            if !is_file_at(&file, path)? {
+                debug!("Lock {:?} was released while taking it, trying again", path);
+                continue;
+            }
+
+            // It's only empty when it was just created
+            let mut previous = String::new();
+            file.read_to_string(&mut previous)
+                .context("read lock file")?;
+            if !previous.is_empty() {
+                warn!(
+                    "Taking over lock {:?} left by process {}, which isn't running anymore",
+                    path,
+                    previous.trim()
+                );
+            }
+            file.set_len(0).context("truncate lock file")?;
+            file.rewind().context("rewind lock file")?;
+            file.write_all(std::process::id().to_string().as_bytes())
+                .context("write PID to lock file")?;
+            debug!("Acquired lock {:?}", path);
+            return Ok(Lock {
+                path: path.into(),
+                _file: file,
+            });
+        }
+
+        anyhow::bail!(
+            "another instance of dotter is working on this repository (it holds {:?})",
+            path
+        )
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Removed while it's still locked, so that nobody takes a lock on a file that's about
+        // to be removed
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to release lock {:?}: {}", self.path, e);
+        } else {
+            debug!("Released lock {:?}", self.path);
+        }
+    }
+}
+
+/// Whether `file` is still the one at `path`
+#[cfg(unix)]
+fn is_file_at(file: &File, path: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let locked = file.metadata().context("get metadata of lock file")?;
+    match fs::metadata(path) {
+        Ok(current) => Ok((current.dev(), current.ino()) == (locked.dev(), locked.ino())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("get metadata of {:?}", path)),
+    }
+}
+
+#[cfg(windows)]
+fn is_file_at(_file: &File, _path: &Path) -> Result<bool> {
+    // A file can't be replaced while it's open, and another can't be created in its place
+    // until every handle to it is closed
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock_is_exclusive_and_released_on_drop() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(".dotter/lock");
+
+        let lock = Lock::acquire(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+        assert!(Lock::acquire(&path).is_err());
+
+        drop(lock);
+        assert!(!path.exists());
+        Lock::acquire(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_lock_is_replaced() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("lock");
+
+        // Left by a process that exited without removing it, like after a crash
+        fs::write(&path, "4194304000").unwrap();
+        let _lock = Lock::acquire(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn removed_lock_file_is_noticed() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("lock");
+
+        // Like when the holder releases the lock between it being opened and locked
+        let opened = File::create(&path).unwrap();
+        assert!(is_file_at(&opened, &path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(!is_file_at(&opened, &path).unwrap());
+        fs::write(&path, "").unwrap();
+        assert!(!is_file_at(&opened, &path).unwrap());
+    }
+}
@@ -2,14 +2,23 @@
 extern crate log;
 
 mod actions;
+mod adopt;
 mod args;
+mod backup;
 mod config;
 mod deploy;
 mod difference;
+mod exit;
 mod filesystem;
 mod handlebars_helpers;
 mod hooks;
 mod init;
+mod lock;
+mod plan;
+mod repair;
+mod report;
+mod status;
+mod transaction;
 mod watch;
 
 use std::fmt::Write;
@@ -19,15 +28,18 @@ use anyhow::{Context, Result};
 use clap::CommandFactory;
 use clap_complete::{generate, generate_to};
 
+use exit::{ExitStatus, Failure};
+
 fn main() {
-    match run() {
-        Ok(success) if success => std::process::exit(0),
-        Ok(_) => std::process::exit(1),
+    let status = match run() {
+        Ok(status) => status,
         Err(e) => {
+            let status = exit::status_of(&e);
             display_error(e);
-            std::process::exit(1);
+            status
         }
-    }
+    };
+    std::process::exit(status as i32);
 }
 
 pub(crate) fn display_error(error: anyhow::Error) {
@@ -43,10 +55,10 @@ pub(crate) fn display_error(error: anyhow::Error) {
     error!("{}", error_message);
 }
 
-/// Returns true if program should exit with success status
-fn run() -> Result<bool> {
+/// Returns the status the program should exit with
+fn run() -> Result<ExitStatus> {
     // Parse arguments
-    let opt = args::get_options();
+    let mut opt = args::get_options();
 
     use simplelog::LevelFilter;
 
@@ -70,7 +82,12 @@ fn run() -> Result<bool> {
             .set_level_padding(simplelog::LevelPadding::Left)
             .add_filter_allow("dotter".into())
             .build(),
-        simplelog::TerminalMode::Mixed,
+        if opt.output == args::OutputFormat::Json {
+            // Keep stdout for the JSON events
+            simplelog::TerminalMode::Stderr
+        } else {
+            simplelog::TerminalMode::Mixed
+        },
         simplelog::ColorChoice::Auto,
     )
     .unwrap();
@@ -83,19 +100,98 @@ If you're truly logged in as root, it is safe to ignore this message.
 Otherwise, run `dotter undeploy` as root, remove cache.toml and cache/ folders, then use Dotter as a regular user.");
     }
 
-    match opt.action.clone().unwrap_or_default() {
+    if opt.escalate.is_none() {
+        opt.escalate = config::configured_escalation(&opt.local_config).context(Failure::new(
+            ExitStatus::Config,
+            "read the `escalate` setting of the local configuration",
+        ))?;
+    }
+
+    let action = opt.action.clone().unwrap_or_default();
+
+    // Held until the action is done. A dry run doesn't change anything, except for `init`
+    let _lock =
+        if action.modifies_repository() && (!opt.dry_run || matches!(action, args::Action::Init)) {
+            Some(lock::Lock::acquire(&opt.lock_file).context("lock repository")?)
+        } else {
+            None
+        };
+
+    // Whether or not this run is transactional, it mustn't build on a half-done deployment
+    if action.modifies_repository() && !opt.dry_run {
+        transaction::recover(
+            &mut filesystem::RealFilesystem::new(opt.noconfirm, opt.escalate.unwrap_or_default()),
+            &transaction::journal_directory(&opt),
+        )
+        .context("recover interrupted deployment")?;
+    }
+
+    match action {
         args::Action::Deploy => {
             debug!("Deploying...");
-            if deploy::deploy(&opt).context("deploy")? {
+            let status = deploy::deploy(&opt).context("deploy")?;
+            if status != ExitStatus::Success {
                 // An error occurred
-                return Ok(false);
+                return Ok(status);
             }
         }
         args::Action::Undeploy => {
             debug!("Un-Deploying...");
-            if deploy::undeploy(opt).context("undeploy")? {
+            let status = deploy::undeploy(opt).context("undeploy")?;
+            if status != ExitStatus::Success {
                 // An error occurred
-                return Ok(false);
+                return Ok(status);
+            }
+        }
+        args::Action::Status => {
+            debug!("Checking status...");
+            if status::status(&opt).context("check status")? {
+                // Something is out of sync
+                return Ok(ExitStatus::Error);
+            }
+        }
+        args::Action::Diff {
+            ref paths,
+            ref packages,
+        } => {
+            debug!("Showing differences...");
+            if difference::show_diffs(&opt, paths, packages).context("show differences")? {
+                // Differences were found
+                return Ok(ExitStatus::Error);
+            }
+        }
+        args::Action::Adopt { ref paths } => {
+            debug!("Adopting changes...");
+            if adopt::adopt(&opt, paths).context("adopt changes")? {
+                // Some changes were left behind
+                return Ok(ExitStatus::Error);
+            }
+        }
+        args::Action::Plan { ref out } => {
+            debug!("Planning deployment...");
+            if plan::plan(&opt, out.as_deref()).context("plan deployment")? {
+                // Some operations couldn't be planned
+                return Ok(ExitStatus::Error);
+            }
+        }
+        args::Action::Apply { ref plan } => {
+            debug!("Applying plan...");
+            let status = plan::apply(&opt, plan).context("apply plan")?;
+            if status != ExitStatus::Success {
+                // An error occurred
+                return Ok(status);
+            }
+        }
+        args::Action::Restore { id } => {
+            debug!("Restoring backups...");
+            backup::restore(&opt, id).context("restore backup")?;
+        }
+        args::Action::Cache {
+            action: args::CacheAction::Repair,
+        } => {
+            debug!("Repairing cache...");
+            if repair::repair(&opt).context("repair cache")? {
+                return Ok(ExitStatus::Error);
             }
         }
         args::Action::Init => {
@@ -124,5 +220,5 @@ Otherwise, run `dotter undeploy` as root, remove cache.toml and cache/ folders,
         }
     }
 
-    Ok(true)
+    Ok(ExitStatus::Success)
 }
@@ -0,0 +1,690 @@
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use handlebars::Handlebars;
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::actions::{mode_differs, ActionKind, ActionRunner};
+use crate::args::Options;
+use crate::config::{
+    Cache, CachedFile, CopyTarget, HardlinkTarget, SymbolicTarget, TemplateTarget, Variables,
+};
+use crate::deploy::{
+    deploy_with, desired_files, execute_action, load_configuration_and_cache, run_deploy,
+};
+use crate::difference::render_template;
+use crate::exit::ExitStatus;
+use crate::filesystem::{
+    self, DryRunFilesystem, Filesystem, HardlinkComparison, SymlinkComparison,
+};
+use crate::handlebars_helpers::create_new_handlebars;
+use crate::report;
+
+/// A single action that `deploy` would perform, along with the state of the files it assumes
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PlannedOperation {
+    pub action: ActionKind,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// Fingerprint of the target when the plan was made
+    pub target_state: String,
+    /// Fingerprint of the template's cached copy when the plan was made
+    pub cache_state: Option<String>,
+    /// Hash of the rendered template (or copied file) that will be deployed
+    pub rendered: Option<String>,
+    pub symlink: Option<SymbolicTarget>,
+    pub template: Option<TemplateTarget>,
+    pub copy: Option<CopyTarget>,
+    pub hardlink: Option<HardlinkTarget>,
+}
+
+impl PlannedOperation {
+    fn symlink(&self) -> Result<&SymbolicTarget> {
+        self.symlink
+            .as_ref()
+            .context("plan is missing the symlink's options")
+    }
+
+    fn template(&self) -> Result<&TemplateTarget> {
+        self.template
+            .as_ref()
+            .context("plan is missing the template's options")
+    }
+
+    fn copy(&self) -> Result<&CopyTarget> {
+        self.copy
+            .as_ref()
+            .context("plan is missing the copy's options")
+    }
+
+    fn hardlink(&self) -> Result<&HardlinkTarget> {
+        self.hardlink
+            .as_ref()
+            .context("plan is missing the hard link's options")
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Plan {
+    /// Fingerprint of the cache file when the plan was made
+    pub cache_state: String,
+    #[serde(default)]
+    pub operations: Vec<PlannedOperation>,
+}
+
+/// Describes the file at `path` - whether it's missing, a directory, a symlink (and where it
+/// points) or a file (and the hash of its contents)
+fn fingerprint(path: &Path) -> Result<String> {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok("missing".into()),
+        Err(e) => return Err(e).context("get metadata"),
+    };
+    Ok(if metadata.file_type().is_symlink() {
+        format!(
+            "symlink:{}",
+            fs::read_link(path).context("read symlink")?.display()
+        )
+    } else if metadata.is_dir() {
+        "directory".into()
+    } else {
+        filesystem::hash_file(path)?
+    })
+}
+
+/// Records the operations instead of performing them
+struct PlanningActionRunner<'a> {
+    fs: DryRunFilesystem,
+    handlebars: &'a Handlebars<'a>,
+    variables: &'a Variables,
+    operations: Vec<PlannedOperation>,
+}
+
+impl<'a> PlanningActionRunner<'a> {
+    fn record(
+        &mut self,
+        action: ActionKind,
+        source: &Path,
+        target: &Path,
+        cache: Option<&Path>,
+    ) -> Result<&mut PlannedOperation> {
+        self.operations.push(PlannedOperation {
+            action,
+            source: source.into(),
+            target: target.into(),
+            target_state: fingerprint(target).context("get state of target")?,
+            cache_state: cache
+                .map(fingerprint)
+                .transpose()
+                .context("get state of cached template")?,
+            rendered: None,
+            symlink: None,
+            template: None,
+            copy: None,
+            hardlink: None,
+        });
+        Ok(self.operations.last_mut().unwrap())
+    }
+
+    fn render(&self, source: &Path, target: &TemplateTarget) -> Result<String> {
+        Ok(filesystem::hash_contents(&render_template(
+            source,
+            target,
+            self.handlebars,
+            self.variables,
+        )?))
+    }
+}
+
+impl<'a> ActionRunner for PlanningActionRunner<'a> {
+    fn delete_symlink(&mut self, source: &Path, target: &Path) -> Result<bool> {
+        self.record(ActionKind::DeleteSymlink, source, target, None)?;
+        Ok(true)
+    }
+
+    fn delete_template(
+        &mut self,
+        source: &Path,
+        cache: &Path,
+        cached: &CachedFile,
+    ) -> Result<bool> {
+        self.record(
+            ActionKind::DeleteTemplate,
+            source,
+            &cached.target,
+            Some(cache),
+        )?;
+        Ok(true)
+    }
+
+    fn create_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool> {
+        self.record(ActionKind::CreateSymlink, source, &target.target, None)?
+            .symlink = Some(target.clone());
+        Ok(true)
+    }
+
+    fn create_template(
+        &mut self,
+        source: &Path,
+        cache: &Path,
+        target: &TemplateTarget,
+        _cached: &mut CachedFile,
+    ) -> Result<bool> {
+        let rendered = self.render(source, target)?;
+        let operation = self.record(
+            ActionKind::CreateTemplate,
+            source,
+            &target.target,
+            Some(cache),
+        )?;
+        operation.rendered = Some(rendered);
+        operation.template = Some(target.clone());
+        Ok(true)
+    }
+
+    fn update_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool> {
+        if self.fs.compare_symlink(source, &target.target)? == SymlinkComparison::Identical {
+            return Ok(true);
+        }
+        self.record(ActionKind::UpdateSymlink, source, &target.target, None)?
+            .symlink = Some(target.clone());
+        Ok(true)
+    }
+
+    fn update_template(
+        &mut self,
+        source: &Path,
+        cache: &Path,
+        target: &TemplateTarget,
+        cached: &mut CachedFile,
+    ) -> Result<bool> {
+        let rendered = self.render(source, target)?;
+        let operation = self.record(
+            ActionKind::UpdateTemplate,
+            source,
+            &target.target,
+            Some(cache),
+        )?;
+        let unchanged = cached.hash == rendered && operation.target_state == rendered;
+        operation.rendered = Some(rendered);
+        operation.template = Some(target.clone());
+        if unchanged && !mode_differs(source, &target.target, target.mode, &mut self.fs) {
+            // Nothing would change
+            self.operations.pop();
+        }
+        Ok(true)
+    }
+
+    fn delete_copy(&mut self, source: &Path, cached: &CachedFile) -> Result<bool> {
+        self.record(ActionKind::DeleteCopy, source, &cached.target, None)?;
+        Ok(true)
+    }
+
+    fn create_copy(
+        &mut self,
+        source: &Path,
+        target: &CopyTarget,
+        _cached: &mut CachedFile,
+    ) -> Result<bool> {
+        let copied = filesystem::hash_file(source).context("hash source file")?;
+        let operation = self.record(ActionKind::CreateCopy, source, &target.target, None)?;
+        operation.rendered = Some(copied);
+        operation.copy = Some(target.clone());
+        Ok(true)
+    }
+
+    fn update_copy(
+        &mut self,
+        source: &Path,
+        target: &CopyTarget,
+        cached: &mut CachedFile,
+    ) -> Result<bool> {
+        let copied = filesystem::hash_file(source).context("hash source file")?;
+        let operation = self.record(ActionKind::UpdateCopy, source, &target.target, None)?;
+        let unchanged = cached.hash == copied && operation.target_state == copied;
+        operation.rendered = Some(copied);
+        operation.copy = Some(target.clone());
+        if unchanged && !mode_differs(source, &target.target, target.mode, &mut self.fs) {
+            // Nothing would change
+            self.operations.pop();
+        }
+        Ok(true)
+    }
+
+    fn delete_hardlink(&mut self, source: &Path, target: &Path) -> Result<bool> {
+        self.record(ActionKind::DeleteHardlink, source, target, None)?;
+        Ok(true)
+    }
+
+    fn create_hardlink(&mut self, source: &Path, target: &HardlinkTarget) -> Result<bool> {
+        self.record(ActionKind::CreateHardlink, source, &target.target, None)?
+            .hardlink = Some(target.clone());
+        Ok(true)
+    }
+
+    fn update_hardlink(&mut self, source: &Path, target: &HardlinkTarget) -> Result<bool> {
+        if self.fs.compare_hardlink(source, &target.target)? == HardlinkComparison::Identical {
+            return Ok(true);
+        }
+        self.record(ActionKind::UpdateHardlink, source, &target.target, None)?
+            .hardlink = Some(target.clone());
+        Ok(true)
+    }
+}
+
+/// Works out what `deploy` would do, prints it and saves it to `out` if given.
+/// Returns true if some operations could not be planned
+pub fn plan(opt: &Options, out: Option<&Path>) -> Result<bool> {
+    let (mut config, mut cache) = load_configuration_and_cache(opt)?;
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+    let desired = desired_files(config.files).context("sort files by how they're deployed")?;
+
+    let cache_state = fingerprint(&opt.cache_file).context("get state of cache file")?;
+
+    let mut runner = PlanningActionRunner {
+        fs: DryRunFilesystem::new().with_root(opt.root.clone()),
+        handlebars: &handlebars,
+        variables: &config.variables,
+        operations: Vec::new(),
+    };
+    let (_, failure) = run_deploy(&mut runner, &desired, &mut cache, opt);
+    if failure.is_some() {
+        error!("Some operations could not be planned, so no plan was saved.");
+        return Ok(true);
+    }
+
+    let plan = Plan {
+        cache_state,
+        operations: runner.operations,
+    };
+    print_plan(&plan);
+
+    if let Some(out) = out {
+        filesystem::save_file(out, &plan).context("save plan")?;
+        info!("Saved plan to {:?}", out);
+    }
+
+    Ok(false)
+}
+
+fn print_plan(plan: &Plan) {
+    for operation in &plan.operations {
+        let marker = match operation.action {
+            ActionKind::CreateSymlink
+            | ActionKind::CreateTemplate
+            | ActionKind::CreateCopy
+            | ActionKind::CreateHardlink => "[+]".green(),
+            ActionKind::DeleteSymlink
+            | ActionKind::DeleteTemplate
+            | ActionKind::DeleteCopy
+            | ActionKind::DeleteHardlink => "[-]".red(),
+            ActionKind::UpdateSymlink
+            | ActionKind::UpdateTemplate
+            | ActionKind::UpdateCopy
+            | ActionKind::UpdateHardlink => "[~]".yellow(),
+        };
+        println!(
+            "{} {} {:?} -> {:?}",
+            marker, operation.action, operation.source, operation.target
+        );
+    }
+
+    if plan.operations.is_empty() {
+        println!("Nothing to do.");
+    }
+}
+
+/// Performs the operations saved in `plan_file` by `plan`, refusing to do anything if the files
+/// they affect changed since.
+/// Returns the status to exit with if an error was printed
+pub fn apply(opt: &Options, plan_file: &Path) -> Result<ExitStatus> {
+    report::with_summary(opt, |summary| {
+        let plan: Plan = filesystem::load_file(plan_file)
+            .context("load plan")?
+            .with_context(|| format!("plan file {:?} does not exist", plan_file))?;
+
+        let (mut config, cache) = load_configuration_and_cache(opt)?;
+        let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+
+        let changes = find_changes(&plan, &handlebars, &config.variables, opt)?;
+        if !changes.is_empty() {
+            for change in &changes {
+                error!("{}", change);
+            }
+            anyhow::bail!("files changed since the plan was made. Run `dotter plan` again");
+        }
+
+        deploy_with(
+            opt,
+            summary,
+            &handlebars,
+            &config.variables,
+            &config.hooks,
+            cache,
+            |runner, cache| run_plan(runner, &plan.operations, cache, opt),
+        )
+    })
+}
+
+/// Describes every difference between the state the plan assumed and the current one
+fn find_changes(
+    plan: &Plan,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+    opt: &Options,
+) -> Result<Vec<String>> {
+    let mut changes = Vec::new();
+
+    if fingerprint(&opt.cache_file).context("get state of cache file")? != plan.cache_state {
+        changes.push(format!("Cache file {:?} changed", opt.cache_file));
+    }
+
+    for operation in &plan.operations {
+        if fingerprint(&operation.target).context("get state of target")? != operation.target_state
+        {
+            changes.push(format!(
+                "Target {:?} of {} {:?} changed",
+                operation.target, operation.action, operation.source
+            ));
+        }
+
+        if let Some(ref cache_state) = operation.cache_state {
+            let cache = opt.cache_directory.join(&operation.source);
+            if &fingerprint(&cache).context("get state of cached template")? != cache_state {
+                changes.push(format!(
+                    "Cached copy {:?} of {} {:?} changed",
+                    cache, operation.action, operation.source
+                ));
+            }
+        }
+
+        if let Some(ref rendered) = operation.rendered {
+            let current = if operation.copy.is_some() {
+                filesystem::hash_file(&operation.source)
+                    .with_context(|| format!("hash source file {:?}", operation.source))?
+            } else {
+                let current = render_template(
+                    &operation.source,
+                    operation.template()?,
+                    handlebars,
+                    variables,
+                )
+                .with_context(|| format!("render template {:?}", operation.source))?;
+                filesystem::hash_contents(&current)
+            };
+            if &current != rendered {
+                changes.push(format!(
+                    "Rendered contents of {} {:?} changed",
+                    operation.action, operation.source
+                ));
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+fn run_plan<A: ActionRunner + ?Sized>(
+    runner: &mut A,
+    operations: &[PlannedOperation],
+    cache: &mut Cache,
+    opt: &Options,
+) -> (bool, Option<ExitStatus>) {
+    let mut suggest_force = false;
+    let mut failure = None;
+
+    for operation in operations {
+        let source = &operation.source;
+        let target = &operation.target;
+        let cache_file = opt.cache_directory.join(source);
+        let context = || format!("{} {:?} -> {:?}", operation.action, source, target);
+        match operation.action {
+            ActionKind::DeleteSymlink => execute_action(
+                runner.delete_symlink(source, target),
+                || {
+                    cache.symlinks.remove(source);
+                },
+                context,
+                &mut suggest_force,
+                &mut failure,
+            ),
+            ActionKind::DeleteTemplate => execute_action(
+                cache
+                    .templates
+                    .get(source)
+                    .context("template is missing from the cache")
+                    .and_then(|cached| runner.delete_template(source, &cache_file, cached)),
+                || {
+                    cache.templates.remove(source);
+                },
+                context,
+                &mut suggest_force,
+                &mut failure,
+            ),
+            ActionKind::CreateSymlink => execute_action(
+                operation
+                    .symlink()
+                    .and_then(|symlink| runner.create_symlink(source, symlink)),
+                || {
+                    cache.symlinks.insert(source.clone(), target.clone());
+                },
+                context,
+                &mut suggest_force,
+                &mut failure,
+            ),
+            ActionKind::CreateTemplate => {
+                let mut cached = CachedFile::new(target.clone(), String::new(), None);
+                execute_action(
+                    operation.template().and_then(|template| {
+                        runner.create_template(source, &cache_file, template, &mut cached)
+                    }),
+                    || {
+                        cache.templates.insert(source.clone(), cached);
+                    },
+                    context,
+                    &mut suggest_force,
+                    &mut failure,
+                )
+            }
+            ActionKind::UpdateSymlink => execute_action(
+                operation
+                    .symlink()
+                    .and_then(|symlink| runner.update_symlink(source, symlink)),
+                || (),
+                context,
+                &mut suggest_force,
+                &mut failure,
+            ),
+            ActionKind::UpdateTemplate => {
+                let mut cached = cache.templates.get(source).cloned();
+                execute_action(
+                    operation.template().and_then(|template| {
+                        let cached = cached
+                            .as_mut()
+                            .context("template is missing from the cache")?;
+                        runner.update_template(source, &cache_file, template, cached)
+                    }),
+                    || {
+                        if let Some(cached) = cached {
+                            cache.templates.insert(source.clone(), cached);
+                        }
+                    },
+                    context,
+                    &mut suggest_force,
+                    &mut failure,
+                )
+            }
+            ActionKind::DeleteCopy => execute_action(
+                cache
+                    .copies
+                    .get(source)
+                    .context("copy is missing from the cache")
+                    .and_then(|cached| runner.delete_copy(source, cached)),
+                || {
+                    cache.copies.remove(source);
+                },
+                context,
+                &mut suggest_force,
+                &mut failure,
+            ),
+            ActionKind::CreateCopy => {
+                let mut cached = CachedFile::new(target.clone(), String::new(), None);
+                execute_action(
+                    operation
+                        .copy()
+                        .and_then(|copy| runner.create_copy(source, copy, &mut cached)),
+                    || {
+                        cache.copies.insert(source.clone(), cached);
+                    },
+                    context,
+                    &mut suggest_force,
+                    &mut failure,
+                )
+            }
+            ActionKind::UpdateCopy => {
+                let mut cached = cache.copies.get(source).cloned();
+                execute_action(
+                    operation.copy().and_then(|copy| {
+                        let cached = cached.as_mut().context("copy is missing from the cache")?;
+                        runner.update_copy(source, copy, cached)
+                    }),
+                    || {
+                        if let Some(cached) = cached {
+                            cache.copies.insert(source.clone(), cached);
+                        }
+                    },
+                    context,
+                    &mut suggest_force,
+                    &mut failure,
+                )
+            }
+            ActionKind::DeleteHardlink => execute_action(
+                runner.delete_hardlink(source, target),
+                || {
+                    cache.hardlinks.remove(source);
+                },
+                context,
+                &mut suggest_force,
+                &mut failure,
+            ),
+            ActionKind::CreateHardlink => execute_action(
+                operation
+                    .hardlink()
+                    .and_then(|hardlink| runner.create_hardlink(source, hardlink)),
+                || {
+                    cache.hardlinks.insert(source.clone(), target.clone());
+                },
+                context,
+                &mut suggest_force,
+                &mut failure,
+            ),
+            ActionKind::UpdateHardlink => execute_action(
+                operation
+                    .hardlink()
+                    .and_then(|hardlink| runner.update_hardlink(source, hardlink)),
+                || (),
+                context,
+                &mut suggest_force,
+                &mut failure,
+            ),
+        }
+    }
+
+    (suggest_force, failure)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::actions::MockActionRunner;
+
+    use super::*;
+
+    use mockall::predicate::*;
+
+    fn operation(action: ActionKind, source: &str, target: &str) -> PlannedOperation {
+        PlannedOperation {
+            action,
+            source: source.into(),
+            target: target.into(),
+            target_state: "missing".into(),
+            cache_state: None,
+            rendered: None,
+            symlink: None,
+            template: None,
+            copy: None,
+            hardlink: None,
+        }
+    }
+
+    #[test]
+    fn run_plan_in_order() {
+        let b_out: TemplateTarget = "b_out".into();
+        let operations = vec![
+            operation(ActionKind::DeleteSymlink, "a_in", "a_out"),
+            PlannedOperation {
+                template: Some(b_out.clone()),
+                ..operation(ActionKind::CreateTemplate, "b_in", "b_out")
+            },
+        ];
+
+        let mut runner = MockActionRunner::new();
+        let mut seq = mockall::Sequence::new();
+        let mut cache = Cache::default();
+        cache.symlinks.insert("a_in".into(), "a_out".into());
+
+        runner
+            .expect_delete_symlink()
+            .times(1)
+            .with(eq(Path::new("a_in")), eq(Path::new("a_out")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(true));
+        runner
+            .expect_create_template()
+            .times(1)
+            .with(
+                eq(Path::new("b_in")),
+                eq(Path::new("cache/b_in")),
+                eq(b_out),
+                always(),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, cached| {
+                cached.hash = "rendered".into();
+                Ok(true)
+            });
+
+        let (suggest_force, failure) = run_plan(
+            &mut runner,
+            &operations,
+            &mut cache,
+            &Options {
+                cache_directory: "cache".into(),
+                ..Options::default()
+            },
+        );
+
+        assert!(!suggest_force);
+        assert_eq!(failure, None);
+        assert!(cache.symlinks.is_empty());
+        assert_eq!(
+            cache.templates.get(Path::new("b_in")),
+            Some(&CachedFile::new("b_out".into(), "rendered".into(), None))
+        );
+    }
+
+    #[test]
+    fn missing_options_is_an_error() {
+        let operations = vec![operation(ActionKind::CreateSymlink, "a_in", "a_out")];
+        let mut runner = MockActionRunner::new();
+        let mut cache = Cache::default();
+
+        let (_, failure) = run_plan(&mut runner, &operations, &mut cache, &Options::default());
+
+        assert_eq!(failure, Some(ExitStatus::Error));
+        assert!(cache.symlinks.is_empty());
+    }
+}
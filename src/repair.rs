@@ -0,0 +1,312 @@
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::actions::keeps_copy;
+use crate::args::Options;
+use crate::config::{self, Cache, CachedFile};
+use crate::deploy::desired_files;
+use crate::difference::render_template;
+use crate::exit::{ExitStatus, Failure};
+use crate::filesystem::{
+    self, DryRunFilesystem, Filesystem, HardlinkComparison, SymlinkComparison, TemplateComparison,
+};
+use crate::handlebars_helpers::create_new_handlebars;
+
+struct Entry {
+    kind: &'static str,
+    source: PathBuf,
+    target: PathBuf,
+    reason: Option<String>,
+}
+
+#[derive(Default)]
+struct Report {
+    recovered: Vec<Entry>,
+    not_deployed: Vec<Entry>,
+    unreconciled: Vec<Entry>,
+}
+
+impl Report {
+    fn recovered(&mut self, kind: &'static str, source: &Path, target: &Path) {
+        self.recovered.push(Entry {
+            kind,
+            source: source.into(),
+            target: target.into(),
+            reason: None,
+        });
+    }
+
+    fn not_deployed(&mut self, kind: &'static str, source: &Path, target: &Path) {
+        self.not_deployed.push(Entry {
+            kind,
+            source: source.into(),
+            target: target.into(),
+            reason: None,
+        });
+    }
+
+    fn unreconciled(
+        &mut self,
+        kind: &'static str,
+        source: &Path,
+        target: &Path,
+        reason: impl ToString,
+    ) {
+        self.unreconciled.push(Entry {
+            kind,
+            source: source.into(),
+            target: target.into(),
+            reason: Some(reason.to_string()),
+        });
+    }
+}
+
+/// Rebuilds the cache from the configuration and what is actually deployed: symlinks and hard
+/// links that point to their source, and templates and copies whose target has the contents they
+/// would be deployed with now. Anything else is left out of the cache, so the next deploy treats
+/// it as a new file.
+/// Returns true if some files could not be reconciled
+pub fn repair(opt: &Options) -> Result<bool> {
+    let mut config = config::load_configuration(
+        &opt.local_config,
+        &opt.global_config,
+        None,
+        opt.root.as_deref(),
+    )
+    .context(Failure::new(ExitStatus::Config, "get a configuration"))?;
+    let handlebars = create_new_handlebars(&mut config)
+        .context(Failure::new(ExitStatus::Config, "initialize handlebars"))?;
+    let desired = desired_files(config.files).context("sort files by how they're deployed")?;
+
+    // Only used to inspect the targets, so it never touches the disk
+    let mut fs = DryRunFilesystem::new().with_root(opt.root.clone());
+    let mut cache = Cache::default();
+    let mut report = Report::default();
+    // Renders of templates that can be merged, to be kept in the cache directory
+    let mut renders = Vec::new();
+
+    for (source, target) in &desired.symlinks {
+        let comparison = fs
+            .compare_symlink(source, &target.target)
+            .with_context(|| format!("compare symlink {:?} -> {:?}", source, target.target))?;
+        match comparison {
+            SymlinkComparison::Identical => {
+                cache.symlinks.insert(source.clone(), target.target.clone());
+                report.recovered("symlink", source, &target.target);
+            }
+            SymlinkComparison::OnlySourceExists => {
+                report.not_deployed("symlink", source, &target.target)
+            }
+            _ => report.unreconciled("symlink", source, &target.target, comparison),
+        }
+    }
+
+    for (source, target) in &desired.templates {
+        let rendered = match render_template(source, target, &handlebars, &config.variables) {
+            Ok(rendered) => rendered,
+            Err(e) => {
+                report.unreconciled("template", source, &target.target, format!("{:#}", e));
+                continue;
+            }
+        };
+        let hash = filesystem::hash_contents(&rendered);
+        let comparison = fs
+            .compare_contents(&target.target, Some(&hash), None)
+            .with_context(|| format!("compare template {:?} -> {:?}", source, target.target))?;
+        match comparison {
+            TemplateComparison::Identical => {
+                let stamp = filesystem::stamp(&target.target);
+                cache.templates.insert(
+                    source.clone(),
+                    CachedFile::new(target.target.clone(), hash, stamp),
+                );
+                if keeps_copy(target, opt.merge) {
+                    renders.push((source.clone(), rendered));
+                }
+                report.recovered("template", source, &target.target);
+            }
+            TemplateComparison::OnlyCacheExists | TemplateComparison::BothMissing => {
+                report.not_deployed("template", source, &target.target)
+            }
+            TemplateComparison::Changed | TemplateComparison::OnlyTargetExists => report
+                .unreconciled(
+                    "template",
+                    source,
+                    &target.target,
+                    "target's contents don't match the rendered source",
+                ),
+            TemplateComparison::TargetNotRegularFile => {
+                report.unreconciled("template", source, &target.target, comparison)
+            }
+        }
+    }
+
+    for (source, target) in &desired.hardlinks {
+        let comparison = fs
+            .compare_hardlink(source, &target.target)
+            .with_context(|| format!("compare hardlink {:?} -> {:?}", source, target.target))?;
+        match comparison {
+            HardlinkComparison::Identical => {
+                cache
+                    .hardlinks
+                    .insert(source.clone(), target.target.clone());
+                report.recovered("hardlink", source, &target.target);
+            }
+            HardlinkComparison::OnlySourceExists => {
+                report.not_deployed("hardlink", source, &target.target)
+            }
+            _ => report.unreconciled("hardlink", source, &target.target, comparison),
+        }
+    }
+
+    for (source, target) in &desired.copies {
+        let hash = match filesystem::hash_file(source) {
+            Ok(hash) => hash,
+            Err(e) => {
+                report.unreconciled("copy", source, &target.target, format!("{:#}", e));
+                continue;
+            }
+        };
+        let comparison = fs
+            .compare_contents(&target.target, Some(&hash), None)
+            .with_context(|| format!("compare copy {:?} -> {:?}", source, target.target))?;
+        match comparison {
+            TemplateComparison::Identical => {
+                let stamp = filesystem::stamp(&target.target);
+                cache.copies.insert(
+                    source.clone(),
+                    CachedFile::new(target.target.clone(), hash, stamp),
+                );
+                report.recovered("copy", source, &target.target);
+            }
+            TemplateComparison::OnlyCacheExists | TemplateComparison::BothMissing => {
+                report.not_deployed("copy", source, &target.target)
+            }
+            TemplateComparison::Changed | TemplateComparison::OnlyTargetExists => report
+                .unreconciled(
+                    "copy",
+                    source,
+                    &target.target,
+                    "target's contents don't match the source",
+                ),
+            TemplateComparison::TargetNotRegularFile => {
+                report.unreconciled("copy", source, &target.target, comparison)
+            }
+        }
+    }
+
+    // Files that were deployed but aren't configured anymore can't be checked, so they're only
+    // reported in case their targets should be removed by hand
+    match config::load_cache(&opt.cache_file, &opt.cache_directory, false) {
+        Ok(Some(old)) => {
+            let forgotten = "not in the configuration anymore, so it was left out";
+            for (source, target) in &old.symlinks {
+                if !desired.symlinks.contains_key(source) {
+                    report.unreconciled("symlink", source, target, forgotten);
+                }
+            }
+            for (source, template) in &old.templates {
+                if !desired.templates.contains_key(source) {
+                    report.unreconciled("template", source, &template.target, forgotten);
+                }
+            }
+            for (source, target) in &old.hardlinks {
+                if !desired.hardlinks.contains_key(source) {
+                    report.unreconciled("hardlink", source, target, forgotten);
+                }
+            }
+            for (source, copy) in &old.copies {
+                if !desired.copies.contains_key(source) {
+                    report.unreconciled("copy", source, &copy.target, forgotten);
+                }
+            }
+        }
+        Ok(None) => debug!("No cache to repair, creating it from scratch"),
+        Err(e) => warn!(
+            "Could not read the old cache, creating it from scratch: {:#}",
+            e
+        ),
+    }
+
+    if !opt.dry_run {
+        save_repaired_cache(opt, &cache, renders, &desired.templates)?;
+    }
+
+    print_report(&report);
+
+    Ok(!report.unreconciled.is_empty())
+}
+
+fn save_repaired_cache(
+    opt: &Options,
+    cache: &Cache,
+    renders: Vec<(PathBuf, Vec<u8>)>,
+    templates: &std::collections::BTreeMap<PathBuf, config::TemplateTarget>,
+) -> Result<()> {
+    if opt.cache_file.exists() {
+        let mut backup = opt.cache_file.as_os_str().to_owned();
+        backup.push(".bak");
+        let backup = PathBuf::from(backup);
+        fs::copy(&opt.cache_file, &backup)
+            .with_context(|| format!("back up old cache to {:?}", backup))?;
+        info!("Backed up the old cache to {:?}", backup);
+    }
+    filesystem::save_file(&opt.cache_file, cache).context("save cache")?;
+
+    // Stale renders would be taken as the base of the next merge, so they're all replaced
+    for source in templates.keys() {
+        let copy = opt.cache_directory.join(source);
+        if copy.is_file() {
+            fs::remove_file(&copy).with_context(|| format!("remove stale render {:?}", copy))?;
+        }
+    }
+    for (source, rendered) in renders {
+        let copy = opt.cache_directory.join(&source);
+        fs::create_dir_all(copy.parent().context("get parent of cached render")?)
+            .context("create parent for cached render")?;
+        filesystem::write_atomically(&copy, &rendered, None)
+            .with_context(|| format!("write render to {:?}", copy))?;
+    }
+
+    Ok(())
+}
+
+fn print_report(report: &Report) {
+    let sections = [
+        ("Recovered", &report.recovered),
+        ("Not deployed", &report.not_deployed),
+        ("Could not reconcile", &report.unreconciled),
+    ];
+    for (title, entries) in sections {
+        if entries.is_empty() {
+            continue;
+        }
+        let header = format!("{} ({}):", title, entries.len());
+        let header = match title {
+            "Recovered" => header.green(),
+            "Not deployed" => header.yellow(),
+            _ => header.red(),
+        };
+        println!("{}", header);
+        for entry in entries {
+            match &entry.reason {
+                Some(reason) => println!(
+                    "    {:<8} {:?} -> {:?}: {}",
+                    entry.kind, entry.source, entry.target, reason
+                ),
+                None => println!(
+                    "    {:<8} {:?} -> {:?}",
+                    entry.kind, entry.source, entry.target
+                ),
+            }
+        }
+        println!();
+    }
+
+    if !report.unreconciled.is_empty() {
+        println!("Files that could not be reconciled were left out of the cache, so deploys won't touch their targets unless --force is used (which backs them up first).");
+    }
+}
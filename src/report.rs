@@ -0,0 +1,533 @@
+use anyhow::Result;
+use handlebars::Handlebars;
+use serde::Serialize;
+
+use std::path::{Path, PathBuf};
+
+use crate::actions::{mode_differs, ActionKind, ActionRunner, RealActionRunner};
+use crate::args::{Options, OutputFormat};
+use crate::config::{
+    CachedFile, CopyTarget, HardlinkTarget, Mode, SymbolicTarget, TemplateTarget, Variables,
+};
+use crate::difference::{diff_contents, diff_nonempty, hunkify_diff, render_contents};
+use crate::filesystem::{self, HardlinkComparison, SymlinkComparison, TemplateComparison};
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Performed,
+    /// The target was already up to date
+    Unchanged,
+    Skipped,
+    Failed,
+}
+
+/// Consecutive changed lines with some context around them
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub new_start: usize,
+    /// Prefixed with "-" if removed, "+" if added or " " if unchanged
+    pub lines: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActionEvent {
+    pub operation: ActionKind,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// State of the target before the action, if it could be determined
+    pub state: Option<String>,
+    pub outcome: Outcome,
+    /// Why the action was skipped or failed
+    pub reason: Option<String>,
+    /// Changes to the target's contents, for templates
+    pub hunks: Vec<Hunk>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Summary {
+    pub dry_run: bool,
+    pub performed: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub rolled_back: bool,
+    pub success: bool,
+    #[serde(skip)]
+    emitted: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Action(&'a ActionEvent),
+    Summary(&'a Summary),
+}
+
+fn emit(event: Event) {
+    // Serializing these can't fail - there are no maps with non-string keys
+    println!(
+        "{}",
+        serde_json::to_string(&event).expect("serialize event")
+    );
+}
+
+impl Summary {
+    pub fn new(opt: &Options) -> Summary {
+        Summary {
+            dry_run: opt.dry_run,
+            ..Summary::default()
+        }
+    }
+
+    /// Prints the summary if JSON output was requested and it wasn't printed already
+    pub fn emit(&mut self, opt: &Options, success: bool) {
+        if self.emitted {
+            return;
+        }
+        self.emitted = true;
+        self.success = success;
+        if opt.output == OutputFormat::Json {
+            emit(Event::Summary(self));
+        }
+    }
+}
+
+/// Runs `f` with a summary to report its actions to, and prints the summary as failed if `f`
+/// returns an error before doing so itself (e.g. because the configuration couldn't be loaded)
+pub fn with_summary<T>(opt: &Options, f: impl FnOnce(&mut Summary) -> Result<T>) -> Result<T> {
+    let mut summary = Summary::new(opt);
+    let result = f(&mut summary);
+    if result.is_err() {
+        summary.emit(opt, false);
+    }
+    result
+}
+
+/// Wraps another runner, printing a JSON event for every action it performs.
+/// Targets are inspected through the filesystem of the wrapped runner.
+pub struct ReportingActionRunner<'a, 'b> {
+    inner: &'a mut RealActionRunner<'b>,
+    handlebars: &'a Handlebars<'a>,
+    variables: &'a Variables,
+    diff_context_lines: usize,
+    summary: &'a mut Summary,
+}
+
+impl<'a, 'b> ReportingActionRunner<'a, 'b> {
+    pub fn new(
+        inner: &'a mut RealActionRunner<'b>,
+        handlebars: &'a Handlebars<'a>,
+        variables: &'a Variables,
+        diff_context_lines: usize,
+        summary: &'a mut Summary,
+    ) -> ReportingActionRunner<'a, 'b> {
+        ReportingActionRunner {
+            inner,
+            handlebars,
+            variables,
+            diff_context_lines,
+            summary,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn report(
+        &mut self,
+        operation: ActionKind,
+        source: &Path,
+        target: &Path,
+        state: Option<String>,
+        unchanged: bool,
+        hunks: Vec<Hunk>,
+        result: Result<bool>,
+    ) -> Result<bool> {
+        let (outcome, reason) = match result {
+            Ok(true) if unchanged => (Outcome::Unchanged, None),
+            Ok(true) => (Outcome::Performed, None),
+            Ok(false) => (Outcome::Skipped, state.clone()),
+            Err(ref e) => (Outcome::Failed, Some(format!("{:#}", e))),
+        };
+        match outcome {
+            Outcome::Performed => self.summary.performed += 1,
+            Outcome::Unchanged => self.summary.unchanged += 1,
+            Outcome::Skipped => self.summary.skipped += 1,
+            Outcome::Failed => self.summary.failed += 1,
+        }
+        emit(Event::Action(&ActionEvent {
+            operation,
+            source: source.into(),
+            target: target.into(),
+            state,
+            outcome,
+            reason,
+            hunks,
+        }));
+        result
+    }
+
+    fn symlink_state(&mut self, source: &Path, target: &Path) -> Option<SymlinkComparison> {
+        self.inner.filesystem().compare_symlink(source, target).ok()
+    }
+
+    fn contents_state(&mut self, target: &Path, hash: Option<&str>) -> Option<TemplateComparison> {
+        self.inner
+            .filesystem()
+            .compare_contents(target, hash, None)
+            .ok()
+    }
+
+    fn hardlink_state(&mut self, source: &Path, target: &Path) -> Option<HardlinkComparison> {
+        self.inner
+            .filesystem()
+            .compare_hardlink(source, target)
+            .ok()
+    }
+
+    fn mode_differs(&mut self, source: &Path, target: &Path, mode: Option<Mode>) -> bool {
+        mode_differs(source, target, mode, self.inner.filesystem())
+    }
+
+    fn source_hash(&mut self, source: &Path) -> Option<String> {
+        let contents = self.inner.filesystem().read(source).ok()?;
+        Some(filesystem::hash_contents(&contents))
+    }
+
+    /// Changes that deploying the template would make to its target
+    fn template_hunks(&mut self, source: &Path, target: &TemplateTarget) -> Vec<Hunk> {
+        let (handlebars, variables) = (self.handlebars, self.variables);
+        let fs = self.inner.filesystem();
+        let rendered = match fs
+            .read(source)
+            .and_then(|contents| render_contents(source, contents, target, handlebars, variables))
+        {
+            Ok(rendered) => rendered,
+            // The action itself will report the error
+            Err(_) => return Vec::new(),
+        };
+        let current = fs.read(&target.target).unwrap_or_default();
+        let diff = diff_contents(&current, &rendered);
+        if !diff_nonempty(&diff) {
+            return Vec::new();
+        }
+        hunkify_diff(diff, self.diff_context_lines)
+            .into_iter()
+            .map(|(old_start, new_start, lines)| Hunk {
+                old_start,
+                new_start,
+                lines: lines
+                    .into_iter()
+                    .map(|line| match line {
+                        diff::Result::Left(l) => format!("-{}", l),
+                        diff::Result::Both(l, _) => format!(" {}", l),
+                        diff::Result::Right(r) => format!("+{}", r),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+impl<'a, 'b> ActionRunner for ReportingActionRunner<'a, 'b> {
+    fn delete_symlink(&mut self, source: &Path, target: &Path) -> Result<bool> {
+        let state = self.symlink_state(source, target);
+        let result = self.inner.delete_symlink(source, target);
+        self.report(
+            ActionKind::DeleteSymlink,
+            source,
+            target,
+            state.as_ref().map(|s| s.to_string()),
+            false,
+            Vec::new(),
+            result,
+        )
+    }
+
+    fn delete_template(
+        &mut self,
+        source: &Path,
+        cache: &Path,
+        cached: &CachedFile,
+    ) -> Result<bool> {
+        let state = self.contents_state(&cached.target, Some(&cached.hash));
+        let result = self.inner.delete_template(source, cache, cached);
+        self.report(
+            ActionKind::DeleteTemplate,
+            source,
+            &cached.target,
+            state.as_ref().map(|s| s.to_string()),
+            false,
+            Vec::new(),
+            result,
+        )
+    }
+
+    fn create_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool> {
+        let state = self.symlink_state(source, &target.target);
+        let result = self.inner.create_symlink(source, target);
+        self.report(
+            ActionKind::CreateSymlink,
+            source,
+            &target.target,
+            state.as_ref().map(|s| s.to_string()),
+            state == Some(SymlinkComparison::Identical),
+            Vec::new(),
+            result,
+        )
+    }
+
+    fn create_template(
+        &mut self,
+        source: &Path,
+        cache: &Path,
+        target: &TemplateTarget,
+        cached: &mut CachedFile,
+    ) -> Result<bool> {
+        let state = self.contents_state(&target.target, None);
+        let hunks = self.template_hunks(source, target);
+        let result = self.inner.create_template(source, cache, target, cached);
+        self.report(
+            ActionKind::CreateTemplate,
+            source,
+            &target.target,
+            state.as_ref().map(|s| s.to_string()),
+            false,
+            hunks,
+            result,
+        )
+    }
+
+    fn update_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool> {
+        let state = self.symlink_state(source, &target.target);
+        let result = self.inner.update_symlink(source, target);
+        self.report(
+            ActionKind::UpdateSymlink,
+            source,
+            &target.target,
+            state.as_ref().map(|s| s.to_string()),
+            state == Some(SymlinkComparison::Identical),
+            Vec::new(),
+            result,
+        )
+    }
+
+    fn update_template(
+        &mut self,
+        source: &Path,
+        cache: &Path,
+        target: &TemplateTarget,
+        cached: &mut CachedFile,
+    ) -> Result<bool> {
+        let state = self.contents_state(&target.target, Some(&cached.hash));
+        let hunks = self.template_hunks(source, target);
+        let unchanged = state == Some(TemplateComparison::Identical)
+            && hunks.is_empty()
+            && !self.mode_differs(source, &target.target, target.mode);
+        let result = self.inner.update_template(source, cache, target, cached);
+        self.report(
+            ActionKind::UpdateTemplate,
+            source,
+            &target.target,
+            state.as_ref().map(|s| s.to_string()),
+            unchanged,
+            hunks,
+            result,
+        )
+    }
+
+    fn delete_copy(&mut self, source: &Path, cached: &CachedFile) -> Result<bool> {
+        let state = self.contents_state(&cached.target, Some(&cached.hash));
+        let result = self.inner.delete_copy(source, cached);
+        self.report(
+            ActionKind::DeleteCopy,
+            source,
+            &cached.target,
+            state.as_ref().map(|s| s.to_string()),
+            false,
+            Vec::new(),
+            result,
+        )
+    }
+
+    fn create_copy(
+        &mut self,
+        source: &Path,
+        target: &CopyTarget,
+        cached: &mut CachedFile,
+    ) -> Result<bool> {
+        // Compared against the source, so that it's identical if the target already has its contents
+        let copied = self.source_hash(source);
+        let state = self.contents_state(&target.target, copied.as_deref());
+        let result = self.inner.create_copy(source, target, cached);
+        self.report(
+            ActionKind::CreateCopy,
+            source,
+            &target.target,
+            state.as_ref().map(|s| s.to_string()),
+            state == Some(TemplateComparison::Identical),
+            Vec::new(),
+            result,
+        )
+    }
+
+    fn update_copy(
+        &mut self,
+        source: &Path,
+        target: &CopyTarget,
+        cached: &mut CachedFile,
+    ) -> Result<bool> {
+        let state = self.contents_state(&target.target, Some(&cached.hash));
+        let unchanged = state == Some(TemplateComparison::Identical)
+            && self.source_hash(source).as_ref() == Some(&cached.hash)
+            && !self.mode_differs(source, &target.target, target.mode);
+        let result = self.inner.update_copy(source, target, cached);
+        self.report(
+            ActionKind::UpdateCopy,
+            source,
+            &target.target,
+            state.as_ref().map(|s| s.to_string()),
+            unchanged,
+            Vec::new(),
+            result,
+        )
+    }
+
+    fn delete_hardlink(&mut self, source: &Path, target: &Path) -> Result<bool> {
+        let state = self.hardlink_state(source, target);
+        let result = self.inner.delete_hardlink(source, target);
+        self.report(
+            ActionKind::DeleteHardlink,
+            source,
+            target,
+            state.as_ref().map(|s| s.to_string()),
+            false,
+            Vec::new(),
+            result,
+        )
+    }
+
+    fn create_hardlink(&mut self, source: &Path, target: &HardlinkTarget) -> Result<bool> {
+        let state = self.hardlink_state(source, &target.target);
+        let result = self.inner.create_hardlink(source, target);
+        self.report(
+            ActionKind::CreateHardlink,
+            source,
+            &target.target,
+            state.as_ref().map(|s| s.to_string()),
+            state == Some(HardlinkComparison::Identical),
+            Vec::new(),
+            result,
+        )
+    }
+
+    fn update_hardlink(&mut self, source: &Path, target: &HardlinkTarget) -> Result<bool> {
+        let state = self.hardlink_state(source, &target.target);
+        let result = self.inner.update_hardlink(source, target);
+        self.report(
+            ActionKind::UpdateHardlink,
+            source,
+            &target.target,
+            state.as_ref().map(|s| s.to_string()),
+            state == Some(HardlinkComparison::Identical),
+            Vec::new(),
+            result,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backup::BackupStore;
+    use crate::config::MergeStrategy;
+    use crate::difference::DiffSink;
+    use crate::filesystem::{Filesystem, MemoryFilesystem};
+
+    use super::*;
+
+    #[test]
+    fn summary_counts_outcomes() {
+        let mut fs = MemoryFilesystem::new();
+        fs.write(Path::new("a_in"), "a".into()).unwrap();
+        fs.write(Path::new("a_out"), "not a symlink".into())
+            .unwrap();
+
+        let handlebars = Handlebars::new();
+        let variables = Variables::default();
+        let mut backups = BackupStore::new("backups".into(), Default::default());
+        let mut inner = RealActionRunner::new(
+            &mut fs,
+            &mut backups,
+            &handlebars,
+            &variables,
+            false,
+            MergeStrategy::Refuse,
+            3,
+            DiffSink::Stdout,
+        );
+        let mut summary = Summary::default();
+        let mut runner =
+            ReportingActionRunner::new(&mut inner, &handlebars, &variables, 3, &mut summary);
+
+        assert!(!runner
+            .delete_symlink(Path::new("a_in"), Path::new("a_out"))
+            .unwrap());
+        runner
+            .create_template(
+                Path::new("b_in"),
+                Path::new("cache/b_in"),
+                &"b_out".into(),
+                &mut CachedFile::new("b_out".into(), "hash".into(), None),
+            )
+            .unwrap_err();
+        assert!(runner
+            .create_symlink(Path::new("a_in"), &"c_out".into())
+            .unwrap());
+
+        assert_eq!(summary.performed, 1);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.unchanged, 0);
+    }
+
+    #[test]
+    fn hunks_come_from_the_runners_filesystem() {
+        let mut fs = MemoryFilesystem::new();
+        fs.write(Path::new("conf_in"), "name = {{name}}".into())
+            .unwrap();
+        fs.write(Path::new("conf_out"), "name = old".into())
+            .unwrap();
+        fs.write(Path::new("plain"), "unchanged".into()).unwrap();
+
+        let handlebars = Handlebars::new();
+        let mut variables = Variables::default();
+        variables.insert("name".into(), "dotter".into());
+        let mut backups = BackupStore::new("backups".into(), Default::default());
+        let mut inner = RealActionRunner::new(
+            &mut fs,
+            &mut backups,
+            &handlebars,
+            &variables,
+            false,
+            MergeStrategy::Refuse,
+            3,
+            DiffSink::Stdout,
+        );
+        let mut summary = Summary::default();
+        let mut runner =
+            ReportingActionRunner::new(&mut inner, &handlebars, &variables, 3, &mut summary);
+
+        let hunks = runner.template_hunks(Path::new("conf_in"), &"conf_out".into());
+        assert_eq!(
+            hunks,
+            vec![Hunk {
+                old_start: 1,
+                new_start: 1,
+                lines: vec!["-name = old".into(), "+name = dotter".into()],
+            }]
+        );
+        assert!(runner
+            .template_hunks(Path::new("plain"), &"plain".into())
+            .is_empty());
+    }
+}
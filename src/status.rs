@@ -0,0 +1,403 @@
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use handlebars::Handlebars;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::actions::mode_differs;
+use crate::args::Options;
+use crate::config::{CachedFile, CopyTarget, TemplateTarget, Variables};
+use crate::deploy::{desired_files, load_configuration_and_cache};
+use crate::difference::render_contents;
+use crate::filesystem::{
+    self, DryRunFilesystem, Filesystem, HardlinkComparison, SymlinkComparison, TemplateComparison,
+};
+use crate::handlebars_helpers::create_new_handlebars;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileStatus {
+    InSync,
+    PendingUpdate,
+    ModeChanged,
+    TargetModified,
+    TargetMissing,
+    PendingCreate,
+    PendingDelete,
+    SourceMissing,
+}
+
+impl std::fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        use self::FileStatus::*;
+        match self {
+            InSync => "In sync",
+            PendingUpdate => "Source changed since last deploy",
+            ModeChanged => "Target mode differs",
+            TargetModified => "Target modified",
+            TargetMissing => "Target missing",
+            PendingCreate => "Pending create",
+            PendingDelete => "Pending delete",
+            SourceMissing => "Source missing",
+        }
+        .fmt(f)
+    }
+}
+
+/// Describes a single deployed (or to be deployed) file
+#[derive(Debug)]
+pub struct StatusEntry {
+    pub kind: &'static str,
+    pub source: PathBuf,
+    pub target: PathBuf,
+}
+
+/// Returns true if anything is out of sync
+pub fn status(opt: &Options) -> Result<bool> {
+    let (mut config, cache) = load_configuration_and_cache(opt)?;
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+    let desired = desired_files(config.files).context("sort files by how they're deployed")?;
+
+    // Never touches the disk
+    let mut fs = DryRunFilesystem::new().with_root(opt.root.clone());
+    let mut report = BTreeMap::<FileStatus, Vec<StatusEntry>>::new();
+    let mut add = |status, kind, source: &Path, target: &Path| {
+        report.entry(status).or_default().push(StatusEntry {
+            kind,
+            source: source.into(),
+            target: target.into(),
+        })
+    };
+
+    for (source, target) in &cache.symlinks {
+        match desired.symlinks.get(source) {
+            Some(desired) if &desired.target == target => {
+                let comparison = fs
+                    .compare_symlink(source, target)
+                    .with_context(|| format!("compare symlink {:?} -> {:?}", source, target))?;
+                add(symlink_status(&comparison), "symlink", source, target);
+            }
+            _ => add(FileStatus::PendingDelete, "symlink", source, target),
+        }
+    }
+
+    for (source, template) in &cache.templates {
+        match desired.templates.get(source) {
+            Some(desired) if desired.target == template.target => {
+                let status = template_status(
+                    source,
+                    template,
+                    desired,
+                    &mut fs,
+                    &handlebars,
+                    &config.variables,
+                )
+                .with_context(|| {
+                    format!("compare template {:?} -> {:?}", source, template.target)
+                })?;
+                add(status, "template", source, &template.target);
+            }
+            _ => add(
+                FileStatus::PendingDelete,
+                "template",
+                source,
+                &template.target,
+            ),
+        }
+    }
+
+    for (source, target) in &cache.hardlinks {
+        match desired.hardlinks.get(source) {
+            Some(desired) if &desired.target == target => {
+                let comparison = fs
+                    .compare_hardlink(source, target)
+                    .with_context(|| format!("compare hardlink {:?} -> {:?}", source, target))?;
+                add(hardlink_status(&comparison), "hardlink", source, target);
+            }
+            _ => add(FileStatus::PendingDelete, "hardlink", source, target),
+        }
+    }
+
+    for (source, copy) in &cache.copies {
+        match desired.copies.get(source) {
+            Some(desired) if desired.target == copy.target => {
+                let status = copy_status(source, copy, desired, &mut fs)
+                    .with_context(|| format!("compare copy {:?} -> {:?}", source, copy.target))?;
+                add(status, "copy", source, &copy.target);
+            }
+            _ => add(FileStatus::PendingDelete, "copy", source, &copy.target),
+        }
+    }
+
+    for (source, target) in &desired.symlinks {
+        if cache.symlinks.get(source) != Some(&target.target) {
+            add(FileStatus::PendingCreate, "symlink", source, &target.target);
+        }
+    }
+
+    for (source, target) in &desired.templates {
+        if cache.templates.get(source).map(|template| &template.target) != Some(&target.target) {
+            add(
+                FileStatus::PendingCreate,
+                "template",
+                source,
+                &target.target,
+            );
+        }
+    }
+
+    for (source, target) in &desired.hardlinks {
+        if cache.hardlinks.get(source) != Some(&target.target) {
+            add(
+                FileStatus::PendingCreate,
+                "hardlink",
+                source,
+                &target.target,
+            );
+        }
+    }
+
+    for (source, target) in &desired.copies {
+        if cache.copies.get(source).map(|copy| &copy.target) != Some(&target.target) {
+            add(FileStatus::PendingCreate, "copy", source, &target.target);
+        }
+    }
+
+    print_report(&report);
+
+    Ok(report.keys().any(|status| *status != FileStatus::InSync))
+}
+
+fn symlink_status(comparison: &SymlinkComparison) -> FileStatus {
+    match comparison {
+        SymlinkComparison::Identical => FileStatus::InSync,
+        SymlinkComparison::OnlySourceExists => FileStatus::TargetMissing,
+        SymlinkComparison::OnlyTargetExists | SymlinkComparison::BothMissing => {
+            FileStatus::SourceMissing
+        }
+        SymlinkComparison::Changed | SymlinkComparison::TargetNotSymlink => {
+            FileStatus::TargetModified
+        }
+    }
+}
+
+fn hardlink_status(comparison: &HardlinkComparison) -> FileStatus {
+    match comparison {
+        HardlinkComparison::Identical => FileStatus::InSync,
+        HardlinkComparison::OnlySourceExists => FileStatus::TargetMissing,
+        HardlinkComparison::OnlyTargetExists | HardlinkComparison::BothMissing => {
+            FileStatus::SourceMissing
+        }
+        HardlinkComparison::Changed | HardlinkComparison::TargetNotRegularFile => {
+            FileStatus::TargetModified
+        }
+    }
+}
+
+fn template_status(
+    source: &Path,
+    cached: &CachedFile,
+    target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+) -> Result<FileStatus> {
+    if !fs.exists(source) {
+        return Ok(FileStatus::SourceMissing);
+    }
+
+    let comparison = fs
+        .compare_contents(&target.target, Some(&cached.hash), cached.stamp())
+        .context("detect templated file's current state")?;
+    debug!("Current state of {:?}: {}", target.target, comparison);
+
+    Ok(match comparison {
+        TemplateComparison::Identical => {
+            let contents = fs.read(source).context("read template source file")?;
+            let rendered = render_contents(source, contents, target, handlebars, variables)?;
+            if filesystem::hash_contents(&rendered) != cached.hash {
+                FileStatus::PendingUpdate
+            } else if mode_differs(source, &target.target, target.mode, fs) {
+                FileStatus::ModeChanged
+            } else {
+                FileStatus::InSync
+            }
+        }
+        TemplateComparison::OnlyCacheExists | TemplateComparison::BothMissing => {
+            FileStatus::TargetMissing
+        }
+        TemplateComparison::Changed
+        | TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::OnlyTargetExists => FileStatus::TargetModified,
+    })
+}
+
+fn copy_status(
+    source: &Path,
+    cached: &CachedFile,
+    target: &CopyTarget,
+    fs: &mut dyn Filesystem,
+) -> Result<FileStatus> {
+    if !fs.exists(source) {
+        return Ok(FileStatus::SourceMissing);
+    }
+
+    let comparison = fs
+        .compare_contents(&cached.target, Some(&cached.hash), cached.stamp())
+        .context("detect copied file's current state")?;
+    debug!("Current state of {:?}: {}", cached.target, comparison);
+
+    Ok(match comparison {
+        TemplateComparison::Identical => {
+            if filesystem::hash_contents(&fs.read(source).context("read source file")?)
+                != cached.hash
+            {
+                FileStatus::PendingUpdate
+            } else if mode_differs(source, &target.target, target.mode, fs) {
+                FileStatus::ModeChanged
+            } else {
+                FileStatus::InSync
+            }
+        }
+        TemplateComparison::OnlyCacheExists | TemplateComparison::BothMissing => {
+            FileStatus::TargetMissing
+        }
+        TemplateComparison::Changed
+        | TemplateComparison::TargetNotRegularFile
+        | TemplateComparison::OnlyTargetExists => FileStatus::TargetModified,
+    })
+}
+
+fn print_report(report: &BTreeMap<FileStatus, Vec<StatusEntry>>) {
+    for (status, entries) in report {
+        let header = format!("{} ({}):", status, entries.len());
+        let header = match status {
+            FileStatus::InSync => header.green(),
+            FileStatus::PendingCreate
+            | FileStatus::PendingDelete
+            | FileStatus::PendingUpdate
+            | FileStatus::ModeChanged => header.yellow(),
+            _ => header.red(),
+        };
+        println!("{}", header);
+        for entry in entries {
+            println!(
+                "    {:<8} {:?} -> {:?}",
+                entry.kind, entry.source, entry.target
+            );
+        }
+        println!();
+    }
+
+    if report.is_empty() {
+        println!("No files configured or deployed.");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::filesystem::MemoryFilesystem;
+
+    use super::*;
+
+    #[test]
+    fn symlink_statuses() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_dir_all(Path::new("/dots"), &None, &None).unwrap();
+        fs.create_dir_all(Path::new("/home/u"), &None, &None)
+            .unwrap();
+        fs.write(Path::new("/dots/rc"), "rc".into()).unwrap();
+        fs.write(Path::new("/dots/other"), "other".into()).unwrap();
+        let (source, link) = (Path::new("/dots/rc"), Path::new("/home/u/.rc"));
+        let status =
+            |fs: &mut MemoryFilesystem| symlink_status(&fs.compare_symlink(source, link).unwrap());
+
+        assert_eq!(status(&mut fs), FileStatus::TargetMissing);
+        fs.make_symlink(link, Path::new("/dots/other"), &None, &None)
+            .unwrap();
+        assert_eq!(status(&mut fs), FileStatus::TargetModified);
+        fs.remove_file(link).unwrap();
+        fs.make_symlink(link, source, &None, &None).unwrap();
+        assert_eq!(status(&mut fs), FileStatus::InSync);
+    }
+
+    #[test]
+    fn template_statuses() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_dir_all(Path::new("/dots"), &None, &None).unwrap();
+        fs.create_dir_all(Path::new("/home/u"), &None, &None)
+            .unwrap();
+        let (source, target) = (
+            Path::new("/dots/conf"),
+            TemplateTarget::from("/home/u/.conf"),
+        );
+        fs.write(source, "hello {{name}}".into()).unwrap();
+        fs.write(&target.target, "hello world".into()).unwrap();
+
+        let handlebars = Handlebars::new();
+        let variables = maplit::btreemap! { "name".into() => "world".into() };
+        let cached = CachedFile::new(
+            target.target.clone(),
+            filesystem::hash_contents(b"hello world"),
+            None,
+        );
+        let status = |fs: &mut MemoryFilesystem| {
+            template_status(source, &cached, &target, fs, &handlebars, &variables).unwrap()
+        };
+
+        assert_eq!(status(&mut fs), FileStatus::InSync);
+
+        fs.write(source, "goodbye {{name}}".into()).unwrap();
+        assert_eq!(status(&mut fs), FileStatus::PendingUpdate);
+
+        fs.write(&target.target, "edited".into()).unwrap();
+        assert_eq!(status(&mut fs), FileStatus::TargetModified);
+
+        fs.remove_file(&target.target).unwrap();
+        assert_eq!(status(&mut fs), FileStatus::TargetMissing);
+
+        fs.remove_file(source).unwrap();
+        assert_eq!(status(&mut fs), FileStatus::SourceMissing);
+    }
+
+    #[test]
+    fn mode_changed() {
+        use crate::config::Mode;
+
+        let mut fs = MemoryFilesystem::new();
+        fs.create_dir_all(Path::new("/dots"), &None, &None).unwrap();
+        fs.create_dir_all(Path::new("/home/u"), &None, &None)
+            .unwrap();
+        let source = Path::new("/dots/key");
+        let target = CopyTarget {
+            mode: Some(Mode(0o600)),
+            ..CopyTarget::from("/home/u/.key")
+        };
+        fs.write(source, "secret".into()).unwrap();
+        fs.write(&target.target, "secret".into()).unwrap();
+        fs.set_mode(&target.target, 0o644, &None).unwrap();
+        let cached = CachedFile::new(
+            target.target.clone(),
+            filesystem::hash_contents(b"secret"),
+            None,
+        );
+
+        assert_eq!(
+            copy_status(source, &cached, &target, &mut fs).unwrap(),
+            FileStatus::ModeChanged
+        );
+        fs.set_mode(&target.target, 0o600, &None).unwrap();
+        assert_eq!(
+            copy_status(source, &cached, &target, &mut fs).unwrap(),
+            FileStatus::InSync
+        );
+
+        // Without a configured mode, the target is expected to have the source's
+        let target = CopyTarget::from("/home/u/.key");
+        fs.set_mode(source, 0o640, &None).unwrap();
+        assert_eq!(
+            copy_status(source, &cached, &target, &mut fs).unwrap(),
+            FileStatus::ModeChanged
+        );
+    }
+}
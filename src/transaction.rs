@@ -0,0 +1,553 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::args::Options;
+use crate::config::{UnixGroup, UnixUser};
+use crate::filesystem::{
+    self, FileStamp, Filesystem, HardlinkComparison, SymlinkComparison, TemplateComparison,
+};
+
+/// How to reverse a single operation that was applied to the filesystem
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "undo", rename_all = "snake_case")]
+enum UndoStep {
+    /// A file was created where nothing existed before
+    Remove { path: PathBuf },
+    /// A file was moved from `to` to `from`
+    MoveBack { from: PathBuf, to: PathBuf },
+    /// These (previously missing) directories were created, innermost first
+    RemoveDirs { paths: Vec<PathBuf> },
+    /// These directories may have been deleted because they were empty
+    CreateDirs { paths: Vec<PathBuf> },
+    /// The file's mode was changed from `mode`
+    SetMode { path: PathBuf, mode: u32 },
+    /// The file's owner was changed from `uid` (and its group from `gid`)
+    SetOwner {
+        path: PathBuf,
+        uid: u32,
+        gid: Option<u32>,
+    },
+}
+
+/// A file which isn't written through the filesystem, copied aside when the transaction began
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct Snapshot {
+    original: PathBuf,
+    copy: PathBuf,
+    /// If false, the file is deleted on rollback
+    existed: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Journal {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    snapshots: Vec<Snapshot>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    steps: Vec<UndoStep>,
+}
+
+/// Wraps a filesystem, recording how to undo every change before it is made, so that a failed
+/// deployment can be rolled back.
+///
+/// The journal is saved to disk before every operation, so that an interrupted transaction is
+/// rolled back by the next run that changes anything (see `recover`).
+pub struct Transaction<'a> {
+    inner: &'a mut dyn Filesystem,
+    directory: PathBuf,
+    journal: Journal,
+}
+
+fn journal_file(directory: &Path) -> PathBuf {
+    directory.join("journal.toml")
+}
+
+/// Where transactional deployments keep their journal
+pub fn journal_directory(opt: &Options) -> PathBuf {
+    opt.cache_directory.join(".journal")
+}
+
+/// Rolls back the transaction that kept its journal in `directory`, if it was interrupted
+pub fn recover(inner: &mut dyn Filesystem, directory: &Path) -> Result<()> {
+    if !journal_file(directory).exists() {
+        return Ok(());
+    }
+
+    warn!("A previous transactional deployment was interrupted. Rolling it back.");
+    let journal = filesystem::load_file(&journal_file(directory))
+        .context("load interrupted journal")?
+        .unwrap_or_default();
+    Transaction {
+        inner,
+        directory: directory.into(),
+        journal,
+    }
+    .rollback()
+    .context("roll back interrupted transaction")
+}
+
+impl<'a> Transaction<'a> {
+    /// Starts a transaction which keeps its journal in `directory`.
+    /// Files in `snapshots` (like the cache file) aren't written through the filesystem, so a copy
+    /// of them is taken and restored on rollback.
+    pub fn begin(
+        inner: &'a mut dyn Filesystem,
+        directory: PathBuf,
+        snapshots: &[&Path],
+    ) -> Result<Transaction<'a>> {
+        recover(&mut *inner, &directory)?;
+
+        fs::create_dir_all(directory.join("stash")).context("create journal directory")?;
+
+        let mut journal = Journal::default();
+        for (i, file) in snapshots.iter().enumerate() {
+            let copy = directory.join(format!("snapshot{}", i));
+            let existed = file.exists();
+            if existed {
+                fs::copy(file, &copy).with_context(|| format!("snapshot {:?}", file))?;
+            }
+            journal.snapshots.push(Snapshot {
+                original: file.to_path_buf(),
+                copy,
+                existed,
+            });
+        }
+
+        let transaction = Transaction {
+            inner,
+            directory,
+            journal,
+        };
+        transaction.save().context("save journal")?;
+        Ok(transaction)
+    }
+
+    fn save(&self) -> Result<()> {
+        filesystem::save_file(&journal_file(&self.directory), &self.journal)
+    }
+
+    /// Records a step before the operation it reverses is executed
+    fn record(&mut self, step: UndoStep) -> Result<()> {
+        trace!("Journal: {:?}", step);
+        self.journal.steps.push(step);
+        self.save().context("save journal")
+    }
+
+    /// If `path` exists, moves it into the stash so it can be put back on rollback
+    fn stash(&mut self, path: &Path) -> Result<()> {
+        if path.symlink_metadata().is_err() {
+            return Ok(());
+        }
+        let stashed = self
+            .directory
+            .join("stash")
+            .join(self.journal.steps.len().to_string());
+        self.record(UndoStep::MoveBack {
+            from: stashed.clone(),
+            to: path.into(),
+        })?;
+        self.inner
+            .move_file(path, &stashed)
+            .context("stash file in journal")
+    }
+
+    /// Keeps all the changes and deletes the journal
+    pub fn commit(self) -> Result<()> {
+        debug!("Committing transaction");
+        self.inner
+            .remove_file(&self.directory)
+            .context("delete journal directory")
+    }
+
+    /// Restores the snapshots, reverses all the recorded changes and deletes the journal
+    pub fn rollback(mut self) -> Result<()> {
+        warn!("Rolling back {} change(s)", self.journal.steps.len());
+        for snapshot in std::mem::take(&mut self.journal.snapshots) {
+            if snapshot.existed {
+                fs::copy(&snapshot.copy, &snapshot.original)
+                    .with_context(|| format!("restore snapshot of {:?}", snapshot.original))?;
+            } else {
+                match fs::remove_file(&snapshot.original) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        Err(e).with_context(|| format!("remove {:?}", snapshot.original))?
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        while let Some(step) = self.journal.steps.pop() {
+            debug!("Undoing: {:?}", step);
+            self.undo(&step)
+                .with_context(|| format!("undo {:?}", step))?;
+            self.save().context("save journal")?;
+        }
+
+        fs::remove_dir_all(&self.directory).context("delete journal directory")
+    }
+
+    fn undo(&mut self, step: &UndoStep) -> Result<()> {
+        // Steps are recorded before the operation, which might've failed - so they have to
+        // tolerate it not having happened
+        match step {
+            UndoStep::Remove { path } => {
+                if path.symlink_metadata().is_ok() {
+                    self.inner.remove_file(path).context("remove file")?;
+                }
+            }
+            UndoStep::MoveBack { from, to } => {
+                if from.symlink_metadata().is_ok() {
+                    if to.symlink_metadata().is_ok() {
+                        self.inner.remove_file(to).context("remove replacement")?;
+                    }
+                    self.inner.move_file(from, to).context("move file back")?;
+                }
+            }
+            UndoStep::RemoveDirs { paths } => {
+                for path in paths {
+                    // Only succeeds if the directory is empty, which is what we want
+                    if let Err(e) = fs::remove_dir(path) {
+                        debug!("Not removing directory {:?}: {}", path, e);
+                    }
+                }
+            }
+            UndoStep::CreateDirs { paths } => {
+                for path in paths {
+                    if !path.exists() {
+                        self.inner
+                            .create_dir_all(path, &None, &None)
+                            .context("recreate directory")?;
+                    }
+                }
+            }
+            UndoStep::SetMode { path, mode } => {
+                if current_mode(path).is_some_and(|current| current != *mode) {
+                    if let Err(e) = set_mode(path, *mode) {
+                        warn!("Failed to restore permissions of {:?}: {}", path, e);
+                    }
+                }
+            }
+            UndoStep::SetOwner { path, uid, gid } => {
+                // Only restore them if they changed, as it takes elevated privileges
+                let changed = current_owner(path).is_some_and(|(current_uid, current_gid)| {
+                    current_uid != *uid || gid.is_some_and(|gid| gid != current_gid)
+                });
+                if changed {
+                    self.inner
+                        .set_owner(
+                            path,
+                            &Some(UnixUser::Uid(*uid as i32)),
+                            &gid.map(|gid| UnixGroup::Gid(gid as i32)),
+                        )
+                        .context("restore owner")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Filesystem for Transaction<'a> {
+    fn compare_symlink(&mut self, source: &Path, link: &Path) -> Result<SymlinkComparison> {
+        self.inner.compare_symlink(source, link)
+    }
+
+    fn compare_contents(
+        &mut self,
+        target: &Path,
+        hash: Option<&str>,
+        stamp: Option<FileStamp>,
+    ) -> Result<TemplateComparison> {
+        self.inner.compare_contents(target, hash, stamp)
+    }
+
+    fn compare_hardlink(&mut self, source: &Path, link: &Path) -> Result<HardlinkComparison> {
+        self.inner.compare_hardlink(source, link)
+    }
+
+    fn stamp(&mut self, path: &Path) -> Option<FileStamp> {
+        self.inner.stamp(path)
+    }
+
+    fn exists(&mut self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn mode(&mut self, path: &Path) -> Option<u32> {
+        self.inner.mode(path)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<()> {
+        // Removing is the same as moving it out of the way
+        self.stash(path)
+    }
+
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        self.stash(path)?;
+        self.record(UndoStep::Remove { path: path.into() })?;
+        self.inner.write(path, content)
+    }
+
+    fn delete_parents(&mut self, path: &Path, no_ask: bool) -> Result<()> {
+        let paths = path
+            .ancestors()
+            .skip(1)
+            .filter(|p| !p.as_os_str().is_empty() && p.is_dir())
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        // Outermost first
+        let paths = paths.into_iter().rev().collect();
+        self.record(UndoStep::CreateDirs { paths })?;
+        self.inner.delete_parents(path, no_ask)
+    }
+
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        self.record(UndoStep::Remove { path: link.into() })?;
+        self.inner.make_symlink(link, target, owner, group)
+    }
+
+    fn make_hardlink(&mut self, link: &Path, target: &Path) -> Result<()> {
+        self.record(UndoStep::Remove { path: link.into() })?;
+        self.inner.make_hardlink(link, target)
+    }
+
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        let paths = path
+            .ancestors()
+            .take_while(|p| !p.as_os_str().is_empty() && p.symlink_metadata().is_err())
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        if !paths.is_empty() {
+            self.record(UndoStep::RemoveDirs { paths })?;
+        }
+        self.inner.create_dir_all(path, owner, group)
+    }
+
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        self.stash(target)?;
+        self.record(UndoStep::Remove {
+            path: target.into(),
+        })?;
+        self.inner.copy_file(source, target, owner, group)
+    }
+
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        if let Some((uid, gid)) = current_owner(file) {
+            self.record(UndoStep::SetOwner {
+                path: file.into(),
+                uid,
+                gid: group.as_ref().map(|_| gid),
+            })?;
+        }
+        self.inner.set_owner(file, owner, group)
+    }
+
+    fn copy_permissions(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+    ) -> Result<()> {
+        if let Some(mode) = current_mode(target) {
+            self.record(UndoStep::SetMode {
+                path: target.into(),
+                mode,
+            })?;
+        }
+        self.inner.copy_permissions(source, target, owner)
+    }
+
+    fn set_mode(&mut self, path: &Path, mode: u32, owner: &Option<UnixUser>) -> Result<()> {
+        if let Some(current) = current_mode(path) {
+            self.record(UndoStep::SetMode {
+                path: path.into(),
+                mode: current,
+            })?;
+        }
+        self.inner.set_mode(path, mode, owner)
+    }
+
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()> {
+        self.stash(target)?;
+        self.record(UndoStep::MoveBack {
+            from: target.into(),
+            to: source.into(),
+        })?;
+        self.inner.move_file(source, target)
+    }
+}
+
+/// The uid and gid of a file
+#[cfg(unix)]
+fn current_owner(path: &Path) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    path.symlink_metadata().ok().map(|m| (m.uid(), m.gid()))
+}
+
+#[cfg(windows)]
+fn current_owner(_path: &Path) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(unix)]
+fn current_mode(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().ok().map(|m| m.permissions().mode())
+}
+
+#[cfg(windows)]
+fn current_mode(_path: &Path) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(windows)]
+fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Escalation;
+    use crate::filesystem::RealFilesystem;
+
+    use super::*;
+
+    struct Setup {
+        _directory: tempfile::TempDir,
+        root: PathBuf,
+        journal: PathBuf,
+        cache: PathBuf,
+    }
+
+    /// A directory with a deployed file, a file that will be removed and a cache file
+    fn setup() -> Setup {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().to_path_buf();
+        fs::write(root.join("deployed"), "old").unwrap();
+        fs::write(root.join("removed"), "removed").unwrap();
+        fs::write(root.join("cache.toml"), "old cache").unwrap();
+        Setup {
+            journal: root.join(".journal"),
+            cache: root.join("cache.toml"),
+            root,
+            _directory: directory,
+        }
+    }
+
+    /// Changes every file in the setup, like a deployment would
+    fn change(transaction: &mut Transaction, setup: &Setup) {
+        let root = &setup.root;
+        transaction
+            .write(&root.join("deployed"), "new".into())
+            .unwrap();
+        transaction
+            .create_dir_all(&root.join("new/parent"), &None, &None)
+            .unwrap();
+        transaction
+            .write(&root.join("new/parent/file"), "created".into())
+            .unwrap();
+        transaction.remove_file(&root.join("removed")).unwrap();
+        // Written directly, like the cache is
+        fs::write(&setup.cache, "new cache").unwrap();
+    }
+
+    fn assert_unchanged(setup: &Setup) {
+        let root = &setup.root;
+        assert_eq!(fs::read_to_string(root.join("deployed")).unwrap(), "old");
+        assert_eq!(fs::read_to_string(root.join("removed")).unwrap(), "removed");
+        assert_eq!(fs::read_to_string(&setup.cache).unwrap(), "old cache");
+        assert!(!root.join("new").exists());
+        assert!(!setup.journal.exists());
+    }
+
+    #[test]
+    fn commit_keeps_changes() {
+        let setup = setup();
+        let mut real_fs = RealFilesystem::new(true, Escalation::Never);
+        let mut transaction =
+            Transaction::begin(&mut real_fs, setup.journal.clone(), &[&setup.cache]).unwrap();
+        change(&mut transaction, &setup);
+        transaction.commit().unwrap();
+
+        let root = &setup.root;
+        assert_eq!(fs::read_to_string(root.join("deployed")).unwrap(), "new");
+        assert_eq!(
+            fs::read_to_string(root.join("new/parent/file")).unwrap(),
+            "created"
+        );
+        assert!(!root.join("removed").exists());
+        assert_eq!(fs::read_to_string(&setup.cache).unwrap(), "new cache");
+        assert!(!setup.journal.exists());
+    }
+
+    #[test]
+    fn rollback_undoes_every_step() {
+        let setup = setup();
+        let mut real_fs = RealFilesystem::new(true, Escalation::Never);
+        let mut transaction =
+            Transaction::begin(&mut real_fs, setup.journal.clone(), &[&setup.cache]).unwrap();
+        change(&mut transaction, &setup);
+        // Fails midway
+        assert!(transaction
+            .write(&setup.root.join("missing/file"), "x".into())
+            .is_err());
+        transaction.rollback().unwrap();
+
+        assert_unchanged(&setup);
+    }
+
+    #[test]
+    fn interrupted_transaction_is_recovered() {
+        let setup = setup();
+        let mut real_fs = RealFilesystem::new(true, Escalation::Never);
+        let mut transaction =
+            Transaction::begin(&mut real_fs, setup.journal.clone(), &[&setup.cache]).unwrap();
+        change(&mut transaction, &setup);
+        // Neither committed nor rolled back, like when the process is killed
+        drop(transaction);
+        assert!(journal_file(&setup.journal).exists());
+
+        recover(&mut real_fs, &setup.journal).unwrap();
+        assert_unchanged(&setup);
+
+        // Nothing left to recover
+        recover(&mut real_fs, &setup.journal).unwrap();
+        assert_unchanged(&setup);
+    }
+}
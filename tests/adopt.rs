@@ -0,0 +1,42 @@
+// Deploys symlinks, which Windows doesn't allow by default
+#![cfg(unix)]
+
+mod common;
+
+use common::{assert_status, Fixture};
+
+#[test]
+fn filters_by_relative_source_and_target_paths() {
+    let fixture = Fixture::new("packages");
+    assert_status(&fixture.run(&["deploy"]), 0);
+
+    // Replaced by a regular file, like some editors do when saving
+    std::fs::remove_file(fixture.home.join(".bashrc")).unwrap();
+    fixture.write_home(".bashrc", "alias la=\"ls -a\"\n");
+    let gitconfig = fixture.read_home(".gitconfig");
+    fixture.write_home(
+        ".gitconfig",
+        &gitconfig.replace("[user]", "[user]\n    name = Dotter"),
+    );
+
+    assert_status(&fixture.run(&["adopt", "--noconfirm", "./bashrc"]), 0);
+    assert_eq!(
+        std::fs::read_to_string(fixture.repo.join("bashrc")).unwrap(),
+        "alias la=\"ls -a\"\n"
+    );
+    assert_eq!(fixture.home_tree()[".bashrc"], fixture.link_to("bashrc"));
+    let source = std::fs::read_to_string(fixture.repo.join("gitconfig")).unwrap();
+    assert!(!source.contains("name = Dotter"), "{}", source);
+
+    // HOME is next to the repository, which dotter runs in
+    assert_status(
+        &fixture.run(&["adopt", "--noconfirm", "../home/.gitconfig"]),
+        0,
+    );
+    let source = std::fs::read_to_string(fixture.repo.join("gitconfig")).unwrap();
+    assert!(
+        source.contains("[user]\n    name = Dotter\n    email = {{email}}"),
+        "{}",
+        source
+    );
+}
@@ -0,0 +1,162 @@
+//! Runs the dotter binary on a copy of a repository from `tests/fixtures`, with a temporary HOME
+//! for its files to be deployed into.
+
+// Each test file only uses some of this
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use tempfile::TempDir;
+
+/// A file HOME starts out with, like a real one wouldn't be empty
+const PROFILE: &str = ".profile";
+
+/// What is at a path in HOME
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    File(String),
+    Symlink(PathBuf),
+    Directory,
+}
+
+pub struct Fixture {
+    /// Deleted along with everything in it when the fixture is dropped
+    _directory: TempDir,
+    pub repo: PathBuf,
+    pub home: PathBuf,
+    temp: PathBuf,
+}
+
+impl Fixture {
+    /// Copies `tests/fixtures/<name>` into a temporary repository next to an empty HOME
+    pub fn new(name: &str) -> Fixture {
+        let directory = tempfile::tempdir().expect("create temporary directory");
+        // Symlinks point to the real path of their source, so it's what tests compare with
+        let root =
+            fs::canonicalize(directory.path()).expect("get real path of temporary directory");
+        let fixture = Fixture {
+            _directory: directory,
+            repo: root.join("repo"),
+            home: root.join("home"),
+            temp: root.join("tmp"),
+        };
+
+        let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        copy_tree(&source, &fixture.repo);
+        fs::create_dir(&fixture.home).expect("create HOME");
+        // Undeploying removes the directories it leaves empty, which would include an empty HOME
+        fs::write(fixture.home.join(PROFILE), "").expect("create file in HOME");
+        fs::create_dir(&fixture.temp).expect("create temporary directory for dotter");
+        fixture
+    }
+
+    /// Runs dotter in the repository
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.run_with_input(args, "")
+    }
+
+    /// Runs dotter in the repository, giving it `input` on standard input
+    pub fn run_with_input(&self, args: &[&str], input: &str) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_dotter"))
+            .args(args)
+            .current_dir(&self.repo)
+            .env("HOME", &self.home)
+            // Hooks are rendered into the temporary directory, which is shared between tests
+            // otherwise
+            .env("TMPDIR", &self.temp)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("spawn dotter");
+        child
+            .stdin
+            .take()
+            .expect("has stdin")
+            .write_all(input.as_bytes())
+            .expect("write to dotter's stdin");
+        child.wait_with_output().expect("wait for dotter")
+    }
+
+    /// Everything in HOME except for its own file, by path relative to it
+    pub fn home_tree(&self) -> BTreeMap<String, Entry> {
+        let mut tree = BTreeMap::new();
+        walk(&self.home, &self.home, &mut tree);
+        tree.remove(PROFILE);
+        tree
+    }
+
+    pub fn read_home(&self, path: &str) -> String {
+        fs::read_to_string(self.home.join(path))
+            .unwrap_or_else(|e| panic!("read {:?} in HOME: {}", path, e))
+    }
+
+    pub fn write_home(&self, path: &str, contents: &str) {
+        let path = self.home.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap_or_else(|e| panic!("write {:?}: {}", path, e));
+    }
+
+    pub fn write_repo(&self, path: &str, contents: &str) {
+        let path = self.repo.join(path);
+        fs::write(&path, contents).unwrap_or_else(|e| panic!("write {:?}: {}", path, e));
+    }
+
+    /// A symlink entry pointing to a file in the repository
+    pub fn link_to(&self, source: &str) -> Entry {
+        Entry::Symlink(self.repo.join(source))
+    }
+}
+
+/// Fails with dotter's output unless it exited with `status`
+pub fn assert_status(output: &Output, status: i32) {
+    assert_eq!(
+        output.status.code(),
+        Some(status),
+        "dotter exited with {}\n--- stdout ---\n{}\n--- stderr ---\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn copy_tree(source: &Path, target: &Path) {
+    fs::create_dir_all(target).unwrap();
+    for entry in fs::read_dir(source).unwrap_or_else(|e| panic!("read {:?}: {}", source, e)) {
+        let entry = entry.unwrap();
+        let target = target.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_tree(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), &target).unwrap();
+        }
+    }
+}
+
+fn walk(home: &Path, directory: &Path, tree: &mut BTreeMap<String, Entry>) {
+    for entry in fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path
+            .strip_prefix(home)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        let metadata = path.symlink_metadata().unwrap();
+        if metadata.file_type().is_symlink() {
+            tree.insert(name, Entry::Symlink(fs::read_link(&path).unwrap()));
+        } else if metadata.is_dir() {
+            tree.insert(name, Entry::Directory);
+            walk(home, &path, tree);
+        } else {
+            let contents =
+                fs::read_to_string(&path).unwrap_or_else(|e| panic!("read {:?}: {}", path, e));
+            tree.insert(name, Entry::File(contents));
+        }
+    }
+}
@@ -0,0 +1,338 @@
+// Deploys symlinks and runs hooks with `sh`, which Windows doesn't have by default
+#![cfg(unix)]
+
+mod common;
+
+use std::collections::BTreeMap;
+
+use common::{assert_status, Entry, Fixture};
+
+#[test]
+fn merges_packages_includes_and_local_config() {
+    let fixture = Fixture::new("packages");
+
+    let output = fixture.run(&["deploy"]);
+    assert_status(&output, 0);
+
+    // `git` depends on `shell`, and `unused` isn't enabled. The conditions depend on which
+    // packages are. Variables come from the packages, work.toml and local.toml, in that order
+    assert_eq!(
+        fixture.home_tree(),
+        BTreeMap::from([
+            (".bashrc".into(), fixture.link_to("bashrc")),
+            (".config".into(), Entry::Directory),
+            (
+                ".config/greeting.conf".into(),
+                Entry::File("hello local\n".into())
+            ),
+            (
+                ".gitconfig".into(),
+                Entry::File(
+                    "[core]\n    editor = vi\n[user]\n    email = work@example.com\n".into()
+                )
+            ),
+            (".enabled".into(), fixture.link_to("enabled")),
+            (".local_only".into(), fixture.link_to("local_only")),
+        ])
+    );
+}
+
+#[test]
+fn redeploy_updates_templates() {
+    let fixture = Fixture::new("packages");
+    assert_status(&fixture.run(&["deploy"]), 0);
+
+    fixture.write_repo("greeting.conf", "goodbye {{name}}\n");
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert_eq!(
+        fixture.read_home(".config/greeting.conf"),
+        "goodbye local\n"
+    );
+}
+
+#[test]
+fn skips_modified_targets_unless_forced() {
+    let fixture = Fixture::new("packages");
+    fixture.write_home(".bashrc", "mine\n");
+    assert_status(&fixture.run(&["deploy"]), 5);
+    assert_eq!(fixture.home_tree()[".bashrc"], Entry::File("mine\n".into()));
+
+    fixture.write_home(".config/greeting.conf", "edited\n");
+    assert_status(&fixture.run(&["deploy"]), 5);
+    assert_eq!(fixture.read_home(".config/greeting.conf"), "edited\n");
+
+    assert_status(&fixture.run(&["deploy", "--force"]), 0);
+    assert_eq!(fixture.home_tree()[".bashrc"], fixture.link_to("bashrc"));
+    assert_eq!(fixture.read_home(".config/greeting.conf"), "hello local\n");
+
+    // What was overwritten was backed up
+    let output = fixture.run(&["restore"]);
+    assert_status(&output, 0);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    assert!(listing.contains(".bashrc"), "{}", listing);
+    assert!(listing.contains("greeting.conf"), "{}", listing);
+}
+
+#[test]
+fn patch_from_stdin() {
+    let fixture = Fixture::new("packages");
+
+    let patch = r#"
+        [files]
+        extra = "~/.extra"
+        [variables]
+        name = "patched"
+    "#;
+    assert_status(&fixture.run_with_input(&["deploy", "--patch"], patch), 0);
+    assert_eq!(fixture.home_tree()[".extra"], fixture.link_to("extra"));
+    assert_eq!(
+        fixture.read_home(".config/greeting.conf"),
+        "hello patched\n"
+    );
+}
+
+#[test]
+fn invalid_configuration() {
+    let fixture = Fixture::new("packages");
+    fixture.write_repo(".dotter/local.toml", "packages = [\"missing\"]\n");
+
+    assert_status(&fixture.run(&["deploy"]), 3);
+    assert!(fixture.home_tree().is_empty());
+
+    fixture.write_repo(".dotter/local.toml", "escalate = \"su\"\n");
+    let output = fixture.run(&["deploy"]);
+    assert_status(&output, 3);
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("escalate"),
+        "{:?}",
+        output
+    );
+    assert!(fixture.home_tree().is_empty());
+}
+
+#[test]
+fn runs_hooks_around_deploy() {
+    let fixture = Fixture::new("hooks");
+
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert_eq!(fixture.home_tree()[".rc"], fixture.link_to("rc"));
+    assert_eq!(
+        std::fs::read_to_string(fixture.repo.join("hooks.log")).unwrap(),
+        "pre-deploy hooked\n\
+         pre-deploy base package\n\
+         pre-deploy hooked package\n\
+         post-deploy base package\n\
+         post-deploy hooked package\n\
+         post-deploy hooked\n"
+    );
+}
+
+#[test]
+fn failing_hook_stops_deploy() {
+    let fixture = Fixture::new("hooks");
+    fixture.write_repo(".dotter/pre_deploy.sh", "exit 1\n");
+
+    assert_status(&fixture.run(&["deploy"]), 6);
+    assert!(fixture.home_tree().is_empty());
+}
+
+#[test]
+fn failing_package_hook_stops_deploy() {
+    let fixture = Fixture::new("hooks");
+    let config = std::fs::read_to_string(fixture.repo.join(".dotter/global.toml")).unwrap();
+    fixture.write_repo(
+        ".dotter/global.toml",
+        &config.replace("echo 'pre-deploy {{kind}} package'", "exit 1; echo"),
+    );
+
+    let output = fixture.run(&["deploy"]);
+    assert_status(&output, 6);
+    let errors = String::from_utf8_lossy(&output.stderr);
+    assert!(errors.contains("package \"base\""), "{}", errors);
+    assert!(fixture.home_tree().is_empty());
+    assert_eq!(
+        std::fs::read_to_string(fixture.repo.join("hooks.log")).unwrap(),
+        "pre-deploy hooked\n"
+    );
+}
+
+#[test]
+fn json_output_keeps_hook_output_off_stdout() {
+    let fixture = Fixture::new("hooks");
+    fixture.write_repo(".dotter/pre_deploy.sh", "echo 'pre-deploy output'\n");
+
+    let output = fixture.run(&["deploy", "--output", "json"]);
+    assert_status(&output, 0);
+    let events = String::from_utf8_lossy(&output.stdout);
+    for line in events.lines() {
+        serde_json::from_str::<serde_json::Value>(line).expect(line);
+    }
+    assert!(events.lines().last().unwrap().contains(r#""success":true"#));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("pre-deploy output"));
+}
+
+#[test]
+fn json_output_summarizes_early_failures() {
+    let fixture = Fixture::new("hooks");
+    fixture.write_repo(".dotter/pre_deploy.sh", "exit 1\n");
+
+    let output = fixture.run(&["deploy", "--output", "json"]);
+    assert_status(&output, 6);
+    let events = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(events.lines().count(), 1, "{}", events);
+    assert!(events.contains(r#""event":"summary""#), "{}", events);
+    assert!(events.contains(r#""success":false"#), "{}", events);
+
+    fixture.write_repo(".dotter/local.toml", "packages = [\"missing\"]\n");
+    let output = fixture.run(&["deploy", "--output", "json"]);
+    assert_status(&output, 3);
+    let events = String::from_utf8_lossy(&output.stdout);
+    assert!(events.contains(r#""success":false"#), "{}", events);
+}
+
+#[test]
+fn dry_run_changes_nothing() {
+    let fixture = Fixture::new("packages");
+
+    assert_status(&fixture.run(&["deploy", "--dry-run"]), 0);
+    assert!(fixture.home_tree().is_empty());
+    assert!(!fixture.repo.join(".dotter/cache.toml").exists());
+}
+
+#[test]
+fn runs_on_change_commands_of_changed_files() {
+    let fixture = Fixture::new("on_change");
+    let log = fixture.repo.join("changes.log");
+
+    // Both sway files share a command, which only runs once
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "rehash\nreload sway\nreload tmux\nreload git\nfc-cache\n"
+    );
+
+    std::fs::remove_file(&log).unwrap();
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert!(!log.exists());
+
+    fixture.write_repo("sway/colors", "set $background #ffffff\n");
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "reload sway\n");
+
+    std::fs::remove_file(&log).unwrap();
+    std::fs::remove_file(fixture.home.join(".prompt")).unwrap();
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "rehash\n");
+}
+
+#[test]
+fn runs_on_change_commands_of_edited_sources() {
+    let fixture = Fixture::new("on_change");
+    let log = fixture.repo.join("changes.log");
+    assert_status(&fixture.run(&["deploy"]), 0);
+    std::fs::remove_file(&log).unwrap();
+
+    // The links are already in place, so only the edits themselves changed the targets
+    fixture.write_repo("prompt", "PS1=\"> \"\n");
+    fixture.write_repo("gitconfig", "[user]\n\tname = Someone\n");
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "rehash\nreload git\n"
+    );
+
+    std::fs::remove_file(&log).unwrap();
+    fixture.write_repo(
+        "fonts.conf",
+        "<fontconfig><dir>~/fonts</dir></fontconfig>\n",
+    );
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "fc-cache\n");
+    assert_eq!(
+        fixture.read_home(".config/fontconfig/fonts.conf"),
+        "<fontconfig><dir>~/fonts</dir></fontconfig>\n"
+    );
+
+    std::fs::remove_file(&log).unwrap();
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert!(!log.exists());
+}
+
+#[test]
+fn failing_on_change_command() {
+    let fixture = Fixture::new("on_change");
+    let config = std::fs::read_to_string(fixture.repo.join(".dotter/global.toml")).unwrap();
+    fixture.write_repo(
+        ".dotter/global.toml",
+        &config.replace("echo 'reload tmux'", "exit 1; echo"),
+    );
+
+    let output = fixture.run(&["deploy"]);
+    assert_status(&output, 6);
+    let errors = String::from_utf8_lossy(&output.stderr);
+    assert!(errors.contains("on_change"), "{}", errors);
+    // Files are still deployed
+    assert_eq!(fixture.read_home(".tmux.conf"), "set -g mouse on\n");
+}
+
+#[test]
+fn restores_mode_of_unchanged_targets() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new("packages");
+    let config = std::fs::read_to_string(fixture.repo.join(".dotter/local.toml")).unwrap();
+    fixture.write_repo(
+        ".dotter/local.toml",
+        &config.replace(
+            r#"local_only = "~/.local_only""#,
+            r#"local_only = { target = "~/.local_only", type = "template", mode = "0600" }"#,
+        ),
+    );
+    let target = fixture.home.join(".local_only");
+    let mode = || std::fs::metadata(&target).unwrap().permissions().mode() & 0o777;
+
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert_eq!(mode(), 0o600);
+
+    std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let output = fixture.run(&["status"]);
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(report.contains("Target mode differs"), "{}", report);
+    let output = fixture.run(&["plan"]);
+    let plan = String::from_utf8_lossy(&output.stdout);
+    assert!(plan.contains("local_only"), "{}", plan);
+
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert_eq!(mode(), 0o600);
+}
+
+#[test]
+fn only_deploying_upgrades_the_cache() {
+    let fixture = Fixture::new("packages");
+    let cache = fixture.repo.join(".dotter/cache.toml");
+    let backup = fixture.repo.join(".dotter/cache.toml.v1.bak");
+    // Written before caches were versioned
+    fixture.write_repo(".dotter/cache.toml", "[symlinks]\n[templates]\n");
+
+    for args in [
+        &["status"][..],
+        &["diff"],
+        &["plan"],
+        &["deploy", "--dry-run"],
+    ] {
+        fixture.run(args);
+        assert_eq!(
+            std::fs::read_to_string(&cache).unwrap(),
+            "[symlinks]\n[templates]\n",
+            "{:?}",
+            args
+        );
+        assert!(!backup.exists(), "{:?}", args);
+    }
+
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert_eq!(
+        std::fs::read_to_string(&backup).unwrap(),
+        "[symlinks]\n[templates]\n"
+    );
+}
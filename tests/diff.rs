@@ -0,0 +1,59 @@
+// Deploys symlinks, which Windows doesn't allow by default
+#![cfg(unix)]
+
+mod common;
+
+use common::{assert_status, Fixture};
+
+#[test]
+fn unchanged_files_produce_no_output() {
+    let fixture = Fixture::new("packages");
+    assert_status(&fixture.run(&["deploy"]), 0);
+
+    let output = fixture.run(&["diff"]);
+    assert_status(&output, 0);
+    assert!(
+        output.stdout.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn filters_by_source_and_target_path() {
+    let fixture = Fixture::new("packages");
+    assert_status(&fixture.run(&["deploy"]), 0);
+    fixture.write_home(".config/greeting.conf", "edited\n");
+    fixture.write_home(".gitconfig", "edited\n");
+
+    let diff = |args: &[&str]| {
+        let output = fixture.run(args);
+        assert_status(&output, 1);
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let all = diff(&["diff"]);
+    assert!(
+        all.contains("greeting.conf") && all.contains("gitconfig"),
+        "{}",
+        all
+    );
+
+    let by_source = diff(&["diff", "greeting.conf"]);
+    assert!(by_source.contains("hello local"), "{}", by_source);
+    assert!(!by_source.contains("gitconfig"), "{}", by_source);
+
+    let home_config = fixture.home.join(".gitconfig");
+    let by_target = diff(&["diff", home_config.to_str().unwrap()]);
+    assert!(by_target.contains("gitconfig"), "{}", by_target);
+    assert!(!by_target.contains("greeting.conf"), "{}", by_target);
+
+    let by_package = diff(&["diff", "--package", "git"]);
+    assert!(by_package.contains("gitconfig"), "{}", by_package);
+    assert!(!by_package.contains("greeting.conf"), "{}", by_package);
+
+    // Unmodified files don't show up, even when selected
+    let unmodified = fixture.run(&["diff", "bashrc"]);
+    assert_status(&unmodified, 0);
+    assert!(unmodified.stdout.is_empty());
+}
@@ -0,0 +1 @@
+echo "pre-undeploy {{name}} package" >> hooks.log
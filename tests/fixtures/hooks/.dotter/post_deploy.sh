@@ -0,0 +1 @@
+echo "post-deploy {{name}}" >> hooks.log
@@ -0,0 +1 @@
+echo "post-undeploy {{name}}" >> hooks.log
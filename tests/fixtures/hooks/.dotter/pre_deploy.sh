@@ -0,0 +1 @@
+echo "pre-deploy {{name}}" >> hooks.log
@@ -0,0 +1 @@
+echo "pre-undeploy {{name}}" >> hooks.log
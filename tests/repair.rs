@@ -0,0 +1,68 @@
+// Deploys symlinks, which Windows only allows with developer mode
+#![cfg(unix)]
+
+mod common;
+
+use common::{assert_status, Fixture};
+
+/// Sources in the repaired cache by kind of file
+fn repaired(fixture: &Fixture) -> toml::value::Table {
+    let cache = std::fs::read_to_string(fixture.repo.join(".dotter/cache.toml")).unwrap();
+    toml::from_str(&cache).unwrap()
+}
+
+fn has(cache: &toml::value::Table, kind: &str, source: &str) -> bool {
+    cache
+        .get(kind)
+        .and_then(|files| files.as_table())
+        .is_some_and(|files| files.contains_key(source))
+}
+
+#[test]
+fn recovers_files_that_are_deployed() {
+    let fixture = Fixture::new("packages");
+    assert_status(&fixture.run(&["deploy"]), 0);
+    std::fs::remove_file(fixture.repo.join(".dotter/cache.toml")).unwrap();
+
+    assert_status(&fixture.run(&["cache", "repair"]), 0);
+    let cache = repaired(&fixture);
+    // A symlink into the repository, and a template whose target has its render
+    assert!(has(&cache, "symlinks", "bashrc"), "{:?}", cache);
+    assert!(has(&cache, "templates", "greeting.conf"), "{:?}", cache);
+
+    // Nothing is left to do
+    let output = fixture.run(&["status"]);
+    assert_status(&output, 0);
+}
+
+#[test]
+fn leaves_out_modified_and_missing_targets() {
+    let fixture = Fixture::new("packages");
+    assert_status(&fixture.run(&["deploy"]), 0);
+    std::fs::remove_file(fixture.repo.join(".dotter/cache.toml")).unwrap();
+    fixture.write_home(".config/greeting.conf", "edited\n");
+    std::fs::remove_file(fixture.home.join(".local_only")).unwrap();
+
+    let output = fixture.run(&["cache", "repair"]);
+    assert_status(&output, 1);
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        report.contains("\"greeting.conf\" -> ") && report.contains("don't match"),
+        "{}",
+        report
+    );
+    assert!(report.contains("Not deployed (1)"), "{}", report);
+
+    let cache = repaired(&fixture);
+    assert!(!has(&cache, "templates", "greeting.conf"), "{:?}", cache);
+    assert!(!has(&cache, "symlinks", "local_only"), "{:?}", cache);
+    assert!(has(&cache, "symlinks", "bashrc"), "{:?}", cache);
+
+    // The modified target is only overwritten when forced
+    assert_status(&fixture.run(&["deploy"]), 5);
+    assert_eq!(fixture.read_home(".config/greeting.conf"), "edited\n");
+    assert_eq!(
+        fixture.home_tree()[".local_only"],
+        fixture.link_to("local_only")
+    );
+}
@@ -0,0 +1,62 @@
+// Deploys symlinks and runs hooks with `sh`, which Windows doesn't have by default
+#![cfg(unix)]
+
+mod common;
+
+use common::{assert_status, Entry, Fixture};
+
+#[test]
+fn removes_deployed_files_and_empty_directories() {
+    let fixture = Fixture::new("packages");
+    assert_status(&fixture.run(&["deploy"]), 0);
+    assert!(!fixture.home_tree().is_empty());
+
+    assert_status(&fixture.run(&["undeploy", "--noconfirm"]), 0);
+    assert!(fixture.home_tree().is_empty(), "{:?}", fixture.home_tree());
+    assert!(fixture.repo.join("greeting.conf").exists());
+}
+
+#[test]
+fn keeps_modified_templates() {
+    let fixture = Fixture::new("packages");
+    assert_status(&fixture.run(&["deploy"]), 0);
+    fixture.write_home(".config/greeting.conf", "edited\n");
+
+    assert_status(&fixture.run(&["undeploy", "--noconfirm"]), 5);
+    assert_eq!(
+        fixture.home_tree().into_iter().collect::<Vec<_>>(),
+        vec![
+            (".config".into(), Entry::Directory),
+            (
+                ".config/greeting.conf".into(),
+                Entry::File("edited\n".into())
+            ),
+        ]
+    );
+}
+
+#[test]
+fn needs_a_cache() {
+    let fixture = Fixture::new("packages");
+
+    assert_status(&fixture.run(&["undeploy", "--noconfirm"]), 1);
+}
+
+#[test]
+fn runs_hooks_around_undeploy() {
+    let fixture = Fixture::new("hooks");
+    assert_status(&fixture.run(&["deploy"]), 0);
+    std::fs::remove_file(fixture.repo.join("hooks.log")).unwrap();
+
+    assert_status(&fixture.run(&["undeploy", "--noconfirm"]), 0);
+    assert!(fixture.home_tree().is_empty());
+    assert_eq!(
+        std::fs::read_to_string(fixture.repo.join("hooks.log")).unwrap(),
+        "pre-undeploy hooked\n\
+         pre-undeploy hooked package\n\
+         pre-undeploy base package\n\
+         post-undeploy hooked package\n\
+         post-undeploy base package\n\
+         post-undeploy hooked\n"
+    );
+}